@@ -0,0 +1,11 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// Covers the top row of a 1x2 target, with Y pointing down in clip space.
+void main() {
+    vec2 pos = vec2(0.0);
+    if (gl_VertexIndex==0) pos = vec2(-1.0, -1.0);
+    if (gl_VertexIndex==1) pos = vec2(3.0, -1.0);
+    if (gl_VertexIndex==2) pos = vec2(-1.0, 0.5);
+    gl_Position = vec4(pos, 0.0, 1.0);
+}
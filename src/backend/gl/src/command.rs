@@ -2,17 +2,18 @@
 
 use crate::gl;
 
-use crate::hal::format::ChannelType;
+use crate::hal::format::{Aspects, ChannelType, Format};
 use crate::hal::range::RangeArg;
 use crate::hal::{self, buffer, command, image, memory, pass, pso, query, ColorSlot};
 
+use crate::info::LegacyFeatures;
 use crate::pool::{self, BufferMemory};
-use crate::{native as n, Backend};
+use crate::{conv, native as n, Backend, Share};
 
 use std::borrow::Borrow;
 use std::ops::Range;
 use std::sync::{Arc, Mutex};
-use std::{mem, slice};
+use std::{fmt, mem, slice};
 
 // Command buffer implementation details:
 //
@@ -49,11 +50,31 @@ impl BufferSlice {
     }
 }
 
+/// Error returned by `RawCommandBuffer::generate_mipmaps`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MipmapError {
+    /// The image is a renderbuffer, which has no mip levels.
+    NotTexture,
+    /// Integer, compressed and depth/stencil formats can't be filtered.
+    Format(Format),
+}
+
+/// Behavior of conditional rendering while the query result isn't available.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConditionalMode {
+    /// Wait for the query result before rendering.
+    Wait,
+    /// Render unconditionally if the query result isn't available yet.
+    NoWait,
+}
+
 ///
 #[derive(Debug)]
 pub enum Command {
     Dispatch(hal::WorkGroupCount),
     DispatchIndirect(gl::types::GLuint, buffer::Offset),
+    /// Make writes of shaders visible to subsequent commands.
+    MemoryBarrier(gl::types::GLbitfield),
     Draw {
         primitive: gl::types::GLenum,
         vertices: Range<hal::VertexCount>,
@@ -67,6 +88,31 @@ pub enum Command {
         base_vertex: hal::VertexOffset,
         instances: Range<hal::InstanceCount>,
     },
+    DrawIndirect {
+        primitive: gl::types::GLenum,
+        buffer: gl::types::GLuint,
+        offset: buffer::Offset,
+        draw_count: hal::DrawCount,
+        stride: u32,
+    },
+    DrawIndexedIndirect {
+        primitive: gl::types::GLenum,
+        index_type: gl::types::GLenum,
+        buffer: gl::types::GLuint,
+        offset: buffer::Offset,
+        draw_count: hal::DrawCount,
+        stride: u32,
+    },
+    DrawIndexedIndirectCount {
+        primitive: gl::types::GLenum,
+        index_type: gl::types::GLenum,
+        buffer: gl::types::GLuint,
+        offset: buffer::Offset,
+        count_buffer: gl::types::GLuint,
+        count_offset: buffer::Offset,
+        max_draw_count: hal::DrawCount,
+        stride: u32,
+    },
     BindIndexBuffer(gl::types::GLuint),
     //BindVertexBuffers(BufferSlice),
     SetViewports {
@@ -85,33 +131,90 @@ pub enum Command {
     ClearBufferColorI(DrawBuffer, [i32; 4]),
     /// Clear depth-stencil drawbuffer of bound framebuffer.
     ClearBufferDepthStencil(Option<pso::DepthValue>, Option<pso::StencilValue>),
+    /// Restrict clears to the render area (`x`, `y`, `w`, `h`), or lift the
+    /// restriction.
+    SetClearArea(Option<[i32; 4]>),
     /// Clear the currently bound texture with the given color.
     ClearTexture([f32; 4]),
 
     /// Set list of color attachments for drawing.
     /// The buffer slice contains a list of `GLenum`.
     DrawBuffers(BufferSlice),
+    /// Discard the contents of framebuffer attachments, restricted to
+    /// the given area if any.
+    /// The buffer slice contains a list of `GLenum`.
+    InvalidateFramebuffer(FrameBufferTarget, BufferSlice, Option<[i32; 4]>),
 
     BindFrameBuffer(FrameBufferTarget, n::FrameBuffer),
+    /// Copy a region from the read to the draw framebuffer.
+    BlitFramebuffer {
+        src: Range<image::Offset>,
+        dst: Range<image::Offset>,
+        mask: gl::types::GLbitfield,
+        filter: gl::types::GLenum,
+    },
     BindTargetView(FrameBufferTarget, AttachmentPoint, n::ImageView),
     SetDrawColorBuffers(usize),
+    SetLineWidth(f32),
+    /// Toggle the sRGB encoding of color writes.
+    SetFramebufferSrgb(bool),
     SetPatchSize(gl::types::GLint),
+    SetPrimitiveRestart(pso::PrimitiveRestart),
+    /// Enable the given logic operation on color writes, or disable it.
+    SetLogicOp(Option<gl::types::GLenum>),
+    /// Apply polygon mode, culling and winding order of a pipeline.
+    SetRasterizer(pso::Rasterizer),
+    SetDepthBias(pso::DepthBias),
+    SetDepthState(pso::DepthTest),
+    /// Dynamic stencil values are already resolved to static ones.
+    SetStencilState(pso::StencilTest),
     BindProgram(gl::types::GLuint),
     BindBlendSlot(ColorSlot, pso::ColorBlendDesc),
-    BindAttribute(
-        n::AttributeDesc,
-        gl::types::GLuint,
-        gl::types::GLsizei,
-        gl::types::GLuint,
-    ),
-    //UnbindAttribute(n::AttributeDesc),
+    /// Set up the vertex attributes for drawing.
+    /// The buffer slice contains a list of `n::VertexAttribute`.
+    BindVertexInput(BufferSlice),
     CopyBufferToBuffer(n::RawBuffer, n::RawBuffer, command::BufferCopy),
-    CopyBufferToTexture(n::RawBuffer, n::Texture, command::BufferImageCopy),
+    /// Upload texels with the pixel format and type of the buffer data.
+    CopyBufferToTexture(
+        n::RawBuffer,
+        n::Texture,
+        gl::types::GLenum,
+        gl::types::GLenum,
+        command::BufferImageCopy,
+    ),
+    /// Upload pre-compressed blocks, with the internal format and data size of the region.
+    CopyBufferToCompressedTexture(
+        n::RawBuffer,
+        n::Texture,
+        gl::types::GLenum,
+        u32,
+        command::BufferImageCopy,
+    ),
     CopyBufferToSurface(n::RawBuffer, n::Surface, command::BufferImageCopy),
-    CopyTextureToBuffer(n::Texture, n::RawBuffer, command::BufferImageCopy),
-    CopySurfaceToBuffer(n::Surface, n::RawBuffer, command::BufferImageCopy),
+    /// Download a whole texture level, with the pixel format and type of the buffer data.
+    CopyTextureToBuffer(
+        n::Texture,
+        n::RawBuffer,
+        gl::types::GLenum,
+        gl::types::GLenum,
+        command::BufferImageCopy,
+    ),
+    /// Read the color attachment of the bound read framebuffer, with the
+    /// pixel format and type of the buffer data.
+    CopyFramebufferToBuffer(
+        n::RawBuffer,
+        gl::types::GLenum,
+        gl::types::GLenum,
+        command::BufferImageCopy,
+    ),
     CopyImageToTexture(n::ImageKind, n::Texture, command::ImageCopy),
     CopyImageToSurface(n::ImageKind, n::Surface, command::ImageCopy),
+    /// Filter the given levels of a texture down from the first one.
+    GenerateMipmap(n::Texture, Range<image::Level>),
+    /// Replay the commands of a secondary command buffer.
+    ExecuteCommands(SecondaryBuffer),
+    BeginTransformFeedback(gl::types::GLenum),
+    EndTransformFeedback,
 
     BindBufferRange(
         gl::types::GLenum,
@@ -120,12 +223,60 @@ pub enum Command {
         gl::types::GLintptr,
         gl::types::GLsizeiptr,
     ),
-    BindTexture(gl::types::GLenum, n::Texture),
+    BindTexture(gl::types::GLenum, n::Texture, n::TextureTarget),
     BindSampler(gl::types::GLuint, n::Texture),
-    SetTextureSamplerSettings(gl::types::GLuint, n::Texture, image::SamplerInfo),
+    BindImageTexture(
+        gl::types::GLuint,
+        n::Texture,
+        image::Level,
+        Option<image::Layer>,
+        gl::types::GLenum,
+    ),
+    SetTextureSamplerSettings(
+        gl::types::GLuint,
+        n::Texture,
+        n::TextureTarget,
+        image::SamplerInfo,
+    ),
+    BeginQuery(gl::types::GLenum, gl::types::GLuint),
+    BeginConditionalRender(gl::types::GLuint, gl::types::GLenum),
+    EndConditionalRender,
+    PushDebugGroup(BufferSlice),
+    PopDebugGroup,
+    InsertDebugMarker(BufferSlice),
+    EndQuery(gl::types::GLenum),
+    WriteTimestamp(gl::types::GLuint),
+    /// Write a value of a query into a buffer at the given offset,
+    /// either 32 or 64 bits wide.
+    CopyQueryResult {
+        query: gl::types::GLuint,
+        result_name: gl::types::GLenum,
+        buffer: n::RawBuffer,
+        offset: buffer::Offset,
+        bits_64: bool,
+    },
+    /// Upload push constant data to a uniform of the bound program.
+    SetUniform(n::UniformDesc, BufferSlice),
 }
 
 pub type FrameBufferTarget = gl::types::GLenum;
+
+/// Recorded commands of a secondary command buffer, stored in the memory of its pool.
+#[derive(Clone)]
+pub struct SecondaryBuffer {
+    pub(crate) memory: Arc<Mutex<BufferMemory>>,
+    pub(crate) id: u64,
+    pub(crate) buf: BufferSlice,
+}
+
+impl fmt::Debug for SecondaryBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecondaryBuffer")
+            .field("id", &self.id)
+            .field("buf", &self.buf)
+            .finish()
+    }
+}
 pub type AttachmentPoint = gl::types::GLenum;
 pub type DrawBuffer = gl::types::GLint;
 
@@ -140,6 +291,7 @@ struct AttachmentClear {
 struct RenderPassCache {
     render_pass: n::RenderPass,
     framebuffer: n::FrameBuffer,
+    render_area: pso::Rect,
     attachment_clears: Vec<AttachmentClear>,
 }
 
@@ -149,8 +301,18 @@ struct Cache {
     primitive: Option<gl::types::GLenum>,
     // Active index type, set by the current index buffer.
     index_type: Option<hal::IndexType>,
+    // Depth-stencil state, set by the current pipeline.
+    depth_stencil: Option<pso::DepthStencilDesc>,
+    // Currently applied depth test.
+    depth_test: Option<pso::DepthTest>,
+    // Currently applied stencil test, with dynamic values resolved.
+    stencil_test: Option<pso::StencilTest>,
     // Stencil reference values (front, back).
     stencil_ref: Option<(pso::StencilValue, pso::StencilValue)>,
+    // Stencil read masks (front, back).
+    stencil_read_mask: Option<(pso::StencilValue, pso::StencilValue)>,
+    // Stencil write masks (front, back).
+    stencil_write_mask: Option<(pso::StencilValue, pso::StencilValue)>,
     // Blend color.
     blend_color: Option<pso::ColorValue>,
     ///
@@ -160,6 +322,14 @@ struct Cache {
     error_state: bool,
     // Vertices per patch for tessellation primitives (patches).
     patch_size: Option<gl::types::GLint>,
+    // Primitive restart index, set by the current pipeline.
+    primitive_restart: Option<pso::PrimitiveRestart>,
+    // Logic operation, set by the current pipeline.
+    logic_op: Option<Option<gl::types::GLenum>>,
+    // Width of rasterized lines.
+    line_width: Option<f32>,
+    // Rasterizer state, set by the current pipeline.
+    rasterizer: Option<pso::Rasterizer>,
     // Active program name.
     program: Option<gl::types::GLuint>,
     // Blend per attachment.
@@ -170,6 +340,20 @@ struct Cache {
     vertex_buffer_descs: Vec<Option<pso::VertexBufferDesc>>,
     // Active attributes.
     attributes: Vec<n::AttributeDesc>,
+    // Uniforms emulating push constants of the active program.
+    uniforms: Vec<n::UniformDesc>,
+    // Push constant data, as 32-bit words.
+    push_constants: Vec<u32>,
+    // Indicates that vertex outputs are being captured.
+    transform_feedback: bool,
+    // Indicates that rendering depends on an occlusion query.
+    conditional_rendering: bool,
+    // Number of open debug markers.
+    debug_markers: usize,
+    // Texture bound to each texture unit.
+    textures: Vec<Option<gl::types::GLuint>>,
+    // Sampler bound to each texture unit.
+    samplers: Vec<Option<gl::types::GLuint>>,
 }
 
 impl Cache {
@@ -177,31 +361,59 @@ impl Cache {
         Cache {
             primitive: None,
             index_type: None,
+            depth_stencil: None,
+            depth_test: None,
+            stencil_test: None,
             stencil_ref: None,
+            stencil_read_mask: None,
+            stencil_write_mask: None,
             blend_color: None,
             framebuffer: None,
             error_state: false,
             patch_size: None,
+            primitive_restart: None,
+            logic_op: None,
+            line_width: None,
+            rasterizer: None,
             program: None,
             blend_targets: None,
             vertex_buffers: Vec::new(),
             vertex_buffer_descs: Vec::new(),
             attributes: Vec::new(),
+            uniforms: Vec::new(),
+            push_constants: Vec::new(),
+            transform_feedback: false,
+            conditional_rendering: false,
+            debug_markers: 0,
+            textures: Vec::new(),
+            samplers: Vec::new(),
         }
     }
 }
 
-// This is a subset of the device limits stripped down to the ones needed
-// for command buffer validation.
+// This is a subset of the device limits and features stripped down to the ones
+// needed for command buffer validation.
 #[derive(Debug, Clone, Copy)]
 pub struct Limits {
     max_viewports: usize,
+    line_width_range: [f32; 2],
+    legacy_features: LegacyFeatures,
+    invalidate_framebuffer: bool,
+    query_buffer_object: bool,
+    min_uniform_buffer_offset_alignment: buffer::Offset,
+    min_storage_buffer_offset_alignment: buffer::Offset,
 }
 
-impl From<hal::Limits> for Limits {
-    fn from(l: hal::Limits) -> Self {
+impl Limits {
+    pub(crate) fn new(share: &Share) -> Self {
         Limits {
-            max_viewports: l.max_viewports,
+            max_viewports: share.limits.max_viewports,
+            line_width_range: share.private_caps.line_width_range,
+            legacy_features: share.legacy_features,
+            invalidate_framebuffer: share.private_caps.invalidate_framebuffer,
+            query_buffer_object: share.private_caps.query_buffer_object,
+            min_uniform_buffer_offset_alignment: share.limits.min_uniform_buffer_offset_alignment,
+            min_storage_buffer_offset_alignment: share.limits.min_storage_buffer_offset_alignment,
         }
     }
 }
@@ -219,6 +431,8 @@ pub struct RawCommandBuffer {
     individual_reset: bool,
 
     fbo: Option<n::FrameBuffer>,
+    // Framebuffer for reading the source of blits.
+    blit_fbo: Option<n::FrameBuffer>,
     /// The framebuffer to use for rendering to the main targets (0 by default).
     ///
     /// Use this to set the framebuffer that will be used for the screen display targets created
@@ -233,6 +447,8 @@ pub struct RawCommandBuffer {
 
     pass_cache: Option<RenderPassCache>,
     cur_subpass: usize,
+    // Render pass and subpass continued by a secondary command buffer.
+    inherited_subpass: Option<(n::RenderPass, pass::SubpassId)>,
 
     limits: Limits,
     active_attribs: usize,
@@ -241,6 +457,7 @@ pub struct RawCommandBuffer {
 impl RawCommandBuffer {
     pub(crate) fn new(
         fbo: Option<n::FrameBuffer>,
+        blit_fbo: Option<n::FrameBuffer>,
         limits: Limits,
         memory: Arc<Mutex<BufferMemory>>,
     ) -> Self {
@@ -270,10 +487,12 @@ impl RawCommandBuffer {
             id,
             individual_reset,
             fbo,
+            blit_fbo,
             display_fb: 0 as n::FrameBuffer,
             cache: Cache::new(),
             pass_cache: None,
             cur_subpass: !0,
+            inherited_subpass: None,
             limits,
             active_attribs: 0,
         }
@@ -286,6 +505,7 @@ impl RawCommandBuffer {
         self.cache = Cache::new();
         self.pass_cache = None;
         self.cur_subpass = !0;
+        self.inherited_subpass = None;
     }
 
     fn push_cmd(&mut self, cmd: Command) {
@@ -320,6 +540,357 @@ impl RawCommandBuffer {
         slice
     }
 
+    // Store push constant data and upload it to the uniforms of the active
+    // program accessed by `stages` which overlap the written words.
+    fn update_push_constants(
+        &mut self,
+        stages: pso::ShaderStageFlags,
+        offset: u32,
+        constants: &[u32],
+    ) {
+        let start = offset as usize / 4;
+        let end = start + constants.len();
+        if self.cache.push_constants.len() < end {
+            self.cache.push_constants.resize(end, 0);
+        }
+        self.cache.push_constants[start..end].copy_from_slice(constants);
+        self.upload_push_constants(stages, offset..offset + constants.len() as u32 * 4);
+    }
+
+    fn upload_push_constants(&mut self, stages: pso::ShaderStageFlags, range: Range<u32>) {
+        let uniforms = self.cache.uniforms.clone();
+        for uniform in uniforms {
+            let end = uniform.offset + uniform.size;
+            if !stages.intersects(uniform.stages)
+                || uniform.offset >= range.end
+                || end <= range.start
+                || end as usize > self.cache.push_constants.len() * 4
+            {
+                continue;
+            }
+            let data =
+                self.cache.push_constants[uniform.offset as usize / 4..end as usize / 4].to_vec();
+            let slice = self.add(&data);
+            self.push_cmd(Command::SetUniform(uniform, slice));
+        }
+    }
+
+    fn bind_descriptor_sets<I, J>(
+        &mut self,
+        layout: &n::PipelineLayout,
+        first_set: usize,
+        sets: I,
+        offsets: J,
+    ) where
+        I: IntoIterator,
+        I::Item: Borrow<n::DescriptorSet>,
+        J: IntoIterator,
+        J::Item: Borrow<command::DescriptorSetOffset>,
+    {
+        let mut offsets = offsets.into_iter().map(|offset| *offset.borrow());
+        let mut set = first_set as _;
+        let drd = &*layout.desc_remap_data.read().unwrap();
+
+        for desc_set in sets {
+            let desc_set = desc_set.borrow();
+
+            // Dynamic offsets are consumed in binding order, one per array element.
+            let mut dynamic_bindings = desc_set
+                .layout
+                .iter()
+                .filter(|binding| match binding.ty {
+                    pso::DescriptorType::UniformBufferDynamic
+                    | pso::DescriptorType::StorageBufferDynamic => true,
+                    _ => false,
+                })
+                .collect::<Vec<_>>();
+            dynamic_bindings.sort_by_key(|binding| binding.binding);
+            let mut dynamic_offsets = Vec::with_capacity(dynamic_bindings.len());
+            for binding in dynamic_bindings {
+                let binding_offsets = offsets.by_ref().take(binding.count).collect::<Vec<_>>();
+                if binding_offsets.len() < binding.count {
+                    error!("Missing dynamic offsets for binding {}", binding.binding);
+                    self.cache.error_state = true;
+                    return;
+                }
+                // Descriptor arrays are bound to a single binding point.
+                if let Some(&offset) = binding_offsets.first() {
+                    dynamic_offsets.push((binding.binding, offset));
+                }
+            }
+
+            let bindings = desc_set.bindings.lock().unwrap();
+            for new_binding in &*bindings {
+                match new_binding {
+                    n::DescSetBindings::Buffer {
+                        ty: btype,
+                        binding,
+                        buffer,
+                        offset,
+                        size,
+                    } => {
+                        let (target, alignment) = match btype {
+                            n::BindingTypes::UniformBuffers => (
+                                gl::UNIFORM_BUFFER,
+                                self.limits.min_uniform_buffer_offset_alignment,
+                            ),
+                            n::BindingTypes::StorageBuffers => (
+                                gl::SHADER_STORAGE_BUFFER,
+                                self.limits.min_storage_buffer_offset_alignment,
+                            ),
+                            n::BindingTypes::Images | n::BindingTypes::StorageImages => {
+                                panic!("Wrong desc set binding")
+                            }
+                        };
+                        let mut offset = *offset;
+                        if let Some(&(_, dynamic_offset)) =
+                            dynamic_offsets.iter().find(|&&(b, _)| b == *binding)
+                        {
+                            if dynamic_offset as buffer::Offset % alignment != 0 {
+                                error!(
+                                    "Dynamic offset {} of binding {} isn't aligned to {}",
+                                    dynamic_offset, binding, alignment
+                                );
+                                self.cache.error_state = true;
+                                continue;
+                            }
+                            offset += dynamic_offset as gl::types::GLintptr;
+                        }
+                        for binding in drd.get_binding(*btype, set, *binding).unwrap() {
+                            self.push_cmd(Command::BindBufferRange(
+                                target, *binding, *buffer, offset, *size,
+                            ))
+                        }
+                    }
+                    n::DescSetBindings::Texture(binding, texture, target) => {
+                        for binding in drd
+                            .get_binding(n::BindingTypes::Images, set, *binding)
+                            .unwrap()
+                        {
+                            self.bind_texture(*binding, *texture, *target)
+                        }
+                    }
+                    n::DescSetBindings::StorageImage {
+                        binding,
+                        texture,
+                        level,
+                        layer,
+                        format,
+                    } => {
+                        for binding in drd
+                            .get_binding(n::BindingTypes::StorageImages, set, *binding)
+                            .unwrap()
+                        {
+                            self.push_cmd(Command::BindImageTexture(
+                                *binding, *texture, *level, *layer, *format,
+                            ))
+                        }
+                    }
+                    n::DescSetBindings::Sampler(binding, sampler) => {
+                        for binding in drd
+                            .get_binding(n::BindingTypes::Images, set, *binding)
+                            .unwrap()
+                        {
+                            self.bind_sampler(*binding, *sampler)
+                        }
+                    }
+                    n::DescSetBindings::SamplerInfo(binding, sinfo) => {
+                        let mut all_txts = drd
+                            .get_binding(n::BindingTypes::Images, set, *binding)
+                            .unwrap()
+                            .into_iter()
+                            .flat_map(|binding| {
+                                bindings.iter().filter_map(move |b| {
+                                    if let n::DescSetBindings::Texture(b, t, target) = b {
+                                        let nbs =
+                                            drd.get_binding(n::BindingTypes::Images, set, *b)?;
+                                        if nbs.contains(binding) {
+                                            Some((*binding, *t, *target))
+                                        } else {
+                                            None
+                                        }
+                                    } else {
+                                        None
+                                    }
+                                })
+                            })
+                            .collect::<Vec<_>>();
+
+                        // TODO: Check that other samplers aren't using the same
+                        // textures as in `all_txts` unless all the bindings of that
+                        // texture are gonna be unbound or the two samplers have
+                        // identical properties.
+
+                        all_txts.sort_unstable_by(|a, b| a.1.cmp(&b.1));
+                        all_txts.dedup_by(|a, b| a.1 == b.1);
+
+                        for (binding, txt, target) in all_txts {
+                            set_slot(&mut self.cache.textures, binding, txt);
+                            self.push_cmd(Command::SetTextureSamplerSettings(
+                                binding,
+                                txt,
+                                target,
+                                sinfo.clone(),
+                            ))
+                        }
+                    }
+                }
+            }
+            set += 1;
+        }
+    }
+
+    /// Bind a texture to a texture unit, unless it's already bound.
+    fn bind_texture(
+        &mut self,
+        unit: gl::types::GLuint,
+        texture: gl::types::GLuint,
+        target: n::TextureTarget,
+    ) {
+        if set_slot(&mut self.cache.textures, unit, texture) {
+            self.push_cmd(Command::BindTexture(unit, texture, target));
+        }
+    }
+
+    /// Bind a sampler to a texture unit, unless it's already bound.
+    fn bind_sampler(&mut self, unit: gl::types::GLuint, sampler: gl::types::GLuint) {
+        if set_slot(&mut self.cache.samplers, unit, sampler) {
+            self.push_cmd(Command::BindSampler(unit, sampler));
+        }
+    }
+
+    fn update_depth_stencil(&mut self) {
+        let depth_stencil = match self.cache.depth_stencil {
+            Some(depth_stencil) => depth_stencil,
+            None => return,
+        };
+
+        if self.cache.depth_test != Some(depth_stencil.depth) {
+            self.cache.depth_test = Some(depth_stencil.depth);
+            self.push_cmd(Command::SetDepthState(depth_stencil.depth));
+        }
+
+        let stencil = match depth_stencil.stencil {
+            pso::StencilTest::On { front, back } => pso::StencilTest::On {
+                front: resolve_stencil_face(
+                    front,
+                    self.cache.stencil_ref.map(|(front, _)| front),
+                    self.cache.stencil_read_mask.map(|(front, _)| front),
+                    self.cache.stencil_write_mask.map(|(front, _)| front),
+                ),
+                back: resolve_stencil_face(
+                    back,
+                    self.cache.stencil_ref.map(|(_, back)| back),
+                    self.cache.stencil_read_mask.map(|(_, back)| back),
+                    self.cache.stencil_write_mask.map(|(_, back)| back),
+                ),
+            },
+            pso::StencilTest::Off => pso::StencilTest::Off,
+        };
+
+        if self.cache.stencil_test != Some(stencil) {
+            self.cache.stencil_test = Some(stencil);
+            self.push_cmd(Command::SetStencilState(stencil));
+        }
+    }
+
+    /// Blit between two images by attaching them to the internal framebuffers.
+    /// Images are stored top row first, so the bounds map directly without flipping.
+    fn blit_framebuffer(
+        &mut self,
+        src: &n::Image,
+        dst: &n::Image,
+        region: &command::ImageBlit,
+        filter: gl::types::GLenum,
+    ) {
+        let command::ImageBlit {
+            ref src_subresource,
+            ref src_bounds,
+            ref dst_subresource,
+            ref dst_bounds,
+        } = *region;
+
+        let (read_fbo, draw_fbo) = match (self.blit_fbo, self.fbo) {
+            (Some(read_fbo), Some(draw_fbo)) => (read_fbo, draw_fbo),
+            _ => {
+                error!("Blitting images requires framebuffer support");
+                self.cache.error_state = true;
+                return;
+            }
+        };
+
+        let aspects = src_subresource.aspects;
+        let (point, mask) = if aspects.contains(Aspects::COLOR) {
+            (gl::COLOR_ATTACHMENT0, gl::COLOR_BUFFER_BIT)
+        } else if aspects.contains(Aspects::DEPTH | Aspects::STENCIL) {
+            (
+                gl::DEPTH_STENCIL_ATTACHMENT,
+                gl::DEPTH_BUFFER_BIT | gl::STENCIL_BUFFER_BIT,
+            )
+        } else if aspects.contains(Aspects::DEPTH) {
+            (gl::DEPTH_ATTACHMENT, gl::DEPTH_BUFFER_BIT)
+        } else {
+            (gl::STENCIL_ATTACHMENT, gl::STENCIL_BUFFER_BIT)
+        };
+
+        let src_layers = src_subresource.layers.clone();
+        let dst_layers = dst_subresource.layers.clone();
+        if src_layers.end - src_layers.start != dst_layers.end - dst_layers.start {
+            error!("Blits require the same number of source and destination layers");
+            self.cache.error_state = true;
+            return;
+        }
+
+        let view = |image: &n::Image, level, layers: &Range<image::Layer>, layer| match image.kind {
+            n::ImageKind::Surface(surface) => n::ImageView::Surface(surface),
+            n::ImageKind::Texture(texture) if layers.end - layers.start == 1 && layer == 0 => {
+                n::ImageView::Texture(texture, level)
+            }
+            n::ImageKind::Texture(texture) => n::ImageView::TextureLayer(texture, level, layer),
+        };
+
+        for (src_layer, dst_layer) in src_layers.clone().zip(dst_layers.clone()) {
+            let src_view = view(src, src_subresource.level, &src_layers, src_layer);
+            let dst_view = view(dst, dst_subresource.level, &dst_layers, dst_layer);
+
+            self.push_cmd(Command::BindFrameBuffer(gl::READ_FRAMEBUFFER, read_fbo));
+            self.push_cmd(Command::BindTargetView(
+                gl::READ_FRAMEBUFFER,
+                point,
+                src_view,
+            ));
+            self.push_cmd(Command::BindFrameBuffer(gl::DRAW_FRAMEBUFFER, draw_fbo));
+            self.push_cmd(Command::BindTargetView(
+                gl::DRAW_FRAMEBUFFER,
+                point,
+                dst_view,
+            ));
+            if mask == gl::COLOR_BUFFER_BIT {
+                self.push_cmd(Command::SetDrawColorBuffers(1));
+            }
+            self.push_cmd(Command::BlitFramebuffer {
+                src: src_bounds.clone(),
+                dst: dst_bounds.clone(),
+                mask,
+                filter,
+            });
+        }
+    }
+
+    // Bind the blend states of the pipeline again, restoring the color write
+    // masks after clears unlocked them.
+    fn restore_color_masks(&mut self) {
+        let blend_targets = match self.cache.blend_targets {
+            Some(ref blend_targets) => blend_targets.clone(),
+            None => return,
+        };
+        for (slot, blend_target) in blend_targets.into_iter().enumerate() {
+            if let Some(blend_target) = blend_target {
+                self.push_cmd(Command::BindBlendSlot(slot as _, blend_target));
+            }
+        }
+    }
+
     fn update_blend_targets(&mut self, blend_targets: &Vec<pso::ColorBlendDesc>) {
         let max_blend_slots = blend_targets.len();
 
@@ -363,45 +934,263 @@ impl RawCommandBuffer {
         }
     }
 
-    pub(crate) fn bind_attributes(&mut self) {
-        let Cache {
-            ref attributes,
-            ref vertex_buffers,
-            ref vertex_buffer_descs,
-            ..
-        } = self.cache;
+    /// Generate the given mip levels of an image, filtering each one down from the first.
+    ///
+    /// This is equivalent to blitting each level down to the next.
+    pub fn generate_mipmaps(
+        &mut self,
+        image: &n::Image,
+        levels: Range<image::Level>,
+    ) -> Result<(), MipmapError> {
+        let texture = match image.kind {
+            n::ImageKind::Texture(texture) => texture,
+            n::ImageKind::Surface(_) => return Err(MipmapError::NotTexture),
+        };
 
-        for attribute in attributes {
-            let binding = attribute.binding as usize;
+        let desc = image.format.base_format().0.desc();
+        let filterable = match image.channel {
+            ChannelType::Uint | ChannelType::Sint => false,
+            _ => !desc.is_compressed() && desc.aspects == Aspects::COLOR,
+        };
+        if !filterable {
+            return Err(MipmapError::Format(image.format));
+        }
 
-            if vertex_buffers.len() <= binding {
-                error!("No vertex buffer bound at {}", binding);
+        if levels.end > levels.start + 1 {
+            // Binds the texture to the active texture unit.
+            self.cache.textures.clear();
+            self.push_cmd(Command::GenerateMipmap(texture, levels));
+        }
+        Ok(())
+    }
+
+    /// Bind buffer ranges capturing the outputs of transform feedback, starting
+    /// at the given binding index.
+    pub fn bind_transform_feedback_buffers<'a, T>(&mut self, first_binding: u32, buffers: T)
+    where
+        T: IntoIterator<Item = (&'a n::Buffer, Range<buffer::Offset>)>,
+    {
+        for (i, (buffer, range)) in buffers.into_iter().enumerate() {
+            self.push_cmd(Command::BindBufferRange(
+                gl::TRANSFORM_FEEDBACK_BUFFER,
+                first_binding + i as u32,
+                buffer.raw,
+                range.start as _,
+                (range.end - range.start) as _,
+            ));
+        }
+    }
+
+    /// Start capturing the vertex outputs of the bound pipeline, which has to be
+    /// created with `Device::create_graphics_pipeline_with_feedback`.
+    ///
+    /// The primitive is the one produced by the last vertex processing stage.
+    pub fn begin_transform_feedback(&mut self, primitive: hal::Primitive) {
+        if self.cache.transform_feedback {
+            error!("Transform feedback is already active");
+            self.cache.error_state = true;
+            return;
+        }
+        match conv::primitive_to_feedback_mode(primitive) {
+            Some(mode) => {
+                self.cache.transform_feedback = true;
+                self.push_cmd(Command::BeginTransformFeedback(mode));
+            }
+            None => {
+                error!("Transform feedback can't capture {:?}", primitive);
+                self.cache.error_state = true;
             }
+        }
+    }
+
+    /// Stop capturing vertex outputs.
+    pub fn end_transform_feedback(&mut self) {
+        if !self.cache.transform_feedback {
+            error!("Transform feedback is not active");
+            self.cache.error_state = true;
+            return;
+        }
+        self.cache.transform_feedback = false;
+        self.push_cmd(Command::EndTransformFeedback);
+    }
 
-            let handle = vertex_buffers[binding];
-
-            match vertex_buffer_descs.get(binding) {
-                Some(&Some(desc)) => {
-                    push_cmd_internal(
-                        &self.id,
-                        &mut self.memory,
-                        &mut self.buf,
-                        Command::BindAttribute(
-                            attribute.clone(),
-                            handle,
-                            desc.stride as _,
-                            desc.rate.as_uint() as u32,
-                        ),
-                    );
+    /// Discard the following rendering commands if no samples passed the
+    /// given occlusion query.
+    pub fn begin_conditional_rendering(
+        &mut self,
+        query: query::Query<Backend>,
+        mode: ConditionalMode,
+    ) {
+        let pool = query.pool;
+        if pool.target != gl::SAMPLES_PASSED && pool.target != gl::ANY_SAMPLES_PASSED {
+            error!("Conditional rendering requires an occlusion query");
+            self.cache.error_state = true;
+            return;
+        }
+        if self.cache.conditional_rendering {
+            error!("Conditional rendering is already active");
+            self.cache.error_state = true;
+            return;
+        }
+        let mode = match mode {
+            ConditionalMode::Wait => gl::QUERY_WAIT,
+            ConditionalMode::NoWait => gl::QUERY_NO_WAIT,
+        };
+        self.cache.conditional_rendering = true;
+        self.push_cmd(Command::BeginConditionalRender(
+            pool.queries[query.id as usize],
+            mode,
+        ));
+    }
+
+    /// Stop discarding rendering commands based on a query.
+    pub fn end_conditional_rendering(&mut self) {
+        if !self.cache.conditional_rendering {
+            error!("Conditional rendering is not active");
+            self.cache.error_state = true;
+            return;
+        }
+        self.cache.conditional_rendering = false;
+        self.push_cmd(Command::EndConditionalRender);
+    }
+
+    /// Open a debug marker grouping the following commands, shown by
+    /// debugging tools. Markers can be nested.
+    ///
+    /// Requires `GL_KHR_debug`, GL has no notion of marker colors so `color`
+    /// is ignored.
+    pub fn begin_debug_marker(&mut self, name: &str, _color: u32) {
+        self.cache.debug_markers += 1;
+        let name = self.add_raw(name.as_bytes());
+        self.push_cmd(Command::PushDebugGroup(name));
+    }
+
+    /// Close the last opened debug marker.
+    pub fn end_debug_marker(&mut self) {
+        if self.cache.debug_markers == 0 {
+            error!("No debug marker is open");
+            self.cache.error_state = true;
+            return;
+        }
+        self.cache.debug_markers -= 1;
+        self.push_cmd(Command::PopDebugGroup);
+    }
+
+    /// Insert a single debug marker, shown by debugging tools.
+    ///
+    /// Requires `GL_KHR_debug`, GL has no notion of marker colors so `color`
+    /// is ignored.
+    pub fn insert_debug_marker(&mut self, name: &str, _color: u32) {
+        let name = self.add_raw(name.as_bytes());
+        self.push_cmd(Command::InsertDebugMarker(name));
+    }
+
+    /// Draw indexed primitives with parameters sourced from `buffer`, reading
+    /// the number of draws from `count_buffer`, clamped to `max_draw_count`.
+    ///
+    /// Without `GL_ARB_indirect_parameters`, the number of draws is read back
+    /// on the host when submitting.
+    pub fn draw_indexed_indirect_count(
+        &mut self,
+        buffer: &n::Buffer,
+        offset: buffer::Offset,
+        count_buffer: &n::Buffer,
+        count_offset: buffer::Offset,
+        max_draw_count: hal::DrawCount,
+        stride: u32,
+    ) {
+        self.bind_attributes();
+
+        let index_type = match self.cache.index_type {
+            Some(hal::IndexType::U16) => gl::UNSIGNED_SHORT,
+            Some(hal::IndexType::U32) => gl::UNSIGNED_INT,
+            None => {
+                warn!("No index type bound. An index buffer needs to be bound before calling `draw_indexed_indirect_count`.");
+                self.cache.error_state = true;
+                return;
+            }
+        };
+        let primitive = match self.cache.primitive {
+            Some(primitive) => primitive,
+            None => {
+                warn!("No primitive bound. An active pipeline needs to be bound before calling `draw_indexed_indirect_count`.");
+                self.cache.error_state = true;
+                return;
+            }
+        };
+        if count_offset % 4 != 0 {
+            error!("Draw count offset {} isn't a multiple of 4", count_offset);
+            self.cache.error_state = true;
+            return;
+        }
+        self.push_cmd(Command::DrawIndexedIndirectCount {
+            primitive,
+            index_type,
+            buffer: buffer.raw,
+            offset,
+            count_buffer: count_buffer.raw,
+            count_offset,
+            max_draw_count,
+            stride,
+        });
+    }
+
+    /// Check that the instance range of a draw call can be expressed with the
+    /// available instancing features, so unsupported draws are rejected on recording.
+    fn validate_instances(&mut self, instances: &Range<hal::InstanceCount>) -> bool {
+        let legacy = self.limits.legacy_features;
+        if instances.end - instances.start > 1 && !legacy.contains(LegacyFeatures::DRAW_INSTANCED) {
+            error!("Instanced draw calls are not supported");
+        } else if instances.start != 0 && !legacy.contains(LegacyFeatures::DRAW_INSTANCED_BASE) {
+            error!(
+                "Instanced draw calls with non-zero base instance ({}) are not supported",
+                instances.start
+            );
+        } else {
+            return true;
+        }
+
+        self.cache.error_state = true;
+        false
+    }
+
+    pub(crate) fn bind_attributes(&mut self) {
+        let mut vertex_attributes = Vec::with_capacity(self.cache.attributes.len());
+        {
+            let Cache {
+                ref attributes,
+                ref vertex_buffers,
+                ref vertex_buffer_descs,
+                ..
+            } = self.cache;
+
+            for attribute in attributes {
+                let binding = attribute.binding as usize;
+
+                if vertex_buffers.len() <= binding {
+                    error!("No vertex buffer bound at {}", binding);
+                    continue;
+                }
+
+                match vertex_buffer_descs.get(binding) {
+                    Some(&Some(desc)) => vertex_attributes.push(n::VertexAttribute {
+                        desc: *attribute,
+                        buffer: vertex_buffers[binding],
+                        stride: desc.stride as _,
+                        rate: desc.rate.as_uint() as u32,
+                    }),
+                    _ => error!("No vertex buffer description bound at {}", binding),
                 }
-                _ => error!("No vertex buffer description bound at {}", binding),
             }
         }
+
+        let attributes = self.add(&vertex_attributes);
+        self.push_cmd(Command::BindVertexInput(attributes));
     }
 
     fn begin_subpass(&mut self) {
         // Split processing and command recording due to borrowchk.
-        let (draw_buffers, clear_cmds) = {
+        let (draw_buffers, clear_cmds, invalidate) = {
             let state = self.pass_cache.as_ref().unwrap();
             let subpass = &state.render_pass.subpasses[self.cur_subpass];
 
@@ -428,7 +1217,8 @@ impl RawCommandBuffer {
                 .attachments
                 .iter()
                 .zip(state.attachment_clears.iter())
-                .filter_map(|(attachment, clear)| {
+                .enumerate()
+                .filter_map(|(id, (attachment, clear))| {
                     // Check if the attachment is first used in this subpass
                     if clear.subpass_id != Some(self.cur_subpass) {
                         return None;
@@ -442,6 +1232,9 @@ impl RawCommandBuffer {
                     // Clear color target
                     if view_format.is_color() {
                         if let Some(cv) = clear.value {
+                            // Color buffers are cleared by their draw buffer index.
+                            let draw_buffer =
+                                subpass.color_attachments.iter().position(|&c| c == id)? as _;
                             let channel = view_format.base_format().1;
 
                             let cmd = match channel {
@@ -452,13 +1245,19 @@ impl RawCommandBuffer {
                                 | ChannelType::Srgb
                                 | ChannelType::Uscaled
                                 | ChannelType::Sscaled => {
-                                    Command::ClearBufferColorF(0, unsafe { cv.color.float32 })
+                                    Command::ClearBufferColorF(draw_buffer, unsafe {
+                                        cv.color.float32
+                                    })
                                 }
                                 ChannelType::Uint => {
-                                    Command::ClearBufferColorU(0, unsafe { cv.color.uint32 })
+                                    Command::ClearBufferColorU(draw_buffer, unsafe {
+                                        cv.color.uint32
+                                    })
                                 }
                                 ChannelType::Sint => {
-                                    Command::ClearBufferColorI(0, unsafe { cv.color.int32 })
+                                    Command::ClearBufferColorI(draw_buffer, unsafe {
+                                        cv.color.int32
+                                    })
                                 }
                             };
 
@@ -487,24 +1286,107 @@ impl RawCommandBuffer {
                 })
                 .collect::<Vec<_>>();
 
-            (draw_buffers, clear_cmds)
+            // Attachments which aren't loaded don't need to preserve their contents,
+            // which saves tile-based GPUs from reading them back into tile memory.
+            let invalidate = if state.framebuffer == n::DEFAULT_FRAMEBUFFER
+                || !self.limits.invalidate_framebuffer
+            {
+                Vec::new()
+            } else {
+                let load = pass::AttachmentLoadOp::Load;
+                state
+                    .render_pass
+                    .attachments
+                    .iter()
+                    .zip(state.attachment_clears.iter())
+                    .enumerate()
+                    .filter(|&(_, (_, clear))| clear.subpass_id == Some(self.cur_subpass))
+                    .filter_map(|(id, (attachment, _))| {
+                        invalidation_point(
+                            id,
+                            attachment.format.unwrap(),
+                            attachment.ops.load != load,
+                            attachment.stencil_ops.load != load,
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            };
+
+            (draw_buffers, clear_cmds, invalidate)
         };
 
+        // Encode color writes as sRGB for sRGB attachments only.
+        // TODO: Find way to emulate this on older Opengl versions.
+        if self
+            .limits
+            .legacy_features
+            .contains(LegacyFeatures::SRGB_COLOR)
+        {
+            let state = self.pass_cache.as_ref().unwrap();
+            let subpass = &state.render_pass.subpasses[self.cur_subpass];
+            let srgb = subpass.color_attachments.iter().any(|&id| {
+                state.render_pass.attachments[id]
+                    .format
+                    .map_or(false, |format| format.base_format().1 == ChannelType::Srgb)
+            });
+            self.push_cmd(Command::SetFramebufferSrgb(srgb));
+        }
+
         // Record commands
         let draw_buffers = self.add(&draw_buffers);
         self.push_cmd(Command::DrawBuffers(draw_buffers));
 
+        // Only the render area is discarded, the rest of the attachments
+        // has to be preserved.
+        if !invalidate.is_empty() {
+            let attachments = self.add(&invalidate);
+            let area = self.pass_cache.as_ref().unwrap().render_area;
+            self.push_cmd(Command::InvalidateFramebuffer(
+                gl::DRAW_FRAMEBUFFER,
+                attachments,
+                Some([area.x as i32, area.y as i32, area.w as i32, area.h as i32]),
+            ));
+        }
+
+        // Clears only affect the render area, the rest of the attachments
+        // has to be preserved.
+        let clear_area = if clear_cmds.is_empty() {
+            None
+        } else {
+            let area = self.pass_cache.as_ref().unwrap().render_area;
+            Some([area.x as i32, area.y as i32, area.w as i32, area.h as i32])
+        };
+        if clear_area.is_some() {
+            self.push_cmd(Command::SetClearArea(clear_area));
+        }
+        let mut cleared_color = false;
         for cmd in clear_cmds {
+            if let Command::ClearBufferDepthStencil(..) = cmd {
+                // Clearing enables all depth and stencil writes,
+                // the pipeline state has to be applied again.
+                self.cache.depth_test = None;
+                self.cache.stencil_test = None;
+            } else {
+                cleared_color = true;
+            }
             self.push_cmd(cmd);
         }
+        if clear_area.is_some() {
+            self.push_cmd(Command::SetClearArea(None));
+        }
+        if cleared_color {
+            self.restore_color_masks();
+        }
+
+        self.update_depth_stencil();
     }
 }
 
 impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
     unsafe fn begin(
         &mut self,
-        _flags: hal::command::CommandBufferFlags,
-        _inheritance_info: hal::command::CommandBufferInheritanceInfo<Backend>,
+        flags: hal::command::CommandBufferFlags,
+        inheritance_info: hal::command::CommandBufferInheritanceInfo<Backend>,
     ) {
         // TODO: Implement flags!
         if self.individual_reset {
@@ -513,6 +1395,18 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
         } else {
             self.soft_reset();
         }
+
+        if flags.contains(hal::command::CommandBufferFlags::RENDER_PASS_CONTINUE) {
+            match inheritance_info.subpass {
+                Some(subpass) => {
+                    self.inherited_subpass = Some((subpass.main_pass.clone(), subpass.index));
+                }
+                None => {
+                    error!("Continuing a render pass requires an inherited subpass");
+                    self.cache.error_state = true;
+                }
+            }
+        }
     }
 
     unsafe fn finish(&mut self) {
@@ -544,18 +1438,51 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
                 });
             }
         }
-    }
+    }
+
+    unsafe fn pipeline_barrier<'a, T>(
+        &mut self,
+        _stages: Range<hal::pso::PipelineStage>,
+        _dependencies: memory::Dependencies,
+        barriers: T,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<memory::Barrier<'a, Backend>>,
+    {
+        // GL keeps everything coherent except for shader storage and image writes,
+        // so only barriers whose source access includes those need a `glMemoryBarrier`.
+        let shader_buffer_write = buffer::Access::SHADER_WRITE | buffer::Access::MEMORY_WRITE;
+        let shader_image_write = image::Access::SHADER_WRITE | image::Access::MEMORY_WRITE;
+
+        let mut bits = 0;
+        for barrier in barriers {
+            match *barrier.borrow() {
+                memory::Barrier::AllBuffers(ref access) => {
+                    if access.start.intersects(shader_buffer_write) {
+                        bits |= conv::buffer_access_to_barrier(access.end);
+                    }
+                }
+                memory::Barrier::AllImages(ref access) => {
+                    if access.start.intersects(shader_image_write) {
+                        bits |= conv::image_access_to_barrier(access.end);
+                    }
+                }
+                memory::Barrier::Buffer { ref states, .. } => {
+                    if states.start.intersects(shader_buffer_write) {
+                        bits |= conv::buffer_access_to_barrier(states.end);
+                    }
+                }
+                memory::Barrier::Image { ref states, .. } => {
+                    if states.start.0.intersects(shader_image_write) {
+                        bits |= conv::image_access_to_barrier(states.end.0);
+                    }
+                }
+            }
+        }
 
-    unsafe fn pipeline_barrier<'a, T>(
-        &mut self,
-        _stages: Range<hal::pso::PipelineStage>,
-        _dependencies: memory::Dependencies,
-        _barriers: T,
-    ) where
-        T: IntoIterator,
-        T::Item: Borrow<memory::Barrier<'a, Backend>>,
-    {
-        // TODO
+        if bits != 0 {
+            self.push_cmd(Command::MemoryBarrier(bits));
+        }
     }
 
     unsafe fn fill_buffer<R>(&mut self, _buffer: &n::Buffer, _range: R, _data: u32)
@@ -573,7 +1500,7 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
         &mut self,
         render_pass: &n::RenderPass,
         framebuffer: &n::FrameBuffer,
-        _render_area: pso::Rect,
+        render_area: pso::Rect,
         clear_values: T,
         _first_subpass: command::SubpassContents,
     ) where
@@ -627,6 +1554,7 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
         self.pass_cache = Some(RenderPassCache {
             render_pass: render_pass.clone(),
             framebuffer: *framebuffer,
+            render_area,
             attachment_clears,
         });
 
@@ -640,7 +1568,42 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
     }
 
     unsafe fn end_render_pass(&mut self) {
-        // TODO
+        // Discard attachments which aren't stored, tile-based GPUs
+        // can skip writing them back to memory. Only the render area is
+        // discarded, the rest of the attachments has to be preserved.
+        let (invalidate, area) = match self.pass_cache {
+            Some(ref state)
+                if state.framebuffer != n::DEFAULT_FRAMEBUFFER
+                    && self.limits.invalidate_framebuffer =>
+            {
+                let dont_care = pass::AttachmentStoreOp::DontCare;
+                let invalidate = state
+                    .render_pass
+                    .attachments
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(id, attachment)| {
+                        invalidation_point(
+                            id,
+                            attachment.format.unwrap(),
+                            attachment.ops.store == dont_care,
+                            attachment.stencil_ops.store == dont_care,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                (invalidate, state.render_area)
+            }
+            _ => return,
+        };
+
+        if !invalidate.is_empty() {
+            let attachments = self.add(&invalidate);
+            self.push_cmd(Command::InvalidateFramebuffer(
+                gl::DRAW_FRAMEBUFFER,
+                attachments,
+                Some([area.x as i32, area.y as i32, area.w as i32, area.h as i32]),
+            ));
+        }
     }
 
     unsafe fn clear_image<T>(
@@ -661,7 +1624,6 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
 
         match self.fbo {
             Some(fbo) => {
-                // TODO: reset color mask
                 // 2. ClearBuffer
                 let view = match image.kind {
                     n::ImageKind::Surface(id) => n::ImageView::Surface(id),
@@ -688,6 +1650,7 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
                     ChannelType::Uint => self.push_cmd(Command::ClearBufferColorU(0, color.uint32)),
                     ChannelType::Sint => self.push_cmd(Command::ClearBufferColorI(0, color.int32)),
                 }
+                self.restore_color_masks();
             }
             None => {
                 // 1. glClear
@@ -696,7 +1659,7 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
                     n::ImageKind::Surface(_id) => unimplemented!(),
                 };
 
-                self.push_cmd(Command::BindTexture(0, text));
+                self.bind_texture(0, text);
                 self.push_cmd(Command::ClearTexture(color.float32));
             }
         }
@@ -714,31 +1677,74 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
 
     unsafe fn resolve_image<T>(
         &mut self,
-        _src: &n::Image,
+        src: &n::Image,
         _src_layout: image::Layout,
-        _dst: &n::Image,
+        dst: &n::Image,
         _dst_layout: image::Layout,
-        _regions: T,
+        regions: T,
     ) where
         T: IntoIterator,
         T::Item: Borrow<command::ImageResolve>,
     {
-        unimplemented!()
+        // Blitting from a multisampled to a single-sampled framebuffer resolves the samples.
+        for region in regions {
+            let r = region.borrow();
+            let end = |offset: image::Offset| image::Offset {
+                x: offset.x + r.extent.width as i32,
+                y: offset.y + r.extent.height as i32,
+                z: offset.z + r.extent.depth as i32,
+            };
+            let blit = command::ImageBlit {
+                src_subresource: r.src_subresource.clone(),
+                src_bounds: r.src_offset..end(r.src_offset),
+                dst_subresource: r.dst_subresource.clone(),
+                dst_bounds: r.dst_offset..end(r.dst_offset),
+            };
+            self.blit_framebuffer(src, dst, &blit, gl::NEAREST);
+        }
     }
 
     unsafe fn blit_image<T>(
         &mut self,
-        _src: &n::Image,
+        src: &n::Image,
         _src_layout: image::Layout,
-        _dst: &n::Image,
+        dst: &n::Image,
         _dst_layout: image::Layout,
-        _filter: image::Filter,
-        _regions: T,
+        filter: image::Filter,
+        regions: T,
     ) where
         T: IntoIterator,
         T::Item: Borrow<command::ImageBlit>,
     {
-        unimplemented!()
+        let filter = match filter {
+            image::Filter::Nearest => gl::NEAREST,
+            image::Filter::Linear => gl::LINEAR,
+        };
+
+        for region in regions {
+            let r = region.borrow();
+            if self.fbo.is_some() && self.blit_fbo.is_some() {
+                self.blit_framebuffer(src, dst, r, filter);
+                continue;
+            }
+
+            // Without framebuffers, blits from one level of an image to the next
+            // can still generate mip chains, which GL does for us.
+            let mip_chain = src.kind == dst.kind
+                && r.src_subresource.level + 1 == r.dst_subresource.level
+                && r.src_subresource.layers == r.dst_subresource.layers;
+            if !mip_chain {
+                error!("Only blits generating the next mip level are supported");
+                self.cache.error_state = true;
+                continue;
+            }
+
+            let levels = r.src_subresource.level..r.dst_subresource.level + 1;
+            if let Err(err) = self.generate_mipmaps(src, levels) {
+                error!("Unable to generate mip levels by blitting: {:?}", err);
+                self.cache.error_state = true;
+            }
+        }
     }
 
     unsafe fn bind_index_buffer(&mut self, ibv: buffer::IndexBufferView<Backend>) {
@@ -778,11 +1784,27 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
         //
         // We try to store everything into a contiguous block of memory,
         // which allows us to avoid memory allocations when executing the commands.
+        // Without viewport arrays only the first viewport can be set.
+        if first_viewport != 0 && self.limits.max_viewports == 1 {
+            error!(
+                "First viewport index {} requires viewport arrays, which are unsupported",
+                first_viewport
+            );
+            self.cache.error_state = true;
+            return;
+        }
+
         let mut viewport_ptr = BufferSlice { offset: 0, size: 0 };
         let mut depth_range_ptr = BufferSlice { offset: 0, size: 0 };
 
         let mut len = 0;
+        let mut ignored = 0;
         for viewport in viewports {
+            // Without viewport arrays only the first viewport can be set.
+            if len > 0 && self.limits.max_viewports == 1 {
+                ignored += 1;
+                continue;
+            }
             let viewport = viewport.borrow();
             let viewport_rect = &[
                 viewport.rect.x as f32,
@@ -796,6 +1818,10 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
             len += 1;
         }
 
+        if ignored > 0 {
+            warn!("Ignoring {} viewports, arrays are unsupported", ignored);
+        }
+
         match len {
             0 => {
                 error!("Number of viewports can not be zero.");
@@ -820,9 +1846,25 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
         T: IntoIterator,
         T::Item: Borrow<pso::Rect>,
     {
+        // Without viewport arrays only the first scissor can be set.
+        if first_scissor != 0 && self.limits.max_viewports == 1 {
+            error!(
+                "First scissor index {} requires viewport arrays, which are unsupported",
+                first_scissor
+            );
+            self.cache.error_state = true;
+            return;
+        }
+
         let mut scissors_ptr = BufferSlice { offset: 0, size: 0 };
         let mut len = 0;
+        let mut ignored = 0;
         for scissor in scissors {
+            // Without viewport arrays only the first scissor can be set.
+            if len > 0 && self.limits.max_viewports == 1 {
+                ignored += 1;
+                continue;
+            }
             let scissor = scissor.borrow();
             let scissor = &[
                 scissor.x as i32,
@@ -834,6 +1876,10 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
             len += 1;
         }
 
+        if ignored > 0 {
+            warn!("Ignoring {} scissors, arrays are unsupported", ignored);
+        }
+
         match len {
             0 => {
                 error!("Number of scissors can not be zero.");
@@ -851,35 +1897,20 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
 
     unsafe fn set_stencil_reference(&mut self, faces: pso::Face, value: pso::StencilValue) {
         assert!(!faces.is_empty());
-
-        let mut front = 0;
-        let mut back = 0;
-
-        if let Some((last_front, last_back)) = self.cache.stencil_ref {
-            front = last_front;
-            back = last_back;
-        }
-
-        if faces.contains(pso::Face::FRONT) {
-            front = value;
-        }
-
-        if faces.contains(pso::Face::BACK) {
-            back = value;
-        }
-
-        // Only cache the stencil references values until
-        // we assembled all the pieces to set the stencil state
-        // from the pipeline.
-        self.cache.stencil_ref = Some((front, back));
+        set_stencil_faces(&mut self.cache.stencil_ref, faces, value, 0);
+        self.update_depth_stencil();
     }
 
-    unsafe fn set_stencil_read_mask(&mut self, _faces: pso::Face, _value: pso::StencilValue) {
-        unimplemented!();
+    unsafe fn set_stencil_read_mask(&mut self, faces: pso::Face, value: pso::StencilValue) {
+        assert!(!faces.is_empty());
+        set_stencil_faces(&mut self.cache.stencil_read_mask, faces, value, !0);
+        self.update_depth_stencil();
     }
 
-    unsafe fn set_stencil_write_mask(&mut self, _faces: pso::Face, _value: pso::StencilValue) {
-        unimplemented!();
+    unsafe fn set_stencil_write_mask(&mut self, faces: pso::Face, value: pso::StencilValue) {
+        assert!(!faces.is_empty());
+        set_stencil_faces(&mut self.cache.stencil_write_mask, faces, value, !0);
+        self.update_depth_stencil();
     }
 
     unsafe fn set_blend_constants(&mut self, cv: pso::ColorValue) {
@@ -893,22 +1924,39 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
         warn!("Depth bounds test is not supported");
     }
 
-    unsafe fn set_line_width(&mut self, _width: f32) {
-        unimplemented!()
+    unsafe fn set_line_width(&mut self, width: f32) {
+        let [min, max] = self.limits.line_width_range;
+        if width > max {
+            warn!("Line width {} exceeds the maximum of {}", width, max);
+        }
+        let width = width.max(min).min(max);
+        if self.cache.line_width != Some(width) {
+            self.cache.line_width = Some(width);
+            self.push_cmd(Command::SetLineWidth(width));
+        }
     }
 
-    unsafe fn set_depth_bias(&mut self, _depth_bias: pso::DepthBias) {
-        unimplemented!()
+    unsafe fn set_depth_bias(&mut self, depth_bias: pso::DepthBias) {
+        if depth_bias.clamp != 0.0 {
+            warn!("Depth bias clamp is not supported");
+        }
+        self.push_cmd(Command::SetDepthBias(depth_bias));
     }
 
     unsafe fn bind_graphics_pipeline(&mut self, pipeline: &n::GraphicsPipeline) {
         let n::GraphicsPipeline {
             primitive,
             patch_size,
+            primitive_restart,
+            line_width,
+            ref rasterizer,
+            depth_stencil,
+            logic_op,
             program,
             ref blend_targets,
             ref attributes,
             ref vertex_buffers,
+            ref uniforms,
         } = *pipeline;
 
         if self.cache.primitive != Some(primitive) {
@@ -922,9 +1970,36 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
             }
         }
 
+        if self.cache.primitive_restart != Some(primitive_restart) {
+            self.cache.primitive_restart = Some(primitive_restart);
+            self.push_cmd(Command::SetPrimitiveRestart(primitive_restart));
+        }
+
+        if self.cache.logic_op != Some(logic_op) {
+            self.cache.logic_op = Some(logic_op);
+            self.push_cmd(Command::SetLogicOp(logic_op));
+        }
+
+        if let Some(width) = line_width {
+            self.set_line_width(width);
+        }
+
+        if self.cache.rasterizer.as_ref() != Some(rasterizer) {
+            self.cache.rasterizer = Some(rasterizer.clone());
+            self.push_cmd(Command::SetRasterizer(rasterizer.clone()));
+        }
+
+        self.cache.depth_stencil = Some(depth_stencil);
+        self.update_depth_stencil();
+
         if self.cache.program != Some(program) {
             self.cache.program = Some(program);
             self.push_cmd(Command::BindProgram(program));
+
+            // Uniforms are part of the program state, restore the current
+            // push constants.
+            self.cache.uniforms = uniforms.clone();
+            self.upload_push_constants(pso::ShaderStageFlags::GRAPHICS, 0..!0);
         }
 
         self.cache.attributes = attributes.clone();
@@ -946,118 +2021,37 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
         J: IntoIterator,
         J::Item: Borrow<command::DescriptorSetOffset>,
     {
-        assert!(offsets.into_iter().next().is_none()); // TODO: offsets unsupported
-
-        let mut set = first_set as _;
-        let drd = &*layout.desc_remap_data.read().unwrap();
-
-        for desc_set in sets {
-            let desc_set = desc_set.borrow();
-            let bindings = desc_set.bindings.lock().unwrap();
-            for new_binding in &*bindings {
-                match new_binding {
-                    n::DescSetBindings::Buffer {
-                        ty: btype,
-                        binding,
-                        buffer,
-                        offset,
-                        size,
-                    } => {
-                        let btype = match btype {
-                            n::BindingTypes::UniformBuffers => gl::UNIFORM_BUFFER,
-                            n::BindingTypes::Images => panic!("Wrong desc set binding"),
-                        };
-                        for binding in drd
-                            .get_binding(n::BindingTypes::UniformBuffers, set, *binding)
-                            .unwrap()
-                        {
-                            self.push_cmd(Command::BindBufferRange(
-                                btype, *binding, *buffer, *offset, *size,
-                            ))
-                        }
-                    }
-                    n::DescSetBindings::Texture(binding, texture) => {
-                        for binding in drd
-                            .get_binding(n::BindingTypes::Images, set, *binding)
-                            .unwrap()
-                        {
-                            self.push_cmd(Command::BindTexture(*binding, *texture))
-                        }
-                    }
-                    n::DescSetBindings::Sampler(binding, sampler) => {
-                        for binding in drd
-                            .get_binding(n::BindingTypes::Images, set, *binding)
-                            .unwrap()
-                        {
-                            self.push_cmd(Command::BindSampler(*binding, *sampler))
-                        }
-                    }
-                    n::DescSetBindings::SamplerInfo(binding, sinfo) => {
-                        let mut all_txts = drd
-                            .get_binding(n::BindingTypes::Images, set, *binding)
-                            .unwrap()
-                            .into_iter()
-                            .flat_map(|binding| {
-                                bindings.iter().filter_map(move |b| {
-                                    if let n::DescSetBindings::Texture(b, t) = b {
-                                        let nbs =
-                                            drd.get_binding(n::BindingTypes::Images, set, *b)?;
-                                        if nbs.contains(binding) {
-                                            Some((*binding, *t))
-                                        } else {
-                                            None
-                                        }
-                                    } else {
-                                        None
-                                    }
-                                })
-                            })
-                            .collect::<Vec<_>>();
-
-                        // TODO: Check that other samplers aren't using the same
-                        // textures as in `all_txts` unless all the bindings of that
-                        // texture are gonna be unbound or the two samplers have
-                        // identical properties.
-
-                        all_txts.sort_unstable_by(|a, b| a.1.cmp(&b.1));
-                        all_txts.dedup_by(|a, b| a.1 == b.1);
-
-                        for (binding, txt) in all_txts {
-                            self.push_cmd(Command::SetTextureSamplerSettings(
-                                binding,
-                                txt,
-                                sinfo.clone(),
-                            ))
-                        }
-                    }
-                }
-            }
-            set += 1;
-        }
+        self.bind_descriptor_sets(layout, first_set, sets, offsets);
     }
 
     unsafe fn bind_compute_pipeline(&mut self, pipeline: &n::ComputePipeline) {
-        let n::ComputePipeline { program } = *pipeline;
+        let n::ComputePipeline {
+            program,
+            ref uniforms,
+        } = *pipeline;
 
         if self.cache.program != Some(program) {
             self.cache.program = Some(program);
             self.push_cmd(Command::BindProgram(program));
+
+            self.cache.uniforms = uniforms.clone();
+            self.upload_push_constants(pso::ShaderStageFlags::COMPUTE, 0..!0);
         }
     }
 
     unsafe fn bind_compute_descriptor_sets<I, J>(
         &mut self,
-        _layout: &n::PipelineLayout,
-        _first_set: usize,
-        _sets: I,
-        _offsets: J,
+        layout: &n::PipelineLayout,
+        first_set: usize,
+        sets: I,
+        offsets: J,
     ) where
         I: IntoIterator,
         I::Item: Borrow<n::DescriptorSet>,
         J: IntoIterator,
         J::Item: Borrow<command::DescriptorSetOffset>,
     {
-        // TODO
+        self.bind_descriptor_sets(layout, first_set, sets, offsets);
     }
 
     unsafe fn dispatch(&mut self, count: hal::WorkGroupCount) {
@@ -1077,6 +2071,12 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
 
         for region in regions {
             let r = region.borrow().clone();
+            let overlapping = r.src < r.dst + r.size && r.dst < r.src + r.size;
+            if src.raw == dst.raw && overlapping {
+                error!("Source and destination regions of buffer copies must not overlap");
+                self.cache.error_state = true;
+                continue;
+            }
             let cmd = Command::CopyBufferToBuffer(src.raw, dst.raw, r);
             self.push_cmd(cmd);
         }
@@ -1124,12 +2124,34 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
         T::Item: Borrow<command::BufferImageCopy>,
     {
         let old_size = self.buf.size;
+        // Texture copies bind the texture to the active texture unit.
+        self.cache.textures.clear();
 
         for region in regions {
             let r = region.borrow().clone();
             let cmd = match dst.kind {
                 n::ImageKind::Surface(s) => Command::CopyBufferToSurface(src.raw, s, r),
-                n::ImageKind::Texture(t) => Command::CopyBufferToTexture(src.raw, t, r),
+                n::ImageKind::Texture(t) => match conv::compressed_format_to_gl(dst.format) {
+                    Some((internal, _)) => {
+                        let size = conv::image_data_size(
+                            dst.format,
+                            r.image_extent.width,
+                            r.image_extent.height,
+                            1,
+                        );
+                        Command::CopyBufferToCompressedTexture(src.raw, t, internal, size as _, r)
+                    }
+                    None => match conv::image_format_to_gl(dst.format) {
+                        Some((_, format, pixel_type)) => {
+                            Command::CopyBufferToTexture(src.raw, t, format, pixel_type, r)
+                        }
+                        None => {
+                            error!("Can't upload images of format {:?}", dst.format);
+                            self.cache.error_state = true;
+                            continue;
+                        }
+                    },
+                },
             };
             self.push_cmd(cmd);
         }
@@ -1150,12 +2172,50 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
         T::Item: Borrow<command::BufferImageCopy>,
     {
         let old_size = self.buf.size;
+        // Texture copies bind the texture to the active texture unit.
+        self.cache.textures.clear();
 
         for region in regions {
             let r = region.borrow().clone();
-            let cmd = match src.kind {
-                n::ImageKind::Surface(s) => Command::CopySurfaceToBuffer(s, dst.raw, r),
-                n::ImageKind::Texture(t) => Command::CopyTextureToBuffer(t, dst.raw, r),
+            let (format, pixel_type) = match conv::image_format_to_gl(src.format) {
+                Some((_, format, pixel_type)) => (format, pixel_type),
+                None => {
+                    error!("Can't read back images of format {:?}", src.format);
+                    self.cache.error_state = true;
+                    continue;
+                }
+            };
+
+            // Reading through a framebuffer supports partial copies and is the only
+            // way to read renderbuffers, like swapchain images.
+            let view = match src.kind {
+                n::ImageKind::Surface(s) => Some(n::ImageView::Surface(s)),
+                n::ImageKind::Texture(t) if src.format.is_color() => {
+                    Some(n::ImageView::Texture(t, r.image_layers.level))
+                }
+                n::ImageKind::Texture(_) => None,
+            };
+
+            let cmd = match (view, self.fbo) {
+                (Some(view), Some(fbo)) => {
+                    self.push_cmd(Command::BindFrameBuffer(gl::READ_FRAMEBUFFER, fbo));
+                    self.push_cmd(Command::BindTargetView(
+                        gl::READ_FRAMEBUFFER,
+                        gl::COLOR_ATTACHMENT0,
+                        view,
+                    ));
+                    Command::CopyFramebufferToBuffer(dst.raw, format, pixel_type, r)
+                }
+                _ => match src.kind {
+                    n::ImageKind::Texture(t) => {
+                        Command::CopyTextureToBuffer(t, dst.raw, format, pixel_type, r)
+                    }
+                    n::ImageKind::Surface(_) => {
+                        error!("Reading back surfaces requires framebuffer support");
+                        self.cache.error_state = true;
+                        continue;
+                    }
+                },
             };
             self.push_cmd(cmd);
         }
@@ -1170,6 +2230,9 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
         vertices: Range<hal::VertexCount>,
         instances: Range<hal::InstanceCount>,
     ) {
+        if !self.validate_instances(&instances) {
+            return;
+        }
         self.bind_attributes();
 
         match self.cache.primitive {
@@ -1193,6 +2256,9 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
         base_vertex: hal::VertexOffset,
         instances: Range<hal::InstanceCount>,
     ) {
+        if !self.validate_instances(&instances) {
+            return;
+        }
         self.bind_attributes();
 
         let (start, index_type) = match self.cache.index_type {
@@ -1224,77 +2290,267 @@ impl command::RawCommandBuffer<Backend> for RawCommandBuffer {
 
     unsafe fn draw_indirect(
         &mut self,
-        _buffer: &n::Buffer,
-        _offset: buffer::Offset,
-        _draw_count: hal::DrawCount,
-        _stride: u32,
+        buffer: &n::Buffer,
+        offset: buffer::Offset,
+        draw_count: hal::DrawCount,
+        stride: u32,
     ) {
-        unimplemented!()
+        self.bind_attributes();
+
+        match self.cache.primitive {
+            Some(primitive) => {
+                self.push_cmd(Command::DrawIndirect {
+                    primitive,
+                    buffer: buffer.raw,
+                    offset,
+                    draw_count,
+                    stride,
+                });
+            }
+            None => {
+                warn!("No primitive bound. An active pipeline needs to be bound before calling `draw_indirect`.");
+                self.cache.error_state = true;
+            }
+        }
     }
 
     unsafe fn draw_indexed_indirect(
         &mut self,
-        _buffer: &n::Buffer,
-        _offset: buffer::Offset,
-        _draw_count: hal::DrawCount,
-        _stride: u32,
+        buffer: &n::Buffer,
+        offset: buffer::Offset,
+        draw_count: hal::DrawCount,
+        stride: u32,
     ) {
-        unimplemented!()
+        self.bind_attributes();
+
+        let index_type = match self.cache.index_type {
+            Some(hal::IndexType::U16) => gl::UNSIGNED_SHORT,
+            Some(hal::IndexType::U32) => gl::UNSIGNED_INT,
+            None => {
+                warn!("No index type bound. An index buffer needs to be bound before calling `draw_indexed_indirect`.");
+                self.cache.error_state = true;
+                return;
+            }
+        };
+        match self.cache.primitive {
+            Some(primitive) => {
+                self.push_cmd(Command::DrawIndexedIndirect {
+                    primitive,
+                    index_type,
+                    buffer: buffer.raw,
+                    offset,
+                    draw_count,
+                    stride,
+                });
+            }
+            None => {
+                warn!("No primitive bound. An active pipeline needs to be bound before calling `draw_indexed_indirect`.");
+                self.cache.error_state = true;
+            }
+        }
     }
 
-    unsafe fn begin_query(&mut self, _query: query::Query<Backend>, _flags: query::ControlFlags) {
-        unimplemented!()
+    unsafe fn begin_query(&mut self, query: query::Query<Backend>, _flags: query::ControlFlags) {
+        let pool = query.pool;
+        self.push_cmd(Command::BeginQuery(
+            pool.target,
+            pool.queries[query.id as usize],
+        ));
     }
 
     unsafe fn copy_query_pool_results(
         &mut self,
-        _pool: &(),
-        _queries: Range<query::Id>,
-        _buffer: &n::Buffer,
-        _offset: buffer::Offset,
-        _stride: buffer::Offset,
-        _flags: query::ResultFlags,
+        pool: &n::QueryPool,
+        queries: Range<query::Id>,
+        buffer: &n::Buffer,
+        offset: buffer::Offset,
+        stride: buffer::Offset,
+        flags: query::ResultFlags,
     ) {
-        unimplemented!()
+        if !self.limits.query_buffer_object {
+            error!("Copying query results into buffers requires query buffer objects");
+            self.cache.error_state = true;
+            return;
+        }
+
+        let bits_64 = flags.contains(query::ResultFlags::BITS_64);
+        let value_size = if bits_64 { 8 } else { 4 };
+        // Results which aren't available yet are left untouched without
+        // `WAIT`, intermediate results can't be queried into buffers.
+        let result_name = if flags.contains(query::ResultFlags::WAIT) {
+            gl::QUERY_RESULT
+        } else {
+            gl::QUERY_RESULT_NO_WAIT
+        };
+
+        for (i, id) in queries.enumerate() {
+            let query = pool.queries[id as usize];
+            let offset = offset + i as buffer::Offset * stride;
+            self.push_cmd(Command::CopyQueryResult {
+                query,
+                result_name,
+                buffer: buffer.raw,
+                offset,
+                bits_64,
+            });
+            if flags.contains(query::ResultFlags::WITH_AVAILABILITY) {
+                self.push_cmd(Command::CopyQueryResult {
+                    query,
+                    result_name: gl::QUERY_RESULT_AVAILABLE,
+                    buffer: buffer.raw,
+                    offset: offset + value_size,
+                    bits_64,
+                });
+            }
+        }
     }
 
-    unsafe fn end_query(&mut self, _query: query::Query<Backend>) {
-        unimplemented!()
+    unsafe fn end_query(&mut self, query: query::Query<Backend>) {
+        self.push_cmd(Command::EndQuery(query.pool.target));
     }
 
-    unsafe fn reset_query_pool(&mut self, _pool: &(), _queries: Range<query::Id>) {
-        unimplemented!()
+    unsafe fn reset_query_pool(&mut self, _pool: &n::QueryPool, _queries: Range<query::Id>) {
+        // Nothing to do, GL queries are reset when beginning them
     }
 
-    unsafe fn write_timestamp(&mut self, _: pso::PipelineStage, _: query::Query<Backend>) {
-        unimplemented!()
+    unsafe fn write_timestamp(&mut self, _: pso::PipelineStage, query: query::Query<Backend>) {
+        self.push_cmd(Command::WriteTimestamp(
+            query.pool.queries[query.id as usize],
+        ));
     }
 
     unsafe fn push_graphics_constants(
         &mut self,
         _layout: &n::PipelineLayout,
-        _stages: pso::ShaderStageFlags,
-        _offset: u32,
-        _constants: &[u32],
+        stages: pso::ShaderStageFlags,
+        offset: u32,
+        constants: &[u32],
     ) {
-        unimplemented!()
+        self.update_push_constants(stages, offset, constants);
     }
 
     unsafe fn push_compute_constants(
         &mut self,
         _layout: &n::PipelineLayout,
-        _offset: u32,
-        _constants: &[u32],
+        offset: u32,
+        constants: &[u32],
     ) {
-        unimplemented!()
+        self.update_push_constants(pso::ShaderStageFlags::COMPUTE, offset, constants);
     }
 
-    unsafe fn execute_commands<'a, T, I>(&mut self, _buffers: I)
+    unsafe fn execute_commands<'a, T, I>(&mut self, buffers: I)
     where
         T: 'a + Borrow<RawCommandBuffer>,
         I: IntoIterator<Item = &'a T>,
     {
-        unimplemented!()
+        for buffer in buffers {
+            let secondary = buffer.borrow();
+
+            let compatible = match (&self.pass_cache, &secondary.inherited_subpass) {
+                (Some(state), Some((render_pass, subpass))) => {
+                    *subpass == self.cur_subpass && state.render_pass.is_compatible(render_pass)
+                }
+                (None, None) => true,
+                _ => false,
+            };
+            if !compatible {
+                error!("Secondary command buffer doesn't continue the current render pass");
+                self.cache.error_state = true;
+                continue;
+            }
+
+            if secondary.cache.error_state {
+                self.cache.error_state = true;
+            }
+            self.push_cmd(Command::ExecuteCommands(SecondaryBuffer {
+                memory: secondary.memory.clone(),
+                id: secondary.id,
+                buf: secondary.buf,
+            }));
+        }
+
+        // Secondary command buffers leave the bound state undefined.
+        let error_state = self.cache.error_state;
+        let debug_markers = self.cache.debug_markers;
+        self.cache = Cache::new();
+        self.cache.error_state = error_state;
+        self.cache.debug_markers = debug_markers;
+    }
+}
+
+/// Attachment point of a framebuffer attachment, if any of its contents are discarded.
+fn invalidation_point(
+    id: pass::AttachmentId,
+    format: Format,
+    discard: bool,
+    discard_stencil: bool,
+) -> Option<gl::types::GLenum> {
+    if format.is_color() {
+        return if discard {
+            Some(gl::COLOR_ATTACHMENT0 + id as gl::types::GLenum)
+        } else {
+            None
+        };
+    }
+
+    let depth = format.is_depth() && discard;
+    let stencil = format.is_stencil() && discard_stencil;
+    match (depth, stencil) {
+        (true, true) => Some(gl::DEPTH_STENCIL_ATTACHMENT),
+        (true, false) => Some(gl::DEPTH_ATTACHMENT),
+        (false, true) => Some(gl::STENCIL_ATTACHMENT),
+        (false, false) => None,
+    }
+}
+
+/// Set the value of a per-unit binding slot, returning whether it changed.
+fn set_slot<T: PartialEq + Copy>(
+    slots: &mut Vec<Option<T>>,
+    unit: gl::types::GLuint,
+    value: T,
+) -> bool {
+    let unit = unit as usize;
+    if slots.len() <= unit {
+        slots.resize(unit + 1, None);
+    }
+    let changed = slots[unit] != Some(value);
+    slots[unit] = Some(value);
+    changed
+}
+
+/// Update the (front, back) stencil values of the given faces.
+fn set_stencil_faces(
+    values: &mut Option<(pso::StencilValue, pso::StencilValue)>,
+    faces: pso::Face,
+    value: pso::StencilValue,
+    default: pso::StencilValue,
+) {
+    let (mut front, mut back) = values.unwrap_or((default, default));
+    if faces.contains(pso::Face::FRONT) {
+        front = value;
+    }
+    if faces.contains(pso::Face::BACK) {
+        back = value;
+    }
+    *values = Some((front, back));
+}
+
+/// Replace dynamic states of a stencil face with the values set on the command buffer.
+fn resolve_stencil_face(
+    face: pso::StencilFace,
+    reference: Option<pso::StencilValue>,
+    read_mask: Option<pso::StencilValue>,
+    write_mask: Option<pso::StencilValue>,
+) -> pso::StencilFace {
+    let resolve = |state, value: Option<pso::StencilValue>, default| match state {
+        pso::State::Dynamic => pso::State::Static(value.unwrap_or(default)),
+        state => state,
+    };
+    pso::StencilFace {
+        reference: resolve(face.reference, reference, 0),
+        mask_read: resolve(face.mask_read, read_mask, !0),
+        mask_write: resolve(face.mask_write, write_mask, !0),
+        ..face
     }
 }
 
@@ -1,6 +1,7 @@
 use crate::gl::{self, types as t};
-use crate::hal::format::Format;
-use crate::hal::{buffer, image as i, Primitive};
+use crate::hal::format::{ChannelType, Component, Format, Swizzle};
+use crate::hal::{buffer, image as i, pso, Primitive};
+use crate::info::TextureCompression;
 use crate::native::VertexAttribFunction;
 
 /*
@@ -18,6 +19,18 @@ pub fn _image_kind_to_gl(kind: i::Kind) -> t::GLenum {
     }
 }*/
 
+pub fn view_kind_to_gl(kind: i::ViewKind) -> t::GLenum {
+    match kind {
+        i::ViewKind::D1 => gl::TEXTURE_1D,
+        i::ViewKind::D1Array => gl::TEXTURE_1D_ARRAY,
+        i::ViewKind::D2 => gl::TEXTURE_2D,
+        i::ViewKind::D2Array => gl::TEXTURE_2D_ARRAY,
+        i::ViewKind::D3 => gl::TEXTURE_3D,
+        i::ViewKind::Cube => gl::TEXTURE_CUBE_MAP,
+        i::ViewKind::CubeArray => gl::TEXTURE_CUBE_MAP_ARRAY,
+    }
+}
+
 pub fn filter_to_gl(mag: i::Filter, min: i::Filter, mip: i::Filter) -> (t::GLenum, t::GLenum) {
     use crate::hal::image::Filter::*;
 
@@ -56,6 +69,28 @@ pub fn buffer_usage_to_gl_target(usage: buffer::Usage) -> Option<t::GLenum> {
     }
 }
 
+pub fn logic_op_to_gl(op: &pso::LogicOp) -> t::GLenum {
+    use crate::hal::pso::LogicOp::*;
+    match *op {
+        Clear => gl::CLEAR,
+        And => gl::AND,
+        AndReverse => gl::AND_REVERSE,
+        Copy => gl::COPY,
+        AndInverted => gl::AND_INVERTED,
+        NoOp => gl::NOOP,
+        Xor => gl::XOR,
+        Or => gl::OR,
+        Nor => gl::NOR,
+        Equivalent => gl::EQUIV,
+        Invert => gl::INVERT,
+        OrReverse => gl::OR_REVERSE,
+        CopyInverted => gl::COPY_INVERTED,
+        OrInverted => gl::OR_INVERTED,
+        Nand => gl::NAND,
+        Set => gl::SET,
+    }
+}
+
 pub fn primitive_to_gl_primitive(primitive: Primitive) -> t::GLenum {
     match primitive {
         Primitive::PointList => gl::POINTS,
@@ -71,6 +106,147 @@ pub fn primitive_to_gl_primitive(primitive: Primitive) -> t::GLenum {
     }
 }
 
+/// Returns the primitive mode of transform feedback capturing the given primitives.
+pub fn primitive_to_feedback_mode(primitive: Primitive) -> Option<t::GLenum> {
+    match primitive {
+        Primitive::PointList => Some(gl::POINTS),
+        Primitive::LineList | Primitive::LineStrip => Some(gl::LINES),
+        Primitive::TriangleList | Primitive::TriangleStrip => Some(gl::TRIANGLES),
+        _ => None,
+    }
+}
+
+/// Returns the internal format, pixel format and pixel type used for
+/// allocating images of the given format.
+pub fn image_format_to_gl(format: Format) -> Option<(t::GLenum, t::GLenum, t::GLenum)> {
+    use crate::hal::format::Format::*;
+    let format = match format {
+        Rgba8Unorm => (gl::RGBA8, gl::RGBA, gl::UNSIGNED_BYTE),
+        Rgba8Srgb => (gl::SRGB8_ALPHA8, gl::RGBA, gl::UNSIGNED_BYTE),
+        Bgra8Unorm => (gl::RGBA8, gl::BGRA, gl::UNSIGNED_BYTE),
+        Bgra8Srgb => (gl::SRGB8_ALPHA8, gl::BGRA, gl::UNSIGNED_BYTE),
+        A2b10g10r10Unorm => (gl::RGB10_A2, gl::RGBA, gl::UNSIGNED_INT_2_10_10_10_REV),
+        Rgba16Unorm => (gl::RGBA16, gl::RGBA, gl::UNSIGNED_SHORT),
+        Rgba16Sfloat => (gl::RGBA16F, gl::RGBA, gl::HALF_FLOAT),
+        _ => return None,
+    };
+
+    Some(format)
+}
+
+/// Returns the internal format of a compressed format, along with the
+/// compression family which needs to be supported for it.
+pub fn compressed_format_to_gl(format: Format) -> Option<(t::GLenum, TextureCompression)> {
+    use crate::hal::format::Format::*;
+
+    // Enums of extensions not covered by the generated bindings.
+    const COMPRESSED_RGB_S3TC_DXT1: t::GLenum = 0x83F0;
+    const COMPRESSED_RGBA_S3TC_DXT1: t::GLenum = 0x83F1;
+    const COMPRESSED_RGBA_S3TC_DXT3: t::GLenum = 0x83F2;
+    const COMPRESSED_RGBA_S3TC_DXT5: t::GLenum = 0x83F3;
+    const COMPRESSED_SRGB_S3TC_DXT1: t::GLenum = 0x8C4C;
+    const COMPRESSED_SRGB_ALPHA_S3TC_DXT1: t::GLenum = 0x8C4D;
+    const COMPRESSED_SRGB_ALPHA_S3TC_DXT3: t::GLenum = 0x8C4E;
+    const COMPRESSED_SRGB_ALPHA_S3TC_DXT5: t::GLenum = 0x8C4F;
+    const COMPRESSED_RGBA_ASTC_4X4: t::GLenum = 0x93B0;
+    const COMPRESSED_SRGB8_ALPHA8_ASTC_4X4: t::GLenum = 0x93D0;
+
+    let s3tc = TextureCompression::S3TC;
+    let s3tc_srgb = TextureCompression::S3TC_SRGB;
+    let rgtc = TextureCompression::RGTC;
+    let bptc = TextureCompression::BPTC;
+    let etc2 = TextureCompression::ETC2;
+    let astc = TextureCompression::ASTC_LDR;
+
+    // ASTC enums are laid out by block size, in the same order for both color spaces.
+    let astc_block = match format {
+        Astc4x4Unorm | Astc4x4Srgb => Some(0),
+        Astc5x4Unorm | Astc5x4Srgb => Some(1),
+        Astc5x5Unorm | Astc5x5Srgb => Some(2),
+        Astc6x5Unorm | Astc6x5Srgb => Some(3),
+        Astc6x6Unorm | Astc6x6Srgb => Some(4),
+        Astc8x5Unorm | Astc8x5Srgb => Some(5),
+        Astc8x6Unorm | Astc8x6Srgb => Some(6),
+        Astc8x8Unorm | Astc8x8Srgb => Some(7),
+        Astc10x5Unorm | Astc10x5Srgb => Some(8),
+        Astc10x6Unorm | Astc10x6Srgb => Some(9),
+        Astc10x8Unorm | Astc10x8Srgb => Some(10),
+        Astc10x10Unorm | Astc10x10Srgb => Some(11),
+        Astc12x10Unorm | Astc12x10Srgb => Some(12),
+        Astc12x12Unorm | Astc12x12Srgb => Some(13),
+        _ => None,
+    };
+    if let Some(block) = astc_block {
+        let base = match format.base_format().1 {
+            ChannelType::Srgb => COMPRESSED_SRGB8_ALPHA8_ASTC_4X4,
+            _ => COMPRESSED_RGBA_ASTC_4X4,
+        };
+        return Some((base + block, astc));
+    }
+
+    let format = match format {
+        Bc1RgbUnorm => (COMPRESSED_RGB_S3TC_DXT1, s3tc),
+        Bc1RgbSrgb => (COMPRESSED_SRGB_S3TC_DXT1, s3tc_srgb),
+        Bc1RgbaUnorm => (COMPRESSED_RGBA_S3TC_DXT1, s3tc),
+        Bc1RgbaSrgb => (COMPRESSED_SRGB_ALPHA_S3TC_DXT1, s3tc_srgb),
+        Bc2Unorm => (COMPRESSED_RGBA_S3TC_DXT3, s3tc),
+        Bc2Srgb => (COMPRESSED_SRGB_ALPHA_S3TC_DXT3, s3tc_srgb),
+        Bc3Unorm => (COMPRESSED_RGBA_S3TC_DXT5, s3tc),
+        Bc3Srgb => (COMPRESSED_SRGB_ALPHA_S3TC_DXT5, s3tc_srgb),
+        Bc4Unorm => (gl::COMPRESSED_RED_RGTC1, rgtc),
+        Bc4Snorm => (gl::COMPRESSED_SIGNED_RED_RGTC1, rgtc),
+        Bc5Unorm => (gl::COMPRESSED_RG_RGTC2, rgtc),
+        Bc5Snorm => (gl::COMPRESSED_SIGNED_RG_RGTC2, rgtc),
+        Bc6hUfloat => (gl::COMPRESSED_RGB_BPTC_UNSIGNED_FLOAT, bptc),
+        Bc6hSfloat => (gl::COMPRESSED_RGB_BPTC_SIGNED_FLOAT, bptc),
+        Bc7Unorm => (gl::COMPRESSED_RGBA_BPTC_UNORM, bptc),
+        Bc7Srgb => (gl::COMPRESSED_SRGB_ALPHA_BPTC_UNORM, bptc),
+        Etc2R8g8b8Unorm => (gl::COMPRESSED_RGB8_ETC2, etc2),
+        Etc2R8g8b8Srgb => (gl::COMPRESSED_SRGB8_ETC2, etc2),
+        Etc2R8g8b8a1Unorm => (gl::COMPRESSED_RGB8_PUNCHTHROUGH_ALPHA1_ETC2, etc2),
+        Etc2R8g8b8a1Srgb => (gl::COMPRESSED_SRGB8_PUNCHTHROUGH_ALPHA1_ETC2, etc2),
+        Etc2R8g8b8a8Unorm => (gl::COMPRESSED_RGBA8_ETC2_EAC, etc2),
+        Etc2R8g8b8a8Srgb => (gl::COMPRESSED_SRGB8_ALPHA8_ETC2_EAC, etc2),
+        EacR11Unorm => (gl::COMPRESSED_R11_EAC, etc2),
+        EacR11Snorm => (gl::COMPRESSED_SIGNED_R11_EAC, etc2),
+        EacR11g11Unorm => (gl::COMPRESSED_RG11_EAC, etc2),
+        EacR11g11Snorm => (gl::COMPRESSED_SIGNED_RG11_EAC, etc2),
+        _ => return None,
+    };
+
+    Some(format)
+}
+
+/// Size in bytes of an image region, rounded up to whole blocks for compressed formats.
+pub fn image_data_size(format: Format, width: u32, height: u32, depth: u32) -> u64 {
+    let desc = format.base_format().0.desc();
+    let (block_width, block_height) = (desc.dim.0 as u32, desc.dim.1 as u32);
+    let blocks_x = (width + block_width - 1) / block_width;
+    let blocks_y = (height + block_height - 1) / block_height;
+    (blocks_x * blocks_y * depth) as u64 * (desc.bits / 8) as u64
+}
+
+fn component_to_gl(component: Component) -> t::GLint {
+    (match component {
+        Component::Zero => gl::ZERO,
+        Component::One => gl::ONE,
+        Component::R => gl::RED,
+        Component::G => gl::GREEN,
+        Component::B => gl::BLUE,
+        Component::A => gl::ALPHA,
+    }) as t::GLint
+}
+
+/// Returns the values for `GL_TEXTURE_SWIZZLE_RGBA`.
+pub fn swizzle_to_gl(swizzle: Swizzle) -> [t::GLint; 4] {
+    [
+        component_to_gl(swizzle.0),
+        component_to_gl(swizzle.1),
+        component_to_gl(swizzle.2),
+        component_to_gl(swizzle.3),
+    ]
+}
+
 pub fn format_to_gl_format(
     format: Format,
 ) -> Option<(gl::types::GLint, gl::types::GLenum, VertexAttribFunction)> {
@@ -113,3 +289,82 @@ pub fn format_to_gl_format(
 
     Some(format)
 }
+
+/// Size in bytes of a uniform type push constant members can be mapped to.
+pub fn uniform_type_size(ty: t::GLenum) -> Option<u32> {
+    Some(match ty {
+        gl::FLOAT | gl::INT | gl::UNSIGNED_INT | gl::BOOL => 4,
+        gl::FLOAT_VEC2 | gl::INT_VEC2 | gl::UNSIGNED_INT_VEC2 | gl::BOOL_VEC2 => 8,
+        gl::FLOAT_VEC3 | gl::INT_VEC3 | gl::UNSIGNED_INT_VEC3 | gl::BOOL_VEC3 => 12,
+        gl::FLOAT_VEC4 | gl::INT_VEC4 | gl::UNSIGNED_INT_VEC4 | gl::BOOL_VEC4 => 16,
+        gl::FLOAT_MAT2 => 16,
+        gl::FLOAT_MAT4 => 64,
+        // `mat3` columns are padded to 16 bytes in push constant blocks,
+        // unlike the tightly packed uniform data.
+        _ => return None,
+    })
+}
+
+/// Memory barrier bits making incoherent writes visible to the given buffer accesses.
+/// Accesses without a dedicated bit fall back to all barrier bits.
+pub fn buffer_access_to_barrier(access: buffer::Access) -> t::GLbitfield {
+    use crate::hal::buffer::Access as A;
+
+    if access.intersects(A::MEMORY_READ | A::MEMORY_WRITE) {
+        return gl::ALL_BARRIER_BITS;
+    }
+
+    let mut bits = 0;
+    if access.contains(A::INDIRECT_COMMAND_READ) {
+        bits |= gl::COMMAND_BARRIER_BIT;
+    }
+    if access.contains(A::INDEX_BUFFER_READ) {
+        bits |= gl::ELEMENT_ARRAY_BARRIER_BIT;
+    }
+    if access.contains(A::VERTEX_BUFFER_READ) {
+        bits |= gl::VERTEX_ATTRIB_ARRAY_BARRIER_BIT;
+    }
+    if access.contains(A::CONSTANT_BUFFER_READ) {
+        bits |= gl::UNIFORM_BARRIER_BIT;
+    }
+    if access.intersects(A::SHADER_READ | A::SHADER_WRITE) {
+        bits |= gl::SHADER_STORAGE_BARRIER_BIT | gl::TEXTURE_FETCH_BARRIER_BIT;
+    }
+    if access.intersects(A::TRANSFER_READ | A::TRANSFER_WRITE) {
+        bits |= gl::BUFFER_UPDATE_BARRIER_BIT | gl::PIXEL_BUFFER_BARRIER_BIT;
+    }
+    if access.intersects(A::HOST_READ | A::HOST_WRITE) {
+        bits |= gl::CLIENT_MAPPED_BUFFER_BARRIER_BIT | gl::BUFFER_UPDATE_BARRIER_BIT;
+    }
+    bits
+}
+
+/// Memory barrier bits making incoherent writes visible to the given image accesses.
+/// Accesses without a dedicated bit fall back to all barrier bits.
+pub fn image_access_to_barrier(access: i::Access) -> t::GLbitfield {
+    use crate::hal::image::Access as A;
+
+    if access.intersects(A::MEMORY_READ | A::MEMORY_WRITE | A::HOST_READ | A::HOST_WRITE) {
+        return gl::ALL_BARRIER_BITS;
+    }
+
+    let mut bits = 0;
+    if access.intersects(A::INPUT_ATTACHMENT_READ | A::SHADER_READ) {
+        bits |= gl::TEXTURE_FETCH_BARRIER_BIT | gl::SHADER_IMAGE_ACCESS_BARRIER_BIT;
+    }
+    if access.contains(A::SHADER_WRITE) {
+        bits |= gl::SHADER_IMAGE_ACCESS_BARRIER_BIT;
+    }
+    if access.intersects(
+        A::COLOR_ATTACHMENT_READ
+            | A::COLOR_ATTACHMENT_WRITE
+            | A::DEPTH_STENCIL_ATTACHMENT_READ
+            | A::DEPTH_STENCIL_ATTACHMENT_WRITE,
+    ) {
+        bits |= gl::FRAMEBUFFER_BARRIER_BIT;
+    }
+    if access.intersects(A::TRANSFER_READ | A::TRANSFER_WRITE) {
+        bits |= gl::TEXTURE_UPDATE_BARRIER_BIT | gl::PIXEL_BUFFER_BARRIER_BIT;
+    }
+    bits
+}
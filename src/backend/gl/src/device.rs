@@ -3,13 +3,14 @@ use std::cell::Cell;
 use std::iter::repeat;
 use std::ops::Range;
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use std::{mem, ptr, slice};
 
 use crate::gl::types::{GLenum, GLfloat, GLint};
 use crate::{gl, GlContainer};
 
 use crate::hal::backend::FastHashMap;
-use crate::hal::format::{Format, Swizzle};
+use crate::hal::format::{Component, Format, SurfaceType, Swizzle};
 use crate::hal::pool::CommandPoolCreateFlags;
 use crate::hal::queue::QueueFamilyId;
 use crate::hal::range::RangeArg;
@@ -19,7 +20,9 @@ use spirv_cross::{glsl, spirv, ErrorCode as SpirvErrorCode};
 
 use crate::info::LegacyFeatures;
 use crate::pool::{BufferMemory, OwnedBuffer, RawCommandPool};
-use crate::{conv, native as n, state};
+use crate::queue::VertexArrayCache;
+use crate::transfer::{PixelTransfer, TransferPool};
+use crate::{command, conv, native as n, state};
 use crate::{Backend as B, Share, Starc, Surface, Swapchain};
 
 /// Emit error during shader module creation. Used if we don't expect an error
@@ -84,7 +87,106 @@ pub(crate) fn get_program_log(gl: &GlContainer, name: n::Program) -> String {
     }
 }
 
-fn create_fbo_internal(share: &Starc<Share>) -> Option<gl::types::GLuint> {
+/// Name given to push constant blocks in the generated GLSL.
+const PUSH_CONSTANT_NAME: &str = "_push_constants";
+
+/// Maps uniform names of push constant members to their offset and the
+/// stages accessing them.
+type PushConstantMap = FastHashMap<String, (u32, pso::ShaderStageFlags)>;
+
+/// Maps names of shader resources to the binding types and slots they have
+/// been assigned, for drivers without explicit layouts in shaders.
+type NameBindingMap = FastHashMap<String, (n::BindingTypes, pso::DescriptorBinding)>;
+
+/// Assign the binding slots of shader resources in a linked program.
+unsafe fn bind_program_resources(gl: &GlContainer, name: n::Program, bindings: &NameBindingMap) {
+    gl.UseProgram(name);
+    for (resource, &(btype, binding)) in bindings {
+        let c_name = format!("{}\0", resource);
+        let c_name = c_name.as_ptr() as *const gl::types::GLchar;
+        match btype {
+            n::BindingTypes::Images => {
+                let location = gl.GetUniformLocation(name, c_name);
+                gl.Uniform1i(location, binding as _);
+            }
+            n::BindingTypes::UniformBuffers => {
+                let index = gl.GetUniformBlockIndex(name, c_name);
+                gl.UniformBlockBinding(name, index, binding);
+            }
+            n::BindingTypes::StorageBuffers => {
+                let index = gl.GetProgramResourceIndex(name, gl::SHADER_STORAGE_BLOCK, c_name);
+                gl.ShaderStorageBlockBinding(name, index, binding);
+            }
+            // Image uniforms hold the image unit, like samplers hold the texture unit.
+            n::BindingTypes::StorageImages => {
+                let location = gl.GetUniformLocation(name, c_name);
+                gl.Uniform1i(location, binding as _);
+            }
+        }
+    }
+}
+
+/// Query the uniforms emulating push constants of a linked program.
+fn get_push_constant_uniforms(
+    gl: &GlContainer,
+    name: n::Program,
+    push_constants: &PushConstantMap,
+) -> Vec<n::UniformDesc> {
+    let mut uniforms = Vec::new();
+    if push_constants.is_empty() {
+        return uniforms;
+    }
+
+    let count = get_program_iv(gl, name, gl::ACTIVE_UNIFORMS);
+    let max_length = get_program_iv(gl, name, gl::ACTIVE_UNIFORM_MAX_LENGTH);
+    let mut buffer = vec![0u8; max_length.max(1) as usize];
+    for index in 0..count as gl::types::GLuint {
+        let mut length = 0;
+        let mut array_size = 0;
+        let mut utype = 0;
+        unsafe {
+            gl.GetActiveUniform(
+                name,
+                index,
+                buffer.len() as _,
+                &mut length,
+                &mut array_size,
+                &mut utype,
+                buffer.as_mut_ptr() as *mut gl::types::GLchar,
+            )
+        };
+        let uniform_name = String::from_utf8_lossy(&buffer[..length as usize]);
+        // Arrays are reported by their first element.
+        let uniform_name = uniform_name.trim_end_matches("[0]");
+        let (offset, stages) = match push_constants.get(uniform_name) {
+            Some(&entry) => entry,
+            None => continue,
+        };
+        let size = match conv::uniform_type_size(utype) {
+            Some(size) => size * array_size as u32,
+            None => {
+                warn!(
+                    "Push constant {} has unsupported type 0x{:x}",
+                    uniform_name, utype
+                );
+                continue;
+            }
+        };
+        let c_name = format!("{}\0", uniform_name);
+        let location = unsafe { gl.GetUniformLocation(name, c_name.as_ptr() as _) };
+        uniforms.push(n::UniformDesc {
+            location,
+            utype,
+            offset,
+            size,
+            stages,
+        });
+    }
+
+    uniforms
+}
+
+pub(crate) fn create_fbo_internal(share: &Starc<Share>) -> Option<gl::types::GLuint> {
     if share.private_caps.framebuffer {
         let gl = &share.context;
         let mut name = 0 as n::FrameBuffer;
@@ -98,42 +200,292 @@ fn create_fbo_internal(share: &Starc<Share>) -> Option<gl::types::GLuint> {
     }
 }
 
+/// Vertex outputs of a graphics pipeline captured into transform feedback buffers.
+#[derive(Clone, Copy, Debug)]
+pub struct TransformFeedbackDesc<'a> {
+    /// Names of the captured outputs of the last vertex processing stage,
+    /// as declared in the GLSL source or translated from SPIR-V.
+    pub varyings: &'a [&'a str],
+    /// Write all outputs to a single buffer instead of one buffer per output.
+    pub interleaved: bool,
+}
+
 /// GL device.
 #[derive(Debug)]
 pub struct Device {
-    share: Starc<Share>,
+    pub(crate) share: Starc<Share>,
+    // Sampler objects shared between identical samplers, with their
+    // reference count.
+    samplers: Mutex<FastHashMap<i::SamplerInfo, (n::Sampler, usize)>>,
+    // Vertex array objects used by the queue, invalidated when destroying
+    // the vertex buffers they reference.
+    vertex_arrays: Arc<Mutex<VertexArrayCache>>,
+    // Pixel buffer objects used by `write_image` and `read_image`.
+    transfers: Mutex<TransferPool>,
 }
 
 impl Drop for Device {
     fn drop(&mut self) {
+        let gl = &self.share.context;
+        for (_, (sampler, _)) in self.samplers.get_mut().unwrap().drain() {
+            unsafe { gl.DeleteSamplers(1, &sampler) };
+        }
+        self.vertex_arrays.lock().unwrap().clear(gl);
+        self.transfers.get_mut().unwrap().clear(&self.share);
         self.share.open.set(false);
     }
 }
 
 impl Device {
     /// Create a new `Device`.
-    pub(crate) fn new(share: Starc<Share>) -> Self {
-        Device { share: share }
+    pub(crate) fn new(share: Starc<Share>, vertex_arrays: Arc<Mutex<VertexArrayCache>>) -> Self {
+        Device {
+            share: share,
+            samplers: Mutex::new(FastHashMap::default()),
+            vertex_arrays,
+            transfers: Mutex::new(TransferPool::new()),
+        }
     }
 
-    pub fn create_shader_module_from_source(
-        &self,
-        data: &[u8],
-        stage: pso::Stage,
-    ) -> Result<n::ShaderModule, d::ShaderError> {
+    /// Number of nanoseconds per tick of timestamp queries.
+    ///
+    /// GL timestamps are always expressed in nanoseconds.
+    pub fn timestamp_period(&self) -> f32 {
+        1.0
+    }
+
+    /// Get the current GPU time, comparable with the results of timestamp
+    /// queries, or `None` if timer queries aren't supported.
+    pub fn timestamp(&self) -> Option<u64> {
         let gl = &self.share.context;
+        if !gl.QueryCounter.is_loaded() {
+            return None;
+        }
+        let mut timestamp = 0;
+        unsafe { gl.GetInteger64v(gl::TIMESTAMP, &mut timestamp) };
+        Some(timestamp as u64)
+    }
+
+    /// Convert the difference between two timestamp query results into a
+    /// duration.
+    pub fn timestamp_duration(&self, begin: u64, end: u64) -> Duration {
+        let ticks = end.saturating_sub(begin);
+        Duration::from_nanos((ticks as f64 * self.timestamp_period() as f64) as u64)
+    }
+
+    /// Create a graphics pipeline capturing its vertex outputs into the
+    /// bound transform feedback buffers, while transform feedback is active.
+    pub unsafe fn create_graphics_pipeline_with_feedback<'a>(
+        &self,
+        desc: &pso::GraphicsPipelineDesc<'a, B>,
+        cache: Option<&n::PipelineCache>,
+        feedback: &TransformFeedbackDesc,
+    ) -> Result<n::GraphicsPipeline, pso::CreationError> {
+        if !self.share.private_caps.transform_feedback {
+            error!("Transform feedback is not supported");
+            return Err(pso::CreationError::Other);
+        }
+        self.build_graphics_pipeline(desc, cache, Some(feedback))
+    }
+
+    unsafe fn build_graphics_pipeline<'a>(
+        &self,
+        desc: &pso::GraphicsPipelineDesc<'a, B>,
+        cache: Option<&n::PipelineCache>,
+        feedback: Option<&TransformFeedbackDesc>,
+    ) -> Result<n::GraphicsPipeline, pso::CreationError> {
+        let subpass = {
+            let subpass = desc.subpass;
+            match subpass.main_pass.subpasses.get(subpass.index) {
+                Some(sp) => sp,
+                None => return Err(pso::CreationError::InvalidSubpass(subpass.index)),
+            }
+        };
+
+        // Without independent blending all attachments share a single blend state.
+        if !self
+            .share
+            .features
+            .contains(c::Features::INDEPENDENT_BLENDING)
+        {
+            if let Some((first, rest)) = desc.blender.targets.split_first() {
+                if rest.iter().any(|target| target != first) {
+                    error!("Independent blending is not supported");
+                    return Err(pso::CreationError::Other);
+                }
+            }
+        }
+
+        if desc.blender.logic_op.is_some() && !self.share.features.contains(c::Features::LOGIC_OP) {
+            error!("Logic operations are not supported");
+            return Err(pso::CreationError::Other);
+        }
+        // Logic operations replace blending, only keep the write masks.
+        let blend_targets = match desc.blender.logic_op {
+            Some(_) => desc
+                .blender
+                .targets
+                .iter()
+                .map(|target| pso::ColorBlendDesc(target.0, pso::BlendState::Off))
+                .collect(),
+            None => desc.blender.targets.clone(),
+        };
+
+        let polygon_mode = desc.rasterizer.polygon_mode;
+        if polygon_mode != pso::PolygonMode::Fill
+            && !self
+                .share
+                .features
+                .contains(c::Features::NON_FILL_POLYGON_MODE)
+        {
+            error!("Polygon mode {:?} is not supported", polygon_mode);
+            return Err(pso::CreationError::Other);
+        }
+
+        let depth_clamp = self.share.features.contains(c::Features::DEPTH_CLAMP);
+        if desc.rasterizer.depth_clamping && !depth_clamp {
+            error!("Depth clamping is not supported");
+            return Err(pso::CreationError::Other);
+        }
+
+        if let Some(pso::State::Static(bias)) = desc.rasterizer.depth_bias {
+            if bias.clamp != 0.0 {
+                warn!("Depth bias clamp is not supported");
+            }
+        }
+
+        let (program, uniforms) = {
+            let shaders = [
+                (pso::Stage::Vertex, Some(&desc.shaders.vertex)),
+                (pso::Stage::Hull, desc.shaders.hull.as_ref()),
+                (pso::Stage::Domain, desc.shaders.domain.as_ref()),
+                (pso::Stage::Geometry, desc.shaders.geometry.as_ref()),
+                (pso::Stage::Fragment, desc.shaders.fragment.as_ref()),
+            ];
+
+            let mut name_binding_map = NameBindingMap::default();
+            let mut push_constants = PushConstantMap::default();
+            let stages = shaders
+                .iter()
+                .filter_map(|&(stage, point_maybe)| point_maybe.map(|point| (stage, point)))
+                .map(|(stage, point)| {
+                    self.translate_shader(
+                        point,
+                        stage,
+                        &mut desc.layout.desc_remap_data.write().unwrap(),
+                        &mut name_binding_map,
+                        &mut push_constants,
+                    )
+                    .map(|source| (stage, source))
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(pso::CreationError::Shader)?;
+            self.check_texture_units(&desc.layout.desc_remap_data.read().unwrap())?;
+
+            self.link_program(
+                &stages,
+                subpass.color_attachments.len(),
+                cache,
+                &name_binding_map,
+                &push_constants,
+                feedback,
+            )
+            .map_err(|log| pso::CreationError::Shader(d::ShaderError::CompilationFailed(log)))?
+        };
+
+        let patch_size = match desc.input_assembler.primitive {
+            c::Primitive::PatchList(size) => Some(size as _),
+            _ => None,
+        };
+
+        let primitive_restart = desc.input_assembler.primitive_restart;
+        if primitive_restart != pso::PrimitiveRestart::Disabled
+            && !self.share.private_caps.primitive_restart
+            && !self.share.private_caps.primitive_restart_fixed_index
+        {
+            error!("Primitive restart is not supported");
+            return Err(pso::CreationError::Other);
+        }
+
+        let mut vertex_buffers = Vec::new();
+        for vb in &desc.vertex_buffers {
+            while vertex_buffers.len() <= vb.binding as usize {
+                vertex_buffers.push(None);
+            }
+            vertex_buffers[vb.binding as usize] = Some(*vb);
+        }
+
+        Ok(n::GraphicsPipeline {
+            program,
+            primitive: conv::primitive_to_gl_primitive(desc.input_assembler.primitive),
+            patch_size,
+            primitive_restart,
+            line_width: match desc.rasterizer.polygon_mode {
+                pso::PolygonMode::Line(width) => Some(width),
+                _ => None,
+            },
+            rasterizer: desc.rasterizer.clone(),
+            depth_stencil: desc.depth_stencil,
+            logic_op: desc.blender.logic_op.as_ref().map(conv::logic_op_to_gl),
+            blend_targets,
+            vertex_buffers,
+            uniforms,
+            attributes: desc
+                .attributes
+                .iter()
+                .map(|&a| {
+                    let (size, format, vertex_attrib_fn) =
+                        conv::format_to_gl_format(a.element.format).unwrap();
+                    n::AttributeDesc {
+                        location: a.location,
+                        offset: a.element.offset,
+                        binding: a.binding,
+                        size,
+                        format,
+                        vertex_attrib_fn,
+                    }
+                })
+                .collect(),
+        })
+    }
 
+    fn shader_target(&self, stage: pso::Stage) -> Result<GLenum, d::ShaderError> {
         let can_compute = self.share.limits.max_compute_work_group_count[0] != 0;
-        let can_tessellate = self.share.limits.max_patch_size != 0;
-        let target = match stage {
+        let features = self.share.features;
+        let can_tessellate = features.contains(c::Features::TESSELLATION_SHADER);
+        let can_geometry = features.contains(c::Features::GEOMETRY_SHADER);
+        Ok(match stage {
             pso::Stage::Vertex => gl::VERTEX_SHADER,
             pso::Stage::Hull if can_tessellate => gl::TESS_CONTROL_SHADER,
             pso::Stage::Domain if can_tessellate => gl::TESS_EVALUATION_SHADER,
-            pso::Stage::Geometry => gl::GEOMETRY_SHADER,
+            pso::Stage::Geometry if can_geometry => gl::GEOMETRY_SHADER,
             pso::Stage::Fragment => gl::FRAGMENT_SHADER,
             pso::Stage::Compute if can_compute => gl::COMPUTE_SHADER,
             _ => return Err(d::ShaderError::UnsupportedStage(stage)),
-        };
+        })
+    }
+
+    fn check_shader_status(&self, name: n::Shader) -> Result<n::Shader, d::ShaderError> {
+        let gl = &self.share.context;
+        let status = get_shader_iv(gl, name, gl::COMPILE_STATUS);
+        let log = get_shader_log(gl, name);
+        if status != 0 {
+            if !log.is_empty() {
+                warn!("\tLog: {}", log);
+            }
+            Ok(name)
+        } else {
+            Err(d::ShaderError::CompilationFailed(log))
+        }
+    }
+
+    pub fn create_shader_module_from_source(
+        &self,
+        data: &[u8],
+        stage: pso::Stage,
+    ) -> Result<n::ShaderModule, d::ShaderError> {
+        let gl = &self.share.context;
+        let target = self.shader_target(stage)?;
 
         let name = unsafe { gl.CreateShader(target) };
         unsafe {
@@ -150,16 +502,367 @@ impl Device {
             panic!("Error compiling shader: {:?}", err);
         }
 
-        let status = get_shader_iv(gl, name, gl::COMPILE_STATUS);
-        let log = get_shader_log(gl, name);
-        if status != 0 {
-            if !log.is_empty() {
-                warn!("\tLog: {}", log);
+        self.check_shader_status(name).map(n::ShaderModule::Raw)
+    }
+
+    /// Label a GL object with `glObjectLabel`, if `GL_KHR_debug` is supported.
+    fn set_object_name(
+        &self,
+        identifier: gl::types::GLenum,
+        object: gl::types::GLuint,
+        name: &str,
+    ) {
+        if !self.share.private_caps.debug_output {
+            return;
+        }
+        let gl = &self.share.context;
+        unsafe {
+            gl.ObjectLabel(
+                identifier,
+                object,
+                name.len() as _,
+                name.as_ptr() as *const gl::types::GLchar,
+            );
+        }
+    }
+
+    /// Set the name of a buffer, shown by debugging tools and in the messages
+    /// of the debug callback.
+    pub fn set_buffer_name(&self, buffer: &n::Buffer, name: &str) {
+        // Buffer objects only exist once bound.
+        let gl = &self.share.context;
+        unsafe {
+            gl.BindBuffer(buffer.target, buffer.raw);
+            gl.BindBuffer(buffer.target, 0);
+        }
+        self.set_object_name(gl::BUFFER, buffer.raw, name);
+    }
+
+    /// Set the name of an image, shown by debugging tools and in the messages
+    /// of the debug callback.
+    pub fn set_image_name(&self, image: &n::Image, name: &str) {
+        match image.kind {
+            n::ImageKind::Surface(surface) => self.set_object_name(gl::RENDERBUFFER, surface, name),
+            n::ImageKind::Texture(texture) => self.set_object_name(gl::TEXTURE, texture, name),
+        }
+    }
+
+    /// Set the name of a sampler, shown by debugging tools and in the
+    /// messages of the debug callback.
+    pub fn set_sampler_name(&self, sampler: &n::FatSampler, name: &str) {
+        match *sampler {
+            n::FatSampler::Sampler(sampler) => self.set_object_name(gl::SAMPLER, sampler, name),
+            // Sampler states are applied to the textures directly.
+            n::FatSampler::Info(_) => {}
+        }
+    }
+
+    /// Set the name of a shader module, shown by debugging tools and in the
+    /// messages of the debug callback.
+    ///
+    /// SPIR-V modules are only translated when creating pipelines, name the
+    /// pipelines instead.
+    pub fn set_shader_module_name(&self, module: &n::ShaderModule, name: &str) {
+        match *module {
+            n::ShaderModule::Raw(shader) => self.set_object_name(gl::SHADER, shader, name),
+            n::ShaderModule::Spirv { .. } => {}
+        }
+    }
+
+    /// Set the name of the program of a graphics pipeline, shown by
+    /// debugging tools and in the messages of the debug callback.
+    pub fn set_graphics_pipeline_name(&self, pipeline: &n::GraphicsPipeline, name: &str) {
+        self.set_object_name(gl::PROGRAM, pipeline.program, name);
+    }
+
+    /// Set the name of the program of a compute pipeline, shown by debugging
+    /// tools and in the messages of the debug callback.
+    pub fn set_compute_pipeline_name(&self, pipeline: &n::ComputePipeline, name: &str) {
+        self.set_object_name(gl::PROGRAM, pipeline.program, name);
+    }
+
+    /// Read back the contents of a buffer, for debugging.
+    ///
+    /// Waits for all pending writes to the buffer. Shader storage writes are
+    /// made visible without recording a barrier. Fails if the range exceeds
+    /// the buffer, or if reading it raises a GL error.
+    pub fn read_buffer<R: RangeArg<u64>>(
+        &self,
+        buffer: &n::Buffer,
+        range: R,
+    ) -> Result<Vec<u8>, ()> {
+        let gl = &self.share.context;
+        let buffer_size = buffer.requirements.size;
+        let offset = *range.start().unwrap_or(&0);
+        let end = *range.end().unwrap_or(&buffer_size);
+        if offset > end || end > buffer_size {
+            error!(
+                "Range {}..{} exceeds buffer {:?} of size {}",
+                offset, end, buffer, buffer_size
+            );
+            return Err(());
+        }
+        let size = (end - offset) as usize;
+        let mut data = vec![0; size];
+
+        let target = gl::COPY_READ_BUFFER;
+        let mut mapped = true;
+        unsafe {
+            if gl.MemoryBarrier.is_loaded() {
+                gl.MemoryBarrier(gl::BUFFER_UPDATE_BARRIER_BIT);
             }
-            Ok(n::ShaderModule::Raw(name))
+            gl.BindBuffer(target, buffer.raw);
+            if gl.GetBufferSubData.is_loaded() {
+                gl.GetBufferSubData(target, offset as _, size as _, data.as_mut_ptr() as *mut _);
+            } else {
+                // GLES has no `glGetBufferSubData`, map the range instead.
+                let ptr = gl.MapBufferRange(target, offset as _, size as _, gl::MAP_READ_BIT);
+                if ptr.is_null() {
+                    mapped = false;
+                } else {
+                    ptr::copy_nonoverlapping(ptr as *const u8, data.as_mut_ptr(), size);
+                    gl.UnmapBuffer(target);
+                }
+            }
+            gl.BindBuffer(target, 0);
+        }
+
+        if !mapped {
+            error!("Failed to map buffer {:?} for reading", buffer);
+            return Err(());
+        }
+        if let Err(err) = self.share.check() {
+            error!("Error {:?} reading back buffer {:?}", err, buffer);
+            return Err(());
+        }
+        Ok(data)
+    }
+
+    /// Configure the pixel buffer objects used by `write_image` and
+    /// `read_image`: `count` buffers of `size` bytes, used in turn.
+    ///
+    /// Defaults to 3 buffers of 4 MiB. Pending read-backs are discarded.
+    pub fn set_transfer_pool(&self, count: usize, size: u64) {
+        self.transfers
+            .lock()
+            .unwrap()
+            .configure(&self.share, count, size);
+    }
+
+    /// Upload tightly packed texels to a region of a 2D image level, going
+    /// through a pixel buffer object.
+    ///
+    /// Returns once the data is copied into the buffer, the transfer to the
+    /// image is executed asynchronously, in order with submitted commands.
+    /// Only blocks when the next buffer is still used by a previous transfer.
+    pub fn write_image(
+        &self,
+        image: &n::Image,
+        level: i::Level,
+        offset: i::Offset,
+        extent: i::Extent,
+        data: &[u8],
+    ) -> Result<(), ()> {
+        self.transfers
+            .lock()
+            .unwrap()
+            .write(&self.share, image, level, offset, extent, data)
+    }
+
+    /// Start reading back a region of a 2D image level into a pixel buffer
+    /// object, in order with submitted commands.
+    ///
+    /// The buffer stays reserved until the data is collected with
+    /// `finish_read`, or the returned transfer is dropped.
+    pub fn read_image(
+        &self,
+        image: &n::Image,
+        level: i::Level,
+        offset: i::Offset,
+        extent: i::Extent,
+    ) -> Result<PixelTransfer, ()> {
+        self.transfers
+            .lock()
+            .unwrap()
+            .read(&self.share, image, level, offset, extent)
+    }
+
+    /// Check if a read-back completed, so that `finish_read` won't block.
+    ///
+    /// Always `true` without sync objects, `finish_read` may block then.
+    pub fn is_transfer_complete(&self, transfer: &PixelTransfer) -> bool {
+        self.transfers
+            .lock()
+            .unwrap()
+            .is_complete(&self.share, transfer)
+    }
+
+    /// Collect the tightly packed texels of a read-back, waiting for it to
+    /// complete.
+    pub fn finish_read(&self, transfer: PixelTransfer) -> Result<Vec<u8>, ()> {
+        self.transfers
+            .lock()
+            .unwrap()
+            .finish_read(&self.share, transfer)
+    }
+
+    /// Create a shader from a SPIR-V binary through `GL_ARB_gl_spirv`.
+    fn create_shader_from_spirv(
+        &self,
+        words: &[u32],
+        constants: &[(u32, u32)],
+        stage: pso::Stage,
+    ) -> Result<n::Shader, d::ShaderError> {
+        let gl = &self.share.context;
+        let target = self.shader_target(stage)?;
+        let specialize_shader = gl.specialize_shader.unwrap();
+        let (indices, values): (Vec<_>, Vec<_>) = constants.iter().cloned().unzip();
+
+        let name = unsafe { gl.CreateShader(target) };
+        unsafe {
+            gl.ShaderBinary(
+                1,
+                &name,
+                SHADER_BINARY_FORMAT_SPIR_V,
+                words.as_ptr() as *const _,
+                (words.len() * mem::size_of::<u32>()) as _,
+            );
+            specialize_shader(
+                name,
+                b"main\0".as_ptr() as *const _,
+                constants.len() as _,
+                indices.as_ptr(),
+                values.as_ptr(),
+            );
+        }
+        info!("\tSpecialized shader {}", name);
+        if let Err(err) = self.share.check() {
+            panic!("Error specializing shader: {:?}", err);
+        }
+
+        self.check_shader_status(name)
+    }
+
+    /// Create a view with its own texture object, for swizzles and format
+    /// reinterpretation which can't be applied on the image texture itself.
+    unsafe fn create_texture_view(
+        &self,
+        image: &n::Image,
+        kind: i::ViewKind,
+        format: Format,
+        swizzle: Swizzle,
+        range: i::SubresourceRange,
+    ) -> Result<n::ImageView, i::ViewError> {
+        let gl = &self.share.context;
+
+        let texture = match image.kind {
+            n::ImageKind::Texture(texture) if self.share.private_caps.texture_view => texture,
+            _ => {
+                error!(
+                    "Texture views are required for swizzle {:?} and format {:?}",
+                    swizzle, format
+                );
+                return Err(i::ViewError::Unsupported);
+            }
+        };
+
+        // Views only share storage between formats of the same size class.
+        let view_format = match conv::image_format_to_gl(format) {
+            Some((int_format, _, _))
+                if format.base_format().0.desc().bits
+                    == image.format.base_format().0.desc().bits =>
+            {
+                int_format
+            }
+            _ => {
+                error!(
+                    "Format {:?} can't be used to view an image of format {:?}",
+                    format, image.format
+                );
+                return Err(i::ViewError::BadFormat(format));
+            }
+        };
+
+        // Images are allocated as 2D textures, which only alias 2D views.
+        let target = match kind {
+            i::ViewKind::D2 | i::ViewKind::D2Array => conv::view_kind_to_gl(kind),
+            _ => {
+                error!("Views of kind {:?} aren't supported for 2D images", kind);
+                return Err(i::ViewError::BadKind(kind));
+            }
+        };
+        if kind == i::ViewKind::D2 && range.layers.end - range.layers.start != 1 {
+            return Err(i::ViewError::Layer(i::LayerError::OutOfBounds(
+                range.layers,
+            )));
+        }
+
+        gl.BindTexture(gl::TEXTURE_2D, texture);
+        let mut immutable = 0;
+        gl.GetTexParameteriv(gl::TEXTURE_2D, gl::TEXTURE_IMMUTABLE_FORMAT, &mut immutable);
+        if immutable == 0 {
+            error!("Texture views require images allocated with immutable storage");
+            return Err(i::ViewError::Unsupported);
+        }
+
+        let mut name = 0;
+        gl.GenTextures(1, &mut name);
+        gl.TextureView(
+            name,
+            target,
+            texture,
+            view_format,
+            range.levels.start as _,
+            (range.levels.end - range.levels.start) as _,
+            range.layers.start as _,
+            (range.layers.end - range.layers.start) as _,
+        );
+
+        // GL keeps BGRA images in RGBA order, so reinterpreting between
+        // the two orders swaps the red and blue channels.
+        let is_bgra = |format: Format| format.base_format().0 == SurfaceType::B8_G8_R8_A8;
+        let swizzle = if is_bgra(format) != is_bgra(image.format) {
+            let swap = |component| match component {
+                Component::R => Component::B,
+                Component::B => Component::R,
+                other => other,
+            };
+            Swizzle(
+                swap(swizzle.0),
+                swap(swizzle.1),
+                swap(swizzle.2),
+                swap(swizzle.3),
+            )
         } else {
-            Err(d::ShaderError::CompilationFailed(log))
+            swizzle
+        };
+        if swizzle != Swizzle::NO {
+            gl.BindTexture(target, name);
+            gl.TexParameteriv(
+                target,
+                gl::TEXTURE_SWIZZLE_RGBA,
+                conv::swizzle_to_gl(swizzle).as_ptr(),
+            );
+            gl.BindTexture(target, 0);
+        }
+        gl.BindTexture(gl::TEXTURE_2D, 0);
+
+        if let Err(err) = self.share.check() {
+            error!(
+                "Error creating texture view: {:?} for {:?} of {:?}",
+                err, format, image.format
+            );
+            gl.DeleteTextures(1, &name);
+            return Err(match err {
+                crate::Error::OutOfMemory => d::OutOfMemory::OutOfDeviceMemory.into(),
+                crate::Error::InvalidValue => {
+                    i::ViewError::Layer(i::LayerError::OutOfBounds(range.layers))
+                }
+                _ => i::ViewError::Unsupported,
+            });
         }
+
+        Ok(n::ImageView::TextureView(name, target))
     }
 
     fn bind_target_compat(
@@ -176,6 +879,14 @@ impl Device {
                 gl.BindTexture(gl::TEXTURE_2D, texture);
                 gl.FramebufferTexture2D(point, attachment, gl::TEXTURE_2D, texture, level as _);
             },
+            n::ImageView::TextureView(texture, gl::TEXTURE_2D) => unsafe {
+                gl.BindTexture(gl::TEXTURE_2D, texture);
+                gl.FramebufferTexture2D(point, attachment, gl::TEXTURE_2D, texture, 0);
+            },
+            n::ImageView::TextureView(texture, target) => unsafe {
+                gl.BindTexture(target, texture);
+                gl.FramebufferTextureLayer(point, attachment, texture, 0, 0);
+            },
             n::ImageView::TextureLayer(texture, level, layer) => unsafe {
                 gl.BindTexture(gl::TEXTURE_2D, texture);
                 gl.FramebufferTexture3D(
@@ -198,6 +909,9 @@ impl Device {
             n::ImageView::Texture(texture, level) => unsafe {
                 gl.FramebufferTexture(point, attachment, texture, level as _);
             },
+            n::ImageView::TextureView(texture, _) => unsafe {
+                gl.FramebufferTexture(point, attachment, texture, 0);
+            },
             n::ImageView::TextureLayer(texture, level, layer) => unsafe {
                 gl.FramebufferTextureLayer(point, attachment, texture, level as _, layer as _);
             },
@@ -227,26 +941,20 @@ impl Device {
     fn specialize_ast(
         &self,
         ast: &mut spirv::Ast<glsl::Target>,
-        specialization: pso::Specialization,
+        constants: &[(u32, &[u8])],
     ) -> Result<(), d::ShaderError> {
         let spec_constants = ast
             .get_specialization_constants()
             .map_err(gen_unexpected_error)?;
 
         for spec_constant in spec_constants {
-            if let Some(constant) = specialization
-                .constants
+            if let Some(&(_, bytes)) = constants
                 .iter()
-                .find(|c| c.id == spec_constant.constant_id)
+                .find(|&&(id, _)| id == spec_constant.constant_id)
             {
-                // Override specialization constant values
-                let value = specialization.data
-                    [constant.range.start as usize..constant.range.end as usize]
-                    .iter()
-                    .rev()
-                    .fold(0u64, |u, &b| (u << 8) + b as u64);
-
-                ast.set_scalar_constant(spec_constant.id, value)
+                // Override specialization constant values, which end up
+                // as the defaults of the generated GLSL constants.
+                ast.set_scalar_constant(spec_constant.id, specialization_value(bytes))
                     .map_err(gen_unexpected_error)?;
             }
         }
@@ -260,7 +968,11 @@ impl Device {
     ) -> Result<String, d::ShaderError> {
         let mut compile_options = glsl::CompilerOptions::default();
         // see version table at https://en.wikipedia.org/wiki/OpenGL_Shading_Language
-        compile_options.version = match self.share.info.shading_language.tuple() {
+        let version = &self.share.info.shading_language;
+        compile_options.version = match version.tuple() {
+            // Newer ES versions aren't exposed by SPIRV-Cross yet.
+            (3, _) if version.is_embedded => glsl::Version::V3_00Es,
+            (1, _) if version.is_embedded => glsl::Version::V1_00Es,
             (4, 60) => glsl::Version::V4_60,
             (4, 50) => glsl::Version::V4_50,
             (4, 40) => glsl::Version::V4_40,
@@ -277,7 +989,6 @@ impl Device {
             other if other > (4, 60) => glsl::Version::V4_60,
             other => panic!("GLSL version is not recognized: {:?}", other),
         };
-        compile_options.vertex.invert_y = true;
         debug!("SPIR-V options {:?}", compile_options);
 
         ast.set_compiler_options(&compile_options)
@@ -295,7 +1006,7 @@ impl Device {
         &self,
         ast: &mut spirv::Ast<glsl::Target>,
         desc_remap_data: &mut n::DescRemapData,
-        nb_map: &mut FastHashMap<String, pso::DescriptorBinding>,
+        nb_map: &mut NameBindingMap,
     ) {
         let res = ast.get_shader_resources().unwrap();
         self.remap_binding(
@@ -312,13 +1023,62 @@ impl Device {
             &res.uniform_buffers,
             n::BindingTypes::UniformBuffers,
         );
+        self.remap_binding(
+            ast,
+            desc_remap_data,
+            nb_map,
+            &res.storage_buffers,
+            n::BindingTypes::StorageBuffers,
+        );
+        self.remap_binding(
+            ast,
+            desc_remap_data,
+            nb_map,
+            &res.storage_images,
+            n::BindingTypes::StorageImages,
+        );
+    }
+
+    fn reflect_push_constants(
+        &self,
+        ast: &mut spirv::Ast<glsl::Target>,
+        stage: pso::Stage,
+        push_constants: &mut PushConstantMap,
+    ) {
+        let res = ast.get_shader_resources().unwrap();
+        for block in &res.push_constant_buffers {
+            // Push constant blocks end up as plain struct uniforms, give
+            // them a known name to look their members up after linking.
+            ast.set_name(block.id, PUSH_CONSTANT_NAME).unwrap();
+
+            let member_count = match ast.get_type(block.base_type_id) {
+                Ok(spirv::Type::Struct {
+                    ref member_types, ..
+                }) => member_types.len() as u32,
+                _ => continue,
+            };
+            for index in 0..member_count {
+                let member = match ast.get_member_name(block.base_type_id, index) {
+                    Ok(ref name) if !name.is_empty() => name.clone(),
+                    // Matches the names generated by SPIRV-Cross.
+                    _ => format!("_m{}", index),
+                };
+                let offset = ast
+                    .get_member_decoration(block.base_type_id, index, spirv::Decoration::Offset)
+                    .unwrap();
+                let entry = push_constants
+                    .entry(format!("{}.{}", PUSH_CONSTANT_NAME, member))
+                    .or_insert((offset, pso::ShaderStageFlags::empty()));
+                entry.1 |= stage.into();
+            }
+        }
     }
 
     fn remap_binding(
         &self,
         ast: &mut spirv::Ast<glsl::Target>,
         desc_remap_data: &mut n::DescRemapData,
-        nb_map: &mut FastHashMap<String, pso::DescriptorBinding>,
+        nb_map: &mut NameBindingMap,
         all_res: &[spirv::Resource],
         btype: n::BindingTypes,
     ) {
@@ -344,7 +1104,7 @@ impl Device {
                 } else {
                     ast.unset_decoration(res.id, spirv::Decoration::Binding)
                         .unwrap();
-                    assert!(nb_map.insert(res.name.clone(), *nb).is_none());
+                    assert!(nb_map.insert(res.name.clone(), (btype, *nb)).is_none());
                 }
                 ast.unset_decoration(res.id, spirv::Decoration::DescriptorSet)
                     .unwrap();
@@ -356,7 +1116,7 @@ impl Device {
         &self,
         ast: &mut spirv::Ast<glsl::Target>,
         desc_remap_data: &mut n::DescRemapData,
-        nb_map: &mut FastHashMap<String, pso::DescriptorBinding>,
+        nb_map: &mut NameBindingMap,
     ) {
         let mut id_map =
             FastHashMap::<u32, (pso::DescriptorSetIndex, pso::DescriptorBinding)>::default();
@@ -365,11 +1125,9 @@ impl Device {
         self.populate_id_map(ast, &mut id_map, &res.separate_samplers);
 
         for cis in ast.get_combined_image_samplers().unwrap() {
-            let (set, binding) = id_map.get(&cis.image_id).unwrap();
-            let nb = desc_remap_data.reserve_binding(n::BindingTypes::Images);
-            desc_remap_data.insert_missing_binding(nb, n::BindingTypes::Images, *set, *binding);
-            let (set, binding) = id_map.get(&cis.sampler_id).unwrap();
-            desc_remap_data.insert_missing_binding(nb, n::BindingTypes::Images, *set, *binding);
+            let image = *id_map.get(&cis.image_id).unwrap();
+            let sampler = *id_map.get(&cis.sampler_id).unwrap();
+            let nb = desc_remap_data.insert_combined_binding(image, sampler);
 
             let new_name = "GFX_HAL_COMBINED_SAMPLER".to_owned()
                 + "_"
@@ -389,13 +1147,41 @@ impl Device {
             } else {
                 ast.unset_decoration(cis.combined_id, spirv::Decoration::Binding)
                     .unwrap();
-                assert!(nb_map.insert(new_name, nb).is_none())
+                assert!(nb_map
+                    .insert(new_name, (n::BindingTypes::Images, nb))
+                    .is_none())
             }
             ast.unset_decoration(cis.combined_id, spirv::Decoration::DescriptorSet)
                 .unwrap();
         }
     }
 
+    /// Check that the texture and image units used by a pipeline layout
+    /// don't exceed the combined limits of all stages.
+    fn check_texture_units(
+        &self,
+        desc_remap_data: &n::DescRemapData,
+    ) -> Result<(), pso::CreationError> {
+        let limits = &self.share.limits;
+        let units = desc_remap_data.binding_count(n::BindingTypes::Images) as usize;
+        if units > limits.max_descriptor_set_samplers {
+            error!(
+                "{} texture units required, {} supported",
+                units, limits.max_descriptor_set_samplers
+            );
+            return Err(pso::CreationError::Other);
+        }
+        let units = desc_remap_data.binding_count(n::BindingTypes::StorageImages) as usize;
+        if units > limits.max_descriptor_set_storage_images {
+            error!(
+                "{} image units required, {} supported",
+                units, limits.max_descriptor_set_storage_images
+            );
+            return Err(pso::CreationError::Other);
+        }
+        Ok(())
+    }
+
     fn populate_id_map(
         &self,
         ast: &mut spirv::Ast<glsl::Target>,
@@ -413,44 +1199,490 @@ impl Device {
         }
     }
 
-    fn compile_shader(
+    fn translate_shader(
         &self,
         point: &pso::EntryPoint<B>,
         stage: pso::Stage,
         desc_remap_data: &mut n::DescRemapData,
-        name_binding_map: &mut FastHashMap<String, pso::DescriptorBinding>,
-    ) -> n::Shader {
+        name_binding_map: &mut NameBindingMap,
+        push_constants: &mut PushConstantMap,
+    ) -> Result<StageSource, d::ShaderError> {
         assert_eq!(point.entry, "main");
-        match *point.module {
+        let (data, translations) = match *point.module {
             n::ShaderModule::Raw(raw) => {
                 debug!("Can't remap bindings for raw shaders. Assuming they are already rebound.");
-                raw
+                return Ok(StageSource::Compiled(raw));
+            }
+            n::ShaderModule::Spirv {
+                ref data,
+                ref translations,
+            } => (data, translations),
+        };
+
+        // SPIR-V shaders can't be linked together with GLSL ones, so only compute,
+        // as the single stage of its program, goes the direct path.
+        let constants = specialization_constants(&point.specialization)?;
+        if stage == pso::Stage::Compute {
+            if let Some(source) = self.direct_spirv_source(data, &constants, desc_remap_data) {
+                info!("Passing SPIR-V directly for the {:?} stage", stage);
+                return Ok(source);
             }
-            n::ShaderModule::Spirv(ref spirv) => {
-                let mut ast = self.parse_spirv(spirv).unwrap();
+        }
+
+        let specialization = constants
+            .iter()
+            .map(|&(id, bytes)| (id, bytes.to_vec()))
+            .collect::<Vec<_>>();
 
-                self.specialize_ast(&mut ast, point.specialization).unwrap();
-                self.remap_bindings(&mut ast, desc_remap_data, name_binding_map);
+        let mut translations = translations.lock().unwrap();
+        let cached = translations.iter().position(|translation| {
+            translation.stage == stage
+                && translation.specialization == specialization
+                && translation.remap_before == *desc_remap_data
+        });
+        let translation = match cached {
+            Some(index) => {
+                info!("Reusing the GLSL translation for the {:?} stage", stage);
+                &translations[index]
+            }
+            None => {
+                info!("Translating SPIR-V to GLSL for the {:?} stage", stage);
+                let remap_before = desc_remap_data.clone();
+                let mut name_bindings = NameBindingMap::default();
+                let mut stage_push_constants = PushConstantMap::default();
+
+                let mut ast = self.parse_spirv(data)?;
+                self.specialize_ast(&mut ast, &constants)?;
+                self.remap_bindings(&mut ast, desc_remap_data, &mut name_bindings);
                 self.combine_separate_images_and_samplers(
                     &mut ast,
                     desc_remap_data,
-                    name_binding_map,
+                    &mut name_bindings,
                 );
+                self.reflect_push_constants(&mut ast, stage, &mut stage_push_constants);
 
-                let glsl = self.translate_spirv(&mut ast).unwrap();
+                let glsl = self.translate_spirv(&mut ast)?;
                 info!("Generated:\n{:?}", glsl);
-                let shader = match self
+
+                translations.push(n::ShaderTranslation {
+                    stage,
+                    specialization,
+                    remap_before,
+                    remap_after: desc_remap_data.clone(),
+                    name_bindings,
+                    push_constants: stage_push_constants,
+                    glsl,
+                });
+                translations.last().unwrap()
+            }
+        };
+
+        *desc_remap_data = translation.remap_after.clone();
+        for (name, &binding) in &translation.name_bindings {
+            name_binding_map.insert(name.clone(), binding);
+        }
+        for (name, &(offset, stages)) in &translation.push_constants {
+            push_constants
+                .entry(name.clone())
+                .or_insert((offset, pso::ShaderStageFlags::empty()))
+                .1 |= stages;
+        }
+        Ok(StageSource::Glsl(translation.glsl.clone()))
+    }
+
+    /// Prepare a SPIR-V module to be consumed by the driver as is.
+    ///
+    /// Bindings are flattened by patching their decorations, which is only possible
+    /// if the module doesn't rely on any of the emulation done during translation.
+    fn direct_spirv_source(
+        &self,
+        data: &[u8],
+        constants: &[(u32, &[u8])],
+        desc_remap_data: &n::DescRemapData,
+    ) -> Option<StageSource> {
+        if !self.share.private_caps.spirv {
+            return None;
+        }
+
+        let ast = self.parse_spirv(data).ok()?;
+        let res = ast.get_shader_resources().ok()?;
+        if !res.separate_images.is_empty()
+            || !res.separate_samplers.is_empty()
+            || !res.push_constant_buffers.is_empty()
+            || !res.storage_images.is_empty()
+        {
+            return None;
+        }
+
+        let mut bindings = FastHashMap::default();
+        let all_res = [
+            (n::BindingTypes::Images, &res.sampled_images),
+            (n::BindingTypes::UniformBuffers, &res.uniform_buffers),
+            (n::BindingTypes::StorageBuffers, &res.storage_buffers),
+        ];
+        for &(btype, resources) in all_res.iter() {
+            for resource in resources {
+                let set = ast
+                    .get_decoration(resource.id, spirv::Decoration::DescriptorSet)
+                    .ok()?;
+                let binding = ast
+                    .get_decoration(resource.id, spirv::Decoration::Binding)
+                    .ok()?;
+                match desc_remap_data.get_binding(btype, set as _, binding) {
+                    Some(&[nb]) => {
+                        bindings.insert(resource.id, nb);
+                    }
+                    _ => return None,
+                }
+            }
+        }
+
+        // `glSpecializeShader` only takes 32-bit values.
+        if constants
+            .iter()
+            .any(|&(_, bytes)| bytes.len() > mem::size_of::<u32>())
+        {
+            return None;
+        }
+        let constants = constants
+            .iter()
+            .map(|&(id, bytes)| (id, specialization_value(bytes) as u32))
+            .collect();
+
+        let mut words = unsafe {
+            slice::from_raw_parts(
+                data.as_ptr() as *const u32,
+                data.len() / mem::size_of::<u32>(),
+            )
+        }
+        .to_vec();
+        patch_spirv_bindings(&mut words, &bindings);
+
+        Some(StageSource::Spirv { words, constants })
+    }
+
+    fn compile_stage(&self, stage: pso::Stage, source: &StageSource) -> n::Shader {
+        match *source {
+            StageSource::Compiled(raw) => raw,
+            StageSource::Spirv {
+                ref words,
+                ref constants,
+            } => self
+                .create_shader_from_spirv(words, constants, stage)
+                .unwrap(),
+            StageSource::Glsl(ref glsl) => {
+                match self
                     .create_shader_module_from_source(glsl.as_bytes(), stage)
                     .unwrap()
                 {
                     n::ShaderModule::Raw(raw) => raw,
                     _ => panic!("Unhandled"),
+                }
+            }
+        }
+    }
+
+    /// Identifier of the driver for which cached program binaries are valid.
+    fn driver_id(&self) -> String {
+        let info = &self.share.info;
+        format!(
+            "{}|{}|{:?}|{:?}",
+            info.platform_name.vendor,
+            info.platform_name.renderer,
+            info.version,
+            info.shading_language,
+        )
+    }
+
+    /// Link a program from the given stages, going through the pipeline cache
+    /// when all of them have been translated from SPIR-V.
+    ///
+    /// Returns the link log on failure.
+    unsafe fn link_program(
+        &self,
+        stages: &[(pso::Stage, StageSource)],
+        color_targets: usize,
+        cache: Option<&n::PipelineCache>,
+        name_binding_map: &NameBindingMap,
+        push_constants: &PushConstantMap,
+        feedback: Option<&TransformFeedbackDesc>,
+    ) -> Result<(n::Program, Vec<n::UniformDesc>), String> {
+        let gl = &self.share.context;
+        let share = &self.share;
+        let name = gl.CreateProgram();
+
+        let cache_key = match cache {
+            Some(_) if share.private_caps.program_binary => {
+                program_cache_key(stages, color_targets, feedback)
+            }
+            _ => None,
+        };
+        let loaded = match (cache, &cache_key) {
+            (Some(cache), Some(key)) => self.load_program_binary(name, cache, key),
+            _ => false,
+        };
+
+        if !loaded {
+            let shader_names = stages
+                .iter()
+                .map(|&(stage, ref source)| {
+                    let shader_name = self.compile_stage(stage, source);
+                    gl.AttachShader(name, shader_name);
+                    shader_name
+                })
+                .collect::<Vec<_>>();
+
+            if !share.private_caps.program_interface && share.private_caps.frag_data_location {
+                for i in 0..color_targets {
+                    let color_name = format!("Target{}\0", i);
+                    gl.BindFragDataLocation(
+                        name,
+                        i as u32,
+                        (&color_name[..]).as_ptr() as *mut gl::types::GLchar,
+                    );
+                }
+            }
+
+            if let Some(feedback) = feedback {
+                let varyings = feedback
+                    .varyings
+                    .iter()
+                    .map(|varying| format!("{}\0", varying))
+                    .collect::<Vec<_>>();
+                let varying_ptrs = varyings
+                    .iter()
+                    .map(|varying| varying.as_ptr() as *const gl::types::GLchar)
+                    .collect::<Vec<_>>();
+                let mode = if feedback.interleaved {
+                    gl::INTERLEAVED_ATTRIBS
+                } else {
+                    gl::SEPARATE_ATTRIBS
                 };
+                gl.TransformFeedbackVaryings(
+                    name,
+                    varying_ptrs.len() as _,
+                    varying_ptrs.as_ptr(),
+                    mode,
+                );
+            }
+
+            if cache_key.is_some() {
+                gl.ProgramParameteri(name, gl::PROGRAM_BINARY_RETRIEVABLE_HINT, gl::TRUE as _);
+            }
+
+            gl.LinkProgram(name);
+            info!("\tLinked program {}", name);
+            if let Err(err) = share.check() {
+                panic!("Error linking program: {:?}", err);
+            }
+
+            for shader_name in shader_names {
+                gl.DetachShader(name, shader_name);
+                gl.DeleteShader(shader_name);
+            }
+
+            let status = get_program_iv(gl, name, gl::LINK_STATUS);
+            let log = get_program_log(gl, name);
+            if status != 0 {
+                if !log.is_empty() {
+                    warn!("\tLog: {}", log);
+                }
+            } else {
+                gl.DeleteProgram(name);
+                return Err(log);
+            }
+
+            if let (Some(cache), Some(key)) = (cache, cache_key) {
+                self.store_program_binary(name, cache, key);
+            }
+        }
+
+        if !share
+            .legacy_features
+            .contains(LegacyFeatures::EXPLICIT_LAYOUTS_IN_SHADER)
+        {
+            bind_program_resources(gl, name, name_binding_map);
+        }
+
+        Ok((name, get_push_constant_uniforms(gl, name, push_constants)))
+    }
+
+    /// Try to restore a linked program from the cache.
+    ///
+    /// Binaries rejected by the driver, e.g. after an update which kept the
+    /// version strings, are evicted so the program gets linked and stored again.
+    unsafe fn load_program_binary(
+        &self,
+        program: n::Program,
+        cache: &n::PipelineCache,
+        key: &[u8],
+    ) -> bool {
+        let gl = &self.share.context;
+        let mut binaries = cache.binaries.lock().unwrap();
+        let loaded = match binaries.get(key) {
+            Some(binary) => {
+                gl.ProgramBinary(
+                    program,
+                    binary.format,
+                    binary.data.as_ptr() as *const _,
+                    binary.data.len() as _,
+                );
+                self.share.check().is_ok() && get_program_iv(gl, program, gl::LINK_STATUS) != 0
+            }
+            None => return false,
+        };
+
+        if loaded {
+            info!("\tLoaded program {} from the pipeline cache", program);
+        } else {
+            warn!("Program binary was rejected by the driver, relinking");
+            binaries.remove(key);
+        }
+        loaded
+    }
+
+    unsafe fn store_program_binary(
+        &self,
+        program: n::Program,
+        cache: &n::PipelineCache,
+        key: Vec<u8>,
+    ) {
+        let gl = &self.share.context;
+        let length = get_program_iv(gl, program, gl::PROGRAM_BINARY_LENGTH);
+        if length <= 0 {
+            return;
+        }
+
+        let mut data = vec![0u8; length as usize];
+        let mut written = 0;
+        let mut format = 0;
+        gl.GetProgramBinary(
+            program,
+            length,
+            &mut written,
+            &mut format,
+            data.as_mut_ptr() as *mut _,
+        );
+        data.truncate(written as usize);
 
-                shader
+        cache
+            .binaries
+            .lock()
+            .unwrap()
+            .insert(key, n::ProgramBinary { format, data });
+    }
+}
+
+/// Raw data of each specialization constant, validated against the data blob.
+fn specialization_constants<'a>(
+    specialization: &pso::Specialization<'a>,
+) -> Result<Vec<(u32, &'a [u8])>, d::ShaderError> {
+    specialization
+        .constants
+        .iter()
+        .map(|constant| {
+            let range = constant.range.start as usize..constant.range.end as usize;
+            match specialization.data.get(range) {
+                Some(bytes) if bytes.len() <= mem::size_of::<u64>() => Ok((constant.id, bytes)),
+                _ => Err(d::ShaderError::CompilationFailed(format!(
+                    "Specialization constant {} has an invalid data range {:?}",
+                    constant.id, constant.range
+                ))),
+            }
+        })
+        .collect()
+}
+
+/// Value of a specialization constant from its little endian data.
+fn specialization_value(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0u64, |u, &b| (u << 8) | b as u64)
+}
+
+/// Shader stage ready to be attached to a program.
+enum StageSource {
+    /// Shader object created from a raw GLSL module.
+    Compiled(n::Shader),
+    /// GLSL translated from SPIR-V, which still needs to be compiled.
+    Glsl(String),
+    /// SPIR-V with flattened bindings and the specialization constant values.
+    Spirv {
+        words: Vec<u32>,
+        constants: Vec<(u32, u32)>,
+    },
+}
+
+// `GL_SHADER_BINARY_FORMAT_SPIR_V`
+const SHADER_BINARY_FORMAT_SPIR_V: GLenum = 0x9551;
+
+const SPIRV_OP_DECORATE: u32 = 71;
+const SPIRV_DECORATION_BINDING: u32 = 33;
+const SPIRV_DECORATION_DESCRIPTOR_SET: u32 = 34;
+
+/// Rewrite the binding decorations of the given ids and move all resources
+/// into descriptor set 0, the only one allowed by `GL_ARB_gl_spirv`.
+fn patch_spirv_bindings(words: &mut [u32], bindings: &FastHashMap<u32, pso::DescriptorBinding>) {
+    // Skip the header.
+    let mut offset = 5;
+    while offset < words.len() {
+        let op = words[offset] & 0xFFFF;
+        let count = (words[offset] >> 16) as usize;
+        if count == 0 {
+            break;
+        }
+        if op == SPIRV_OP_DECORATE && count == 4 {
+            let id = words[offset + 1];
+            let decoration = words[offset + 2];
+            if decoration == SPIRV_DECORATION_DESCRIPTOR_SET {
+                words[offset + 3] = 0;
+            } else if decoration == SPIRV_DECORATION_BINDING {
+                if let Some(&nb) = bindings.get(&id) {
+                    words[offset + 3] = nb;
+                }
+            }
+        }
+        offset += count;
+    }
+}
+
+/// Key identifying a program in the pipeline cache by its sources.
+///
+/// Programs built from raw shader objects have no sources to key by and aren't cached.
+fn program_cache_key(
+    stages: &[(pso::Stage, StageSource)],
+    color_targets: usize,
+    feedback: Option<&TransformFeedbackDesc>,
+) -> Option<Vec<u8>> {
+    let mut key = vec![color_targets as u8];
+    if let Some(feedback) = feedback {
+        key.push(feedback.interleaved as u8);
+        for varying in feedback.varyings {
+            key.extend_from_slice(varying.as_bytes());
+            key.push(0);
+        }
+    }
+    for &(stage, ref source) in stages {
+        match *source {
+            StageSource::Glsl(ref glsl) => {
+                key.push(stage as u8);
+                key.extend_from_slice(glsl.as_bytes());
+                key.push(0);
             }
+            StageSource::Spirv {
+                ref words,
+                ref constants,
+            } => {
+                key.push(stage as u8);
+                for &word in words {
+                    key.extend_from_slice(&unsafe { mem::transmute::<_, [u8; 4]>(word) });
+                }
+                for &(id, value) in constants {
+                    key.extend_from_slice(&unsafe { mem::transmute::<_, [u8; 8]>([id, value]) });
+                }
+            }
+            StageSource::Compiled(_) => return None,
         }
     }
+    Some(key)
 }
 
 pub(crate) unsafe fn set_sampler_info<SetParamFloat, SetParamFloatVec, SetParamInt>(
@@ -467,10 +1699,20 @@ pub(crate) unsafe fn set_sampler_info<SetParamFloat, SetParamFloatVec, SetParamI
     let (min, mag) = conv::filter_to_gl(info.mag_filter, info.min_filter, info.mip_filter);
     match info.anisotropic {
         i::Anisotropic::On(fac) if fac > 1 => {
-            if share.private_caps.sampler_anisotropy_ext {
-                set_param_float(gl::TEXTURE_MAX_ANISOTROPY_EXT, fac as GLfloat);
-            } else if share.features.contains(c::Features::SAMPLER_ANISOTROPY) {
-                set_param_float(gl::TEXTURE_MAX_ANISOTROPY_EXT, fac as GLfloat);
+            if share.features.contains(c::Features::SAMPLER_ANISOTROPY) {
+                let max = share.limits.max_sampler_anisotropy;
+                let fac = if fac as GLfloat > max {
+                    warn!(
+                        "Anisotropy level {} exceeds the maximum supported level {}",
+                        fac, max
+                    );
+                    max
+                } else {
+                    fac as GLfloat
+                };
+                set_param_float(gl::TEXTURE_MAX_ANISOTROPY_EXT, fac);
+            } else {
+                warn!("Anisotropic filtering is not supported");
             }
         }
         _ => (),
@@ -519,14 +1761,22 @@ pub(crate) unsafe fn set_sampler_info<SetParamFloat, SetParamFloatVec, SetParamI
 impl d::Device<B> for Device {
     unsafe fn allocate_memory(
         &self,
-        _mem_type: c::MemoryTypeId,
+        mem_type: c::MemoryTypeId,
         size: u64,
     ) -> Result<n::Memory, d::AllocationError> {
-        // TODO
+        let properties = match self.share.memory_types().get(mem_type.0) {
+            Some(memory_type) => memory_type.properties,
+            None => {
+                error!("Invalid memory type {:?}", mem_type);
+                return Err(d::OutOfMemory::OutOfDeviceMemory.into());
+            }
+        };
         Ok(n::Memory {
-            properties: memory::Properties::CPU_VISIBLE | memory::Properties::CPU_CACHED,
+            properties,
             first_bound_buffer: Cell::new(0),
             size,
+            persistent_size: Cell::new(None),
+            persistent_ptr: Cell::new(ptr::null_mut()),
         })
     }
 
@@ -536,7 +1786,8 @@ impl d::Device<B> for Device {
         flags: CommandPoolCreateFlags,
     ) -> Result<RawCommandPool, d::OutOfMemory> {
         let fbo = create_fbo_internal(&self.share);
-        let limits = self.share.limits.into();
+        let blit_fbo = create_fbo_internal(&self.share);
+        let limits = command::Limits::new(&self.share);
         let memory = if flags.contains(CommandPoolCreateFlags::RESET_INDIVIDUAL) {
             BufferMemory::Individual {
                 storage: FastHashMap::default(),
@@ -550,15 +1801,16 @@ impl d::Device<B> for Device {
 
         Ok(RawCommandPool {
             fbo,
+            blit_fbo,
             limits,
             memory: Arc::new(Mutex::new(memory)),
         })
     }
 
     unsafe fn destroy_command_pool(&self, pool: RawCommandPool) {
-        if let Some(fbo) = pool.fbo {
-            let gl = &self.share.context;
-            gl.DeleteFramebuffers(1, &fbo);
+        let gl = &self.share.context;
+        for fbo in pool.fbo.iter().chain(pool.blit_fbo.iter()) {
+            gl.DeleteFramebuffers(1, fbo);
         }
     }
 
@@ -579,14 +1831,13 @@ impl d::Device<B> for Device {
         let subpasses = subpasses
             .into_iter()
             .map(|subpass| {
-                let color_attachments = subpass
-                    .borrow()
-                    .colors
-                    .iter()
-                    .map(|&(index, _)| index)
-                    .collect();
+                let subpass = subpass.borrow();
+                let color_attachments = subpass.colors.iter().map(|&(index, _)| index).collect();
 
-                n::SubpassDesc { color_attachments }
+                n::SubpassDesc {
+                    color_attachments,
+                    depth_stencil: subpass.depth_stencil.map(|&(index, _)| index),
+                }
             })
             .collect();
 
@@ -637,238 +1888,120 @@ impl d::Device<B> for Device {
                         // We need to figure out combos once we get the shaders, until then we
                         // do nothing
                     }
-                    UniformBuffer => {
+                    UniformBuffer | UniformBufferDynamic => {
                         drd.insert_missing_binding_into_spare(
                             n::BindingTypes::UniformBuffers,
                             set as _,
                             binding.binding,
                         );
                     }
-                    StorageImage | UniformTexelBuffer | UniformBufferDynamic
-                    | StorageTexelBuffer | StorageBufferDynamic | StorageBuffer
-                    | InputAttachment => unimplemented!(), // 6
-                }
-            })
-        });
-
-        Ok(n::PipelineLayout {
-            desc_remap_data: Arc::new(RwLock::new(drd)),
-        })
-    }
-
-    unsafe fn create_pipeline_cache(&self, _data: Option<&[u8]>) -> Result<(), d::OutOfMemory> {
-        Ok(())
-    }
-
-    unsafe fn get_pipeline_cache_data(&self, _cache: &()) -> Result<Vec<u8>, d::OutOfMemory> {
-        //empty
-        Ok(Vec::new())
-    }
-
-    unsafe fn destroy_pipeline_cache(&self, _: ()) {
-        //empty
-    }
-
-    unsafe fn merge_pipeline_caches<I>(&self, _: &(), _: I) -> Result<(), d::OutOfMemory>
-    where
-        I: IntoIterator,
-        I::Item: Borrow<()>,
-    {
-        //empty
-        Ok(())
-    }
-
-    unsafe fn create_graphics_pipeline<'a>(
-        &self,
-        desc: &pso::GraphicsPipelineDesc<'a, B>,
-        _cache: Option<&()>,
-    ) -> Result<n::GraphicsPipeline, pso::CreationError> {
-        let gl = &self.share.context;
-        let share = &self.share;
-        let desc = desc.borrow();
-        let subpass = {
-            let subpass = desc.subpass;
-            match subpass.main_pass.subpasses.get(subpass.index) {
-                Some(sp) => sp,
-                None => return Err(pso::CreationError::InvalidSubpass(subpass.index)),
-            }
-        };
-
-        let program = {
-            let name = gl.CreateProgram();
-
-            // Attach shaders to program
-            let shaders = [
-                (pso::Stage::Vertex, Some(&desc.shaders.vertex)),
-                (pso::Stage::Hull, desc.shaders.hull.as_ref()),
-                (pso::Stage::Domain, desc.shaders.domain.as_ref()),
-                (pso::Stage::Geometry, desc.shaders.geometry.as_ref()),
-                (pso::Stage::Fragment, desc.shaders.fragment.as_ref()),
-            ];
-
-            let mut name_binding_map = FastHashMap::<String, pso::DescriptorBinding>::default();
-            let shader_names = &shaders
-                .iter()
-                .filter_map(|&(stage, point_maybe)| {
-                    point_maybe.map(|point| {
-                        let shader_name = self.compile_shader(
-                            point,
-                            stage,
-                            &mut desc.layout.desc_remap_data.write().unwrap(),
-                            &mut name_binding_map,
+                    StorageBuffer | StorageBufferDynamic => {
+                        drd.insert_missing_binding_into_spare(
+                            n::BindingTypes::StorageBuffers,
+                            set as _,
+                            binding.binding,
                         );
-
-                        gl.AttachShader(name, shader_name);
-
-                        shader_name
-                    })
-                })
-                .collect::<Vec<_>>();
-
-            if !share.private_caps.program_interface && share.private_caps.frag_data_location {
-                for i in 0..subpass.color_attachments.len() {
-                    let color_name = format!("Target{}\0", i);
-                    gl.BindFragDataLocation(
-                        name,
-                        i as u32,
-                        (&color_name[..]).as_ptr() as *mut gl::types::GLchar,
-                    );
+                    }
+                    StorageImage => {
+                        drd.insert_missing_binding_into_spare(
+                            n::BindingTypes::StorageImages,
+                            set as _,
+                            binding.binding,
+                        );
+                    }
+                    UniformTexelBuffer | StorageTexelBuffer | InputAttachment => {
+                        unimplemented!()
+                    }
                 }
-            }
-
-            gl.LinkProgram(name);
-            info!("\tLinked program {}", name);
-            if let Err(err) = share.check() {
-                panic!("Error linking program: {:?}", err);
-            }
-
-            for shader_name in shader_names {
-                gl.DetachShader(name, *shader_name);
-                gl.DeleteShader(*shader_name);
-            }
+            })
+        });
 
-            if !self
-                .share
-                .legacy_features
-                .contains(LegacyFeatures::EXPLICIT_LAYOUTS_IN_SHADER)
-            {
-                let gl = &self.share.context;
-                gl.UseProgram(name);
-                for (bname, binding) in name_binding_map.iter() {
-                    let loc = gl.GetUniformLocation(name, bname.as_ptr() as _);
-                    gl.Uniform1i(loc, *binding as _);
-                }
-            }
+        Ok(n::PipelineLayout {
+            desc_remap_data: Arc::new(RwLock::new(drd)),
+        })
+    }
 
-            let status = get_program_iv(gl, name, gl::LINK_STATUS);
-            let log = get_program_log(gl, name);
-            if status != 0 {
-                if !log.is_empty() {
-                    warn!("\tLog: {}", log);
-                }
-            } else {
-                return Err(pso::CreationError::Shader(
-                    d::ShaderError::CompilationFailed(log),
-                ));
-            }
+    unsafe fn create_pipeline_cache(
+        &self,
+        data: Option<&[u8]>,
+    ) -> Result<n::PipelineCache, d::OutOfMemory> {
+        Ok(match data {
+            Some(data) => n::PipelineCache::deserialize(data, &self.driver_id()),
+            None => n::PipelineCache::default(),
+        })
+    }
 
-            name
-        };
+    unsafe fn get_pipeline_cache_data(
+        &self,
+        cache: &n::PipelineCache,
+    ) -> Result<Vec<u8>, d::OutOfMemory> {
+        Ok(cache.serialize(&self.driver_id()))
+    }
 
-        let patch_size = match desc.input_assembler.primitive {
-            c::Primitive::PatchList(size) => Some(size as _),
-            _ => None,
-        };
+    unsafe fn destroy_pipeline_cache(&self, _: n::PipelineCache) {
+        //drop
+    }
 
-        let mut vertex_buffers = Vec::new();
-        for vb in &desc.vertex_buffers {
-            while vertex_buffers.len() <= vb.binding as usize {
-                vertex_buffers.push(None);
+    unsafe fn merge_pipeline_caches<I>(
+        &self,
+        target: &n::PipelineCache,
+        sources: I,
+    ) -> Result<(), d::OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<n::PipelineCache>,
+    {
+        let mut binaries = target.binaries.lock().unwrap();
+        for source in sources {
+            let source = source.borrow().binaries.lock().unwrap();
+            for (key, binary) in source.iter() {
+                binaries
+                    .entry(key.clone())
+                    .or_insert_with(|| binary.clone());
             }
-            vertex_buffers[vb.binding as usize] = Some(*vb);
         }
+        Ok(())
+    }
 
-        Ok(n::GraphicsPipeline {
-            program,
-            primitive: conv::primitive_to_gl_primitive(desc.input_assembler.primitive),
-            patch_size,
-            blend_targets: desc.blender.targets.clone(),
-            vertex_buffers,
-            attributes: desc
-                .attributes
-                .iter()
-                .map(|&a| {
-                    let (size, format, vertex_attrib_fn) =
-                        conv::format_to_gl_format(a.element.format).unwrap();
-                    n::AttributeDesc {
-                        location: a.location,
-                        offset: a.element.offset,
-                        binding: a.binding,
-                        size,
-                        format,
-                        vertex_attrib_fn,
-                    }
-                })
-                .collect(),
-        })
+    unsafe fn create_graphics_pipeline<'a>(
+        &self,
+        desc: &pso::GraphicsPipelineDesc<'a, B>,
+        cache: Option<&n::PipelineCache>,
+    ) -> Result<n::GraphicsPipeline, pso::CreationError> {
+        self.build_graphics_pipeline(desc, cache, None)
     }
 
     unsafe fn create_compute_pipeline<'a>(
         &self,
         desc: &pso::ComputePipelineDesc<'a, B>,
-        _cache: Option<&()>,
+        cache: Option<&n::PipelineCache>,
     ) -> Result<n::ComputePipeline, pso::CreationError> {
-        let gl = &self.share.context;
-        let share = &self.share;
-
-        let program = {
-            let name = gl.CreateProgram();
-
-            let mut name_binding_map = FastHashMap::<String, pso::DescriptorBinding>::default();
-            let shader = self.compile_shader(
-                &desc.shader,
-                pso::Stage::Compute,
-                &mut desc.layout.desc_remap_data.write().unwrap(),
-                &mut name_binding_map,
-            );
-            gl.AttachShader(name, shader);
-
-            gl.LinkProgram(name);
-            info!("\tLinked program {}", name);
-            if let Err(err) = share.check() {
-                panic!("Error linking program: {:?}", err);
-            }
-
-            gl.DetachShader(name, shader);
-            gl.DeleteShader(shader);
-
-            if !self
-                .share
-                .legacy_features
-                .contains(LegacyFeatures::EXPLICIT_LAYOUTS_IN_SHADER)
-            {
-                let gl = &self.share.context;
-                gl.UseProgram(name);
-                for (bname, binding) in name_binding_map.iter() {
-                    let loc = gl.GetUniformLocation(name, bname.as_ptr() as _);
-                    gl.Uniform1i(loc, *binding as _);
-                }
-            }
-
-            let status = get_program_iv(gl, name, gl::LINK_STATUS);
-            let log = get_program_log(gl, name);
-            if status != 0 {
-                if !log.is_empty() {
-                    warn!("\tLog: {}", log);
-                }
-            } else {
-                return Err(pso::CreationError::Other);
-            }
-
-            name
+        let (program, uniforms) = {
+            let mut name_binding_map = NameBindingMap::default();
+            let mut push_constants = PushConstantMap::default();
+            let source = self
+                .translate_shader(
+                    &desc.shader,
+                    pso::Stage::Compute,
+                    &mut desc.layout.desc_remap_data.write().unwrap(),
+                    &mut name_binding_map,
+                    &mut push_constants,
+                )
+                .map_err(pso::CreationError::Shader)?;
+            self.check_texture_units(&desc.layout.desc_remap_data.read().unwrap())?;
+
+            self.link_program(
+                &[(pso::Stage::Compute, source)],
+                0,
+                cache,
+                &name_binding_map,
+                &push_constants,
+                None,
+            )
+            .map_err(|_| pso::CreationError::Other)?
         };
 
-        Ok(n::ComputePipeline { program })
+        Ok(n::ComputePipeline { program, uniforms })
     }
 
     unsafe fn create_framebuffer<I>(
@@ -891,17 +2024,38 @@ impl d::Device<B> for Device {
         gl.GenFramebuffers(1, &mut name);
         gl.BindFramebuffer(target, name);
 
-        let att_points = [
-            gl::COLOR_ATTACHMENT0,
-            gl::COLOR_ATTACHMENT1,
-            gl::COLOR_ATTACHMENT2,
-            gl::COLOR_ATTACHMENT3,
-        ];
-
+        // Color attachments are attached at the point matching their index in
+        // the render pass, which subpasses refer to when selecting draw buffers.
+        let limits = &self.share.limits;
+        let max_color_attachments = limits
+            .max_color_attachments
+            .min(limits.max_fragment_output_attachments);
+        let mut color_points = Vec::new();
         let mut attachments_len = 0;
-        //TODO: exclude depth/stencil attachments from here
-        for (&att_point, view) in att_points.iter().zip(attachments.into_iter()) {
+        for (index, view) in attachments.into_iter().enumerate() {
             attachments_len += 1;
+            let format = pass.attachments.get(index).and_then(|att| att.format);
+            let att_point = match format {
+                Some(format) if format.is_depth() && format.is_stencil() => {
+                    gl::DEPTH_STENCIL_ATTACHMENT
+                }
+                Some(format) if format.is_depth() => gl::DEPTH_ATTACHMENT,
+                Some(format) if format.is_stencil() => gl::STENCIL_ATTACHMENT,
+                _ if index < max_color_attachments => {
+                    let att_point = gl::COLOR_ATTACHMENT0 + index as GLenum;
+                    color_points.push(att_point);
+                    att_point
+                }
+                _ => {
+                    // Invalid usage rather than an allocation failure, which
+                    // is all the error can report: leave the attachment out.
+                    error!(
+                        "Color attachment {} exceeds the maximum of {} color attachments, ignoring it",
+                        index, max_color_attachments
+                    );
+                    continue;
+                }
+            };
             if self.share.private_caps.framebuffer_texture {
                 Self::bind_target(gl, target, att_point, view.borrow());
             } else {
@@ -909,10 +2063,8 @@ impl d::Device<B> for Device {
             }
         }
         assert_eq!(attachments_len, pass.attachments.len());
-        // attachments_len actually equals min(attachments.len(), att_points.len()) until the next assert
 
-        assert!(pass.attachments.len() <= att_points.len());
-        gl.DrawBuffers(attachments_len as _, att_points.as_ptr());
+        gl.DrawBuffers(color_points.len() as _, color_points.as_ptr());
         let _status = gl.CheckFramebufferStatus(target); //TODO: check status
         gl.BindFramebuffer(target, 0);
 
@@ -931,7 +2083,10 @@ impl d::Device<B> for Device {
         &self,
         raw_data: &[u8],
     ) -> Result<n::ShaderModule, d::ShaderError> {
-        Ok(n::ShaderModule::Spirv(raw_data.into()))
+        Ok(n::ShaderModule::Spirv {
+            data: raw_data.into(),
+            translations: Mutex::new(Vec::new()),
+        })
     }
 
     unsafe fn create_sampler(
@@ -946,6 +2101,12 @@ impl d::Device<B> for Device {
             return Ok(n::FatSampler::Info(info));
         }
 
+        let mut samplers = self.samplers.lock().unwrap();
+        if let Some(entry) = samplers.get_mut(&info) {
+            entry.1 += 1;
+            return Ok(n::FatSampler::Sampler(entry.0));
+        }
+
         let gl = &self.share.context;
         let mut name = 0 as n::Sampler;
 
@@ -963,6 +2124,7 @@ impl d::Device<B> for Device {
                 d::OutOfMemory::OutOfHostMemory,
             ))
         } else {
+            samplers.insert(info, (name, 1));
             Ok(n::FatSampler::Sampler(name))
         }
     }
@@ -994,13 +2156,15 @@ impl d::Device<B> for Device {
         let mut name = 0;
         gl.GenBuffers(1, &mut name);
 
+        // Buffers can be bound to any memory type.
+        let type_count = self.share.memory_types().len();
         Ok(n::Buffer {
             raw: name,
             target,
             requirements: memory::Requirements {
                 size,
                 alignment: 1, // TODO: do we need specific alignment for any use-case?
-                type_mask: 0x7,
+                type_mask: (1 << type_count) - 1,
             },
         })
     }
@@ -1028,19 +2192,22 @@ impl d::Device<B> for Device {
         let cpu_can_write = memory.can_upload();
 
         if self.share.private_caps.buffer_storage {
-            //TODO: gl::DYNAMIC_STORAGE_BIT | gl::MAP_PERSISTENT_BIT
-            let flags = memory.map_flags();
+            //TODO: gl::DYNAMIC_STORAGE_BIT
+            let mut flags = memory.map_flags();
+            // Keep the buffer mapped, as it is the one used by `map_memory`.
+            if offset == 0 && flags != 0 {
+                flags |= gl::MAP_PERSISTENT_BIT | gl::MAP_COHERENT_BIT;
+                memory.persistent_size.set(Some(buffer.requirements.size));
+            }
             //TODO: use *Named calls to avoid binding
             gl.BindBuffer(target, buffer.raw);
             gl.BufferStorage(target, buffer.requirements.size as _, ptr::null(), flags);
             gl.BindBuffer(target, 0);
         } else {
-            let flags = if cpu_can_read && cpu_can_write {
-                gl::DYNAMIC_DRAW
-            } else if cpu_can_write {
-                gl::STREAM_DRAW
-            } else if cpu_can_read {
+            let flags = if cpu_can_read {
                 gl::STREAM_READ
+            } else if cpu_can_write {
+                gl::DYNAMIC_DRAW
             } else {
                 gl::STATIC_DRAW
             };
@@ -1077,6 +2244,21 @@ impl d::Device<B> for Device {
         let offset = *range.start().unwrap_or(&0);
         let size = *range.end().unwrap_or(&memory.size) - offset;
 
+        if let Some(persistent_size) = memory.persistent_size.get() {
+            if memory.persistent_ptr.get().is_null() {
+                let access = access | gl::MAP_PERSISTENT_BIT | gl::MAP_COHERENT_BIT;
+                gl.BindBuffer(target, buffer);
+                let ptr = gl.MapBufferRange(target, 0, persistent_size as _, access);
+                gl.BindBuffer(target, 0);
+                memory.persistent_ptr.set(ptr as *mut _);
+
+                if let Err(err) = self.share.check() {
+                    panic!("Error mapping memory: {:?} for memory {:?}", err, memory);
+                }
+            }
+            return Ok(memory.persistent_ptr.get().offset(offset as isize));
+        }
+
         let ptr = {
             gl.BindBuffer(target, buffer);
             let ptr = gl.MapBufferRange(target, offset as _, size as _, access);
@@ -1099,6 +2281,11 @@ impl d::Device<B> for Device {
         };
         let target = gl::PIXEL_PACK_BUFFER;
 
+        // Persistent mappings are kept until the buffer is destroyed.
+        if memory.persistent_size.get().is_some() {
+            return;
+        }
+
         gl.BindBuffer(target, buffer);
         gl.UnmapBuffer(target);
         gl.BindBuffer(target, 0);
@@ -1120,14 +2307,28 @@ impl d::Device<B> for Device {
 
     unsafe fn invalidate_mapped_memory_ranges<'a, I, R>(
         &self,
-        _ranges: I,
+        ranges: I,
     ) -> Result<(), d::OutOfMemory>
     where
         I: IntoIterator,
         I::Item: Borrow<(&'a n::Memory, R)>,
         R: RangeArg<u64>,
     {
-        unimplemented!()
+        // Mappings which aren't persistent are created on `map_memory`, with
+        // up-to-date contents. Persistent mappings only see shader storage
+        // writes issued before a barrier, and once these completed.
+        let persistent = ranges.into_iter().any(|range| {
+            let memory = range.borrow().0;
+            memory.can_download() && !memory.persistent_ptr.get().is_null()
+        });
+        if persistent {
+            let gl = &self.share.context;
+            if gl.MemoryBarrier.is_loaded() {
+                gl.MemoryBarrier(gl::CLIENT_MAPPED_BUFFER_BARRIER_BIT);
+            }
+            gl.Finish();
+        }
+        Ok(())
     }
 
     unsafe fn create_buffer_view<R: RangeArg<u64>>(
@@ -1150,15 +2351,24 @@ impl d::Device<B> for Device {
     ) -> Result<n::Image, i::CreationError> {
         let gl = &self.share.context;
 
-        let (int_format, iformat, itype) = match format {
-            Format::Rgba8Unorm => (gl::RGBA8, gl::RGBA, gl::UNSIGNED_BYTE),
-            Format::Rgba8Srgb => (gl::SRGB8_ALPHA8, gl::RGBA, gl::UNSIGNED_BYTE),
-            _ => unimplemented!(),
+        let compressed = conv::compressed_format_to_gl(format);
+        let (int_format, iformat, itype) = match (conv::image_format_to_gl(format), compressed) {
+            (Some(desc), _) => desc,
+            (None, Some((internal, family))) => {
+                if !self.share.private_caps.texture_compression.contains(family) {
+                    error!("Compressed format {:?} is not supported", format);
+                    return Err(i::CreationError::Format(format));
+                }
+                (internal, 0, 0)
+            }
+            (None, None) => return Err(i::CreationError::Format(format)),
         };
 
         let channel = format.base_format().1;
 
+        // Compressed formats can't back renderbuffers.
         let image = if num_levels > 1
+            || compressed.is_some()
             || usage.contains(i::Usage::STORAGE)
             || usage.contains(i::Usage::SAMPLED)
         {
@@ -1184,17 +2394,30 @@ impl d::Device<B> for Device {
                         let mut w = w;
                         let mut h = h;
                         for i in 0..num_levels {
-                            gl.TexImage2D(
-                                gl::TEXTURE_2D,
-                                i as _,
-                                int_format as _,
-                                w as _,
-                                h as _,
-                                0,
-                                iformat,
-                                itype,
-                                std::ptr::null(),
-                            );
+                            if compressed.is_some() {
+                                gl.CompressedTexImage2D(
+                                    gl::TEXTURE_2D,
+                                    i as _,
+                                    int_format,
+                                    w as _,
+                                    h as _,
+                                    0,
+                                    conv::image_data_size(format, w, h, 1) as _,
+                                    std::ptr::null(),
+                                );
+                            } else {
+                                gl.TexImage2D(
+                                    gl::TEXTURE_2D,
+                                    i as _,
+                                    int_format as _,
+                                    w as _,
+                                    h as _,
+                                    0,
+                                    iformat,
+                                    itype,
+                                    std::ptr::null(),
+                                );
+                            }
                             w = std::cmp::max(w / 2, 1);
                             h = std::cmp::max(h / 2, 1);
                         }
@@ -1216,10 +2439,8 @@ impl d::Device<B> for Device {
             n::ImageKind::Surface(name)
         };
 
-        let surface_desc = format.base_format().0.desc();
-        let bytes_per_texel = surface_desc.bits / 8;
         let ext = kind.extent();
-        let size = (ext.width * ext.height * ext.depth) as u64 * bytes_per_texel as u64;
+        let size = conv::image_data_size(format, ext.width, ext.height, ext.depth);
 
         if let Err(err) = self.share.check() {
             panic!(
@@ -1230,6 +2451,7 @@ impl d::Device<B> for Device {
 
         Ok(n::Image {
             kind: image,
+            format,
             channel,
             requirements: memory::Requirements {
                 size,
@@ -1263,17 +2485,21 @@ impl d::Device<B> for Device {
     unsafe fn create_image_view(
         &self,
         image: &n::Image,
-        _kind: i::ViewKind,
-        _format: Format,
+        kind: i::ViewKind,
+        format: Format,
         swizzle: Swizzle,
         range: i::SubresourceRange,
     ) -> Result<n::ImageView, i::ViewError> {
+        // Only texture views can reinterpret images or cover several levels.
+        if format != image.format
+            || swizzle != Swizzle::NO
+            || range.levels.end > range.levels.start + 1
+        {
+            return self.create_texture_view(image, kind, format, swizzle, range);
+        }
         //TODO: check if `layers.end` covers all the layers
         let level = range.levels.start;
         assert_eq!(level + 1, range.levels.end);
-        //assert_eq!(format, image.format);
-        assert_eq!(swizzle, Swizzle::NO);
-        //TODO: check format
         match image.kind {
             n::ImageKind::Surface(surface) => {
                 if range.levels.start == 0 && range.layers.start == 0 {
@@ -1343,33 +2569,41 @@ impl d::Device<B> for Device {
             let set = &mut write.set;
             let mut bindings = set.bindings.lock().unwrap();
             let binding = write.binding;
-            let mut offset = write.array_offset as _;
+            let ty = set
+                .layout
+                .iter()
+                .find(|b| b.binding == binding)
+                .map(|b| b.ty);
+            let buffer_type = match ty {
+                Some(pso::DescriptorType::StorageBuffer)
+                | Some(pso::DescriptorType::StorageBufferDynamic) => {
+                    n::BindingTypes::StorageBuffers
+                }
+                _ => n::BindingTypes::UniformBuffers,
+            };
+            let storage_image = ty == Some(pso::DescriptorType::StorageImage);
 
             for descriptor in write.descriptors {
                 match descriptor.borrow() {
                     pso::Descriptor::Buffer(buffer, ref range) => {
                         let start = range.start.unwrap_or(0);
                         let end = range.end.unwrap_or(buffer.requirements.size);
-                        let size = (end - start) as _;
-
                         bindings.push(n::DescSetBindings::Buffer {
-                            ty: n::BindingTypes::UniformBuffers,
+                            ty: buffer_type,
                             binding,
                             buffer: buffer.raw,
-                            offset,
-                            size,
+                            offset: start as _,
+                            size: (end - start) as _,
                         });
-
-                        offset += size;
                     }
                     pso::Descriptor::CombinedImageSampler(view, _layout, sampler) => {
-                        match view {
+                        let (tex, target) = match *view {
                             n::ImageView::Texture(tex, _)
-                            | n::ImageView::TextureLayer(tex, _, _) => {
-                                bindings.push(n::DescSetBindings::Texture(binding, *tex))
-                            }
+                            | n::ImageView::TextureLayer(tex, _, _) => (tex, gl::TEXTURE_2D),
+                            n::ImageView::TextureView(tex, target) => (tex, target),
                             n::ImageView::Surface(_) => unimplemented!(),
-                        }
+                        };
+                        bindings.push(n::DescSetBindings::Texture(binding, tex, target));
                         match sampler {
                             n::FatSampler::Sampler(sampler) => {
                                 bindings.push(n::DescSetBindings::Sampler(binding, *sampler))
@@ -1378,14 +2612,47 @@ impl d::Device<B> for Device {
                                 .push(n::DescSetBindings::SamplerInfo(binding, info.clone())),
                         }
                     }
-                    pso::Descriptor::Image(view, _layout) => match view {
-                        n::ImageView::Texture(tex, _) | n::ImageView::TextureLayer(tex, _, _) => {
-                            bindings.push(n::DescSetBindings::Texture(binding, *tex))
-                        }
-                        n::ImageView::Surface(_) => panic!(
-                            "Texture was created with only render target usage which is invalid."
-                        ),
-                    },
+                    pso::Descriptor::Image(view, _layout) if storage_image => {
+                        let (texture, target, level, layer) = match *view {
+                            n::ImageView::Texture(tex, level) => (tex, gl::TEXTURE_2D, level, None),
+                            n::ImageView::TextureLayer(tex, level, layer) => {
+                                (tex, gl::TEXTURE_2D, level, Some(layer))
+                            }
+                            n::ImageView::TextureView(tex, target) => (tex, target, 0, None),
+                            n::ImageView::Surface(_) => {
+                                panic!("Storage images must be created with storage usage.")
+                            }
+                        };
+                        // Image units need the format of the view, which
+                        // isn't stored with it.
+                        let gl = &self.share.context;
+                        let mut format = 0;
+                        gl.BindTexture(target, texture);
+                        gl.GetTexLevelParameteriv(
+                            target,
+                            level as _,
+                            gl::TEXTURE_INTERNAL_FORMAT,
+                            &mut format,
+                        );
+                        bindings.push(n::DescSetBindings::StorageImage {
+                            binding,
+                            texture,
+                            level,
+                            layer,
+                            format: format as _,
+                        });
+                    }
+                    pso::Descriptor::Image(view, _layout) => {
+                        let (tex, target) = match *view {
+                            n::ImageView::Texture(tex, _)
+                            | n::ImageView::TextureLayer(tex, _, _) => (tex, gl::TEXTURE_2D),
+                            n::ImageView::TextureView(tex, target) => (tex, target),
+                            n::ImageView::Surface(_) => panic!(
+                                "Texture was created with only render target usage which is invalid."
+                            ),
+                        };
+                        bindings.push(n::DescSetBindings::Texture(binding, tex, target));
+                    }
                     pso::Descriptor::Sampler(sampler) => match sampler {
                         n::FatSampler::Sampler(sampler) => {
                             bindings.push(n::DescSetBindings::Sampler(binding, *sampler))
@@ -1412,7 +2679,7 @@ impl d::Device<B> for Device {
     }
 
     fn create_semaphore(&self) -> Result<n::Semaphore, d::OutOfMemory> {
-        Ok(n::Semaphore)
+        Ok(n::Semaphore::new(ptr::null()))
     }
 
     fn create_fence(&self, signalled: bool) -> Result<n::Fence, d::OutOfMemory> {
@@ -1452,20 +2719,32 @@ impl d::Device<B> for Device {
         if !self.share.private_caps.sync {
             return Ok(true);
         }
-        match wait_fence(fence, &self.share, timeout_ns) {
-            gl::TIMEOUT_EXPIRED => Ok(false),
-            gl::WAIT_FAILED => {
-                if let Err(err) = self.share.check() {
-                    error!("Error when waiting on fence: {:?}", err);
+        // Fences which were never submitted can't be signalled by waiting.
+        if fence.0.get().is_null() {
+            return Ok(false);
+        }
+        loop {
+            match wait_fence(fence, &self.share, timeout_ns) {
+                // Drivers may clamp the timeout, keep waiting.
+                gl::TIMEOUT_EXPIRED if timeout_ns == !0 => continue,
+                gl::TIMEOUT_EXPIRED => return Ok(false),
+                gl::WAIT_FAILED => {
+                    if let Err(err) = self.share.check() {
+                        error!("Error when waiting on fence: {:?}", err);
+                    }
+                    return Ok(false);
                 }
-                Ok(false)
+                _ => return Ok(true),
             }
-            _ => Ok(true),
         }
     }
 
-    unsafe fn get_fence_status(&self, _: &n::Fence) -> Result<bool, d::DeviceLost> {
-        unimplemented!()
+    unsafe fn get_fence_status(&self, fence: &n::Fence) -> Result<bool, d::DeviceLost> {
+        if !self.share.private_caps.sync {
+            return Ok(true);
+        }
+        let sync = fence.0.get();
+        Ok(!sync.is_null() && is_sync_signalled(&self.share, sync))
     }
 
     unsafe fn free_memory(&self, _memory: n::Memory) {
@@ -1474,25 +2753,95 @@ impl d::Device<B> for Device {
 
     unsafe fn create_query_pool(
         &self,
-        _ty: query::Type,
-        _count: query::Id,
-    ) -> Result<(), query::CreationError> {
-        unimplemented!()
+        ty: query::Type,
+        count: query::Id,
+    ) -> Result<n::QueryPool, query::CreationError> {
+        let gl = &self.share.context;
+        let target = match ty {
+            query::Type::Occlusion if self.share.info.version.is_embedded => gl::ANY_SAMPLES_PASSED,
+            query::Type::Occlusion => gl::SAMPLES_PASSED,
+            query::Type::Timestamp if gl.QueryCounter.is_loaded() => gl::TIMESTAMP,
+            _ => return Err(query::CreationError::Unsupported(ty)),
+        };
+        if !gl.GenQueries.is_loaded() {
+            return Err(query::CreationError::Unsupported(ty));
+        }
+
+        let mut queries = vec![0; count as usize];
+        gl.GenQueries(count as _, queries.as_mut_ptr());
+
+        if let Err(err) = self.share.check() {
+            error!("Error creating query pool: {:?} for {:?}", err, ty);
+            gl.DeleteQueries(count as _, queries.as_ptr());
+            return Err(match err {
+                crate::Error::OutOfMemory => {
+                    query::CreationError::OutOfMemory(d::OutOfMemory::OutOfHostMemory)
+                }
+                _ => query::CreationError::Unsupported(ty),
+            });
+        }
+
+        Ok(n::QueryPool { target, queries })
     }
 
-    unsafe fn destroy_query_pool(&self, _: ()) {
-        unimplemented!()
+    unsafe fn destroy_query_pool(&self, pool: n::QueryPool) {
+        let gl = &self.share.context;
+        gl.DeleteQueries(pool.queries.len() as _, pool.queries.as_ptr());
     }
 
     unsafe fn get_query_pool_results(
         &self,
-        _pool: &(),
-        _queries: Range<query::Id>,
-        _data: &mut [u8],
-        _stride: buffer::Offset,
-        _flags: query::ResultFlags,
+        pool: &n::QueryPool,
+        queries: Range<query::Id>,
+        data: &mut [u8],
+        stride: buffer::Offset,
+        flags: query::ResultFlags,
     ) -> Result<bool, d::OomOrDeviceLost> {
-        unimplemented!()
+        let gl = &self.share.context;
+        let bits_64 = flags.contains(query::ResultFlags::BITS_64);
+        let value_size = if bits_64 { 8 } else { 4 };
+        let mut all_available = true;
+
+        for (i, id) in queries.enumerate() {
+            let query = pool.queries[id as usize];
+            let available = if flags.contains(query::ResultFlags::WAIT) {
+                true
+            } else {
+                let mut available = 0;
+                gl.GetQueryObjectuiv(query, gl::QUERY_RESULT_AVAILABLE, &mut available);
+                available != 0
+            };
+            all_available &= available;
+
+            let offset = i * stride as usize;
+            if available || flags.contains(query::ResultFlags::PARTIAL) {
+                let result_name = if available {
+                    Some(gl::QUERY_RESULT)
+                } else if self.share.private_caps.query_buffer_object {
+                    Some(gl::QUERY_RESULT_NO_WAIT)
+                } else {
+                    // Intermediate results can't be queried, report zero.
+                    None
+                };
+                let value = result_name.map_or(0, |name| get_query_value(gl, query, name, bits_64));
+                write_query_value(&mut data[offset..], value, bits_64);
+            }
+            if flags.contains(query::ResultFlags::WITH_AVAILABILITY) {
+                write_query_value(&mut data[offset + value_size..], available as u64, bits_64);
+            }
+        }
+
+        if let Err(err) = self.share.check() {
+            error!("Error getting query pool results: {:?}", err);
+            return Err(match err {
+                crate::Error::OutOfMemory => {
+                    d::OomOrDeviceLost::OutOfMemory(d::OutOfMemory::OutOfHostMemory)
+                }
+                _ => d::OomOrDeviceLost::DeviceLost(d::DeviceLost),
+            });
+        }
+
+        Ok(all_available)
     }
 
     unsafe fn destroy_shader_module(&self, _: n::ShaderModule) {
@@ -1521,7 +2870,12 @@ impl d::Device<B> for Device {
     }
 
     unsafe fn destroy_buffer(&self, buffer: n::Buffer) {
-        self.share.context.DeleteBuffers(1, &buffer.raw);
+        let gl = &self.share.context;
+        self.vertex_arrays
+            .lock()
+            .unwrap()
+            .evict_buffer(gl, buffer.raw);
+        gl.DeleteBuffers(1, &buffer.raw);
     }
     unsafe fn destroy_buffer_view(&self, _: n::BufferView) {
         // Nothing to do
@@ -1535,14 +2889,34 @@ impl d::Device<B> for Device {
         }
     }
 
-    unsafe fn destroy_image_view(&self, _image_view: n::ImageView) {
-        // Nothing to do
+    unsafe fn destroy_image_view(&self, image_view: n::ImageView) {
+        if let n::ImageView::TextureView(texture, _) = image_view {
+            self.share.context.DeleteTextures(1, &texture);
+        }
     }
 
     unsafe fn destroy_sampler(&self, sampler: n::FatSampler) {
         let gl = &self.share.context;
         match sampler {
-            n::FatSampler::Sampler(s) => gl.DeleteSamplers(1, &s),
+            n::FatSampler::Sampler(s) => {
+                // Only delete the sampler object once it isn't shared anymore.
+                let mut samplers = self.samplers.lock().unwrap();
+                let unused = samplers
+                    .iter_mut()
+                    .find(|(_, entry)| entry.0 == s)
+                    .and_then(|(info, entry)| {
+                        entry.1 -= 1;
+                        if entry.1 == 0 {
+                            Some(info.clone())
+                        } else {
+                            None
+                        }
+                    });
+                if let Some(info) = unused {
+                    samplers.remove(&info);
+                    gl.DeleteSamplers(1, &s);
+                }
+            }
             _ => (),
         }
     }
@@ -1563,21 +2937,41 @@ impl d::Device<B> for Device {
         }
     }
 
-    unsafe fn destroy_semaphore(&self, _: n::Semaphore) {
-        // Nothing to do
+    unsafe fn destroy_semaphore(&self, semaphore: n::Semaphore) {
+        let gl = &self.share.context;
+        let sync = semaphore.0.get();
+        if self.share.private_caps.sync && gl.IsSync(sync) == gl::TRUE {
+            gl.DeleteSync(sync);
+        }
     }
 
     unsafe fn create_swapchain(
         &self,
         surface: &mut Surface,
         config: c::SwapchainConfig,
-        _old_swapchain: Option<Swapchain>,
+        old_swapchain: Option<Swapchain>,
     ) -> Result<(Swapchain, c::Backbuffer<B>), c::window::CreationError> {
-        Ok(self.create_swapchain_impl(surface, config))
+        if let Some(old_swapchain) = old_swapchain {
+            self.destroy_swapchain(old_swapchain);
+        }
+        self.create_swapchain_impl(surface, config)
     }
 
-    unsafe fn destroy_swapchain(&self, _swapchain: Swapchain) {
-        // Nothing to do
+    unsafe fn destroy_swapchain(&self, swapchain: Swapchain) {
+        swapchain.destroy_shared();
+        let gl = &self.share.context;
+        gl.DeleteFramebuffers(swapchain.fbos.len() as _, swapchain.fbos.as_ptr());
+        gl.DeleteRenderbuffers(
+            swapchain.renderbuffers.len() as _,
+            swapchain.renderbuffers.as_ptr(),
+        );
+        if let Some((fbo, rbo)) = swapchain.resolve {
+            gl.DeleteFramebuffers(1, &fbo);
+            gl.DeleteRenderbuffers(1, &rbo);
+        }
+        for fence in swapchain.presented {
+            self.destroy_fence(fence);
+        }
     }
 
     fn wait_idle(&self) -> Result<(), error::HostExecutionError> {
@@ -1588,19 +2982,111 @@ impl d::Device<B> for Device {
     }
 }
 
-pub(crate) fn wait_fence(fence: &n::Fence, share: &Starc<Share>, timeout_ns: u64) -> GLenum {
-    // TODO:
-    // This can be called by multiple objects wanting to ensure they have exclusive
-    // access to a resource. How much does this call costs ? The status of the fence
-    // could be cached to avoid calling this more than once (in core or in the backend ?).
-    let gl = &share.context;
+/// Read a query result, as 64 bits if requested and supported.
+unsafe fn get_query_value(
+    gl: &GlContainer,
+    query: gl::types::GLuint,
+    result_name: GLenum,
+    bits_64: bool,
+) -> u64 {
+    if bits_64 && gl.GetQueryObjectui64v.is_loaded() {
+        let mut value = 0;
+        gl.GetQueryObjectui64v(query, result_name, &mut value);
+        value
+    } else {
+        let mut value = 0;
+        gl.GetQueryObjectuiv(query, result_name, &mut value);
+        value as u64
+    }
+}
+
+/// Write a query result, truncated to 32 bits unless `bits_64` is set.
+fn write_query_value(data: &mut [u8], value: u64, bits_64: bool) {
     unsafe {
-        if share.private_caps.sync {
-            gl.ClientWaitSync(fence.0.get(), gl::SYNC_FLUSH_COMMANDS_BIT, timeout_ns)
+        if bits_64 {
+            ptr::write_unaligned(data[..8].as_mut_ptr() as *mut u64, value);
         } else {
-            // We fallback to waiting for *everything* to finish
-            gl.Flush();
-            gl::CONDITION_SATISFIED
+            ptr::write_unaligned(data[..4].as_mut_ptr() as *mut u32, value as u32);
+        }
+    }
+}
+
+/// Replace the sync object stored in `slot` by a new one, which will be
+/// signalled once all previously issued commands have completed.
+pub(crate) fn signal_sync(share: &Starc<Share>, slot: &Cell<gl::types::GLsync>) {
+    if !share.private_caps.sync {
+        return;
+    }
+    let gl = &share.context;
+    unsafe {
+        let old = slot.replace(gl.FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0));
+        if !old.is_null() && gl.IsSync(old) == gl::TRUE {
+            gl.DeleteSync(old);
         }
     }
 }
+
+/// Check the status of a sync object without flushing or waiting.
+pub(crate) fn is_sync_signalled(share: &Starc<Share>, sync: gl::types::GLsync) -> bool {
+    let gl = &share.context;
+    let mut status = 0;
+    unsafe { gl.GetSynciv(sync, gl::SYNC_STATUS, 1, ptr::null_mut(), &mut status) };
+    status == gl::SIGNALED as GLint
+}
+
+pub(crate) fn wait_fence(fence: &n::Fence, share: &Starc<Share>, timeout_ns: u64) -> GLenum {
+    let gl = &share.context;
+    if !share.private_caps.sync {
+        // We fallback to waiting for *everything* to finish
+        unsafe { gl.Finish() };
+        return gl::CONDITION_SATISFIED;
+    }
+
+    let sync = fence.0.get();
+    if sync.is_null() {
+        return gl::TIMEOUT_EXPIRED;
+    }
+    // Signalled fences are common when polling, skip flushing the command stream.
+    if is_sync_signalled(share, sync) {
+        return gl::ALREADY_SIGNALED;
+    }
+    unsafe { gl.ClientWaitSync(sync, gl::SYNC_FLUSH_COMMANDS_BIT, timeout_ns) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{specialization_constants, specialization_value};
+    use crate::hal::pso;
+
+    fn values(specialization: pso::Specialization) -> Vec<(u32, u64)> {
+        specialization_constants(&specialization)
+            .unwrap()
+            .into_iter()
+            .map(|(id, bytes)| (id, specialization_value(bytes)))
+            .collect()
+    }
+
+    #[test]
+    fn test_specialization_constants() {
+        let constants = [
+            pso::SpecializationConstant { id: 0, range: 0..4 },
+            pso::SpecializationConstant { id: 3, range: 4..5 },
+        ];
+        let first = pso::Specialization {
+            constants: &constants,
+            data: &[1, 0, 0, 0, 1],
+        };
+        let second = pso::Specialization {
+            constants: &constants,
+            data: &[2, 1, 0, 0, 0],
+        };
+        assert_eq!(values(first), vec![(0, 1), (3, 1)]);
+        assert_eq!(values(second), vec![(0, 0x102), (3, 0)]);
+
+        let truncated = pso::Specialization {
+            constants: &constants,
+            data: &[0; 4],
+        };
+        assert!(specialization_constants(&truncated).is_err());
+    }
+}
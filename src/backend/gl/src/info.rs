@@ -1,4 +1,4 @@
-use crate::hal::{Features, Limits};
+use crate::hal::{image, Features, Limits};
 use std::collections::HashSet;
 use std::{ffi, fmt, mem, str};
 use crate::{gl, Error, GlContainer};
@@ -119,6 +119,16 @@ fn get_string(gl: &GlContainer, name: gl::types::GLenum) -> &'static str {
     }
 }
 
+// `GL_CONTEXT_FLAG_NO_ERROR_BIT_KHR`
+const CONTEXT_FLAG_NO_ERROR_BIT: usize = 0x8;
+// `GL_CONTEXT_FLAG_ROBUST_ACCESS_BIT`
+const CONTEXT_FLAG_ROBUST_ACCESS_BIT: usize = 0x4;
+// `GL_CONTEXT_ROBUST_ACCESS`, for ES contexts without context flags
+const CONTEXT_ROBUST_ACCESS: gl::types::GLenum = 0x90F3;
+// `GL_RESET_NOTIFICATION_STRATEGY` and `GL_LOSE_CONTEXT_ON_RESET`
+const RESET_NOTIFICATION_STRATEGY: gl::types::GLenum = 0x8256;
+const LOSE_CONTEXT_ON_RESET: usize = 0x8252;
+
 fn get_usize(gl: &GlContainer, name: gl::types::GLenum) -> Result<usize, Error> {
     let mut value = 0 as gl::types::GLint;
     unsafe { gl.GetIntegerv(name, &mut value) };
@@ -178,6 +188,81 @@ pub struct PrivateCaps {
     pub map: bool,
     /// Indicates if we only have support via the EXT.
     pub sampler_anisotropy_ext: bool,
+    /// Maximum sample count of multisampled renderbuffers
+    pub max_samples: u32,
+    /// Range of supported widths for aliased lines
+    pub line_width_range: [f32; 2],
+    /// Can install a debug message callback
+    pub debug_output: bool,
+    /// Supports adaptive vsync through a negative swap interval
+    pub swap_control_tear: bool,
+    /// Context was created without error reporting (`GL_KHR_no_error`)
+    pub no_error: bool,
+    /// Can source draw parameters from a buffer
+    pub draw_indirect: bool,
+    /// Can source multiple draws from a buffer in a single call
+    pub multi_draw_indirect: bool,
+    /// Can source the number of draws from a buffer
+    pub indirect_parameters: bool,
+    /// Can retrieve and reload linked program binaries
+    pub program_binary: bool,
+    /// Can create shaders from SPIR-V modules
+    pub spirv: bool,
+    /// Can create textures sharing the storage of another one
+    pub texture_view: bool,
+    /// Supported compressed texture formats
+    pub texture_compression: TextureCompression,
+    /// Can filter across cube map faces, needs to be enabled explicitly
+    pub seamless_cube_map: bool,
+    /// Can restart primitives at a custom index value
+    pub primitive_restart: bool,
+    /// Can restart primitives at the maximum value of the index type
+    pub primitive_restart_fixed_index: bool,
+    /// Can capture vertex outputs into buffers
+    pub transform_feedback: bool,
+    /// Can discard the contents of framebuffer attachments
+    pub invalidate_framebuffer: bool,
+    /// Can query results without waiting (`GL_QUERY_RESULT_NO_WAIT`)
+    pub query_buffer_object: bool,
+    /// Robustness the context was created with
+    pub robustness: Robustness,
+}
+
+/// Robustness of a context, chosen when creating it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Robustness {
+    /// Out-of-bounds buffer accesses have defined results
+    /// (`GL_CONTEXT_FLAG_ROBUST_ACCESS_BIT`).
+    pub robust_access: bool,
+    /// GPU resets lose the context (`GL_LOSE_CONTEXT_ON_RESET`) and are
+    /// reported by `glGetGraphicsResetStatus`.
+    pub lose_context_on_reset: bool,
+}
+
+impl Robustness {
+    fn get(gl: &GlContainer, info: &Info) -> Self {
+        use self::Requirement::*;
+        if !info.is_supported(&[
+            Core(4, 5),
+            Es(3, 2),
+            Ext("GL_ARB_robustness"),
+            Ext("GL_KHR_robustness"),
+            Ext("GL_EXT_robustness"),
+        ]) {
+            return Robustness::default();
+        }
+        let robust_access = if info.is_supported(&[Core(3, 0), Es(3, 2)]) {
+            get_usize(gl, gl::CONTEXT_FLAGS)
+                .map_or(false, |flags| flags & CONTEXT_FLAG_ROBUST_ACCESS_BIT != 0)
+        } else {
+            get_usize(gl, CONTEXT_ROBUST_ACCESS).map_or(false, |robust| robust != 0)
+        };
+        Robustness {
+            robust_access,
+            lose_context_on_reset: get_usize(gl, RESET_NOTIFICATION_STRATEGY)
+                .map_or(false, |strategy| strategy == LOSE_CONTEXT_ON_RESET),
+        }
+    }
 }
 
 /// OpenGL implementation information
@@ -232,6 +317,24 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Texture compression families, each exposed through their own extensions.
+    pub struct TextureCompression: u32 {
+        /// BC1-3 (DXT) formats.
+        const S3TC = 0x00000001;
+        /// sRGB variants of the BC1-3 formats.
+        const S3TC_SRGB = 0x00000002;
+        /// BC4-5 formats.
+        const RGTC = 0x00000004;
+        /// BC6-7 formats.
+        const BPTC = 0x00000008;
+        /// ETC2 and EAC formats.
+        const ETC2 = 0x00000010;
+        /// ASTC formats with LDR profile.
+        const ASTC_LDR = 0x00000020;
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum Requirement {
     Core(u32, u32),
@@ -300,44 +403,270 @@ impl Info {
     }
 }
 
+/// Bit mask of the power of two sample counts up to `max_samples`.
+fn sample_count_mask(max_samples: u32) -> u8 {
+    let max_samples = max_samples.max(1);
+    // Round down, drivers may report a maximum which isn't a power of two.
+    let max_count = (1u32 << (31 - max_samples.leading_zeros())).min(64);
+    ((max_count << 1) - 1) as u8
+}
+
 /// Load the information pertaining to the driver and the corresponding device
 /// capabilities.
 pub(crate) fn query_all(gl: &GlContainer) -> (Info, Features, LegacyFeatures, Limits, PrivateCaps) {
     use self::Requirement::*;
     let info = Info::get(gl);
-    let max_texture_size = get_usize(gl, gl::MAX_TEXTURE_SIZE).unwrap_or(64) as u32;
+    // Limits which can't be queried fall back to the minimums required by
+    // the specification.
+    let get = |name, minimum| get_usize(gl, name).unwrap_or(minimum);
+    let get_if = |requirements: &[Requirement], name, minimum| {
+        if info.is_supported(requirements) {
+            get(name, minimum)
+        } else {
+            0
+        }
+    };
+
+    let max_texture_size = get(gl::MAX_TEXTURE_SIZE, 1024) as u32;
+    let max_samples =
+        if info.is_supported(&[Core(3, 0), Es(3, 0), Ext("GL_ARB_framebuffer_object")]) {
+            get(gl::MAX_SAMPLES, 1) as u32
+        } else {
+            1
+        };
+    let sample_count_mask = sample_count_mask(max_samples);
+    let max_texture_units = get(gl::MAX_TEXTURE_IMAGE_UNITS, 16);
+    let max_vertex_attributes = get(gl::MAX_VERTEX_ATTRIBS, 16);
+    let max_draw_buffers = get(gl::MAX_DRAW_BUFFERS, 8);
+    let mut max_viewport_dimensions = [0 as gl::types::GLint; 2];
+    unsafe { gl.GetIntegerv(gl::MAX_VIEWPORT_DIMS, max_viewport_dimensions.as_mut_ptr()) };
+    let mut line_width_range = [1.0 as gl::types::GLfloat; 2];
+    unsafe { gl.GetFloatv(gl::ALIASED_LINE_WIDTH_RANGE, line_width_range.as_mut_ptr()) };
 
     let mut limits = Limits {
         max_image_1d_size: max_texture_size,
         max_image_2d_size: max_texture_size,
-        max_image_3d_size: max_texture_size,
-        max_image_cube_size: max_texture_size,
-        max_image_array_layers: get_usize(gl, gl::MAX_ARRAY_TEXTURE_LAYERS).unwrap_or(1) as u16,
-        max_texel_elements: get_usize(gl, gl::MAX_TEXTURE_BUFFER_SIZE).unwrap_or(0),
+        max_image_3d_size: get(gl::MAX_3D_TEXTURE_SIZE, 256) as u32,
+        max_image_cube_size: get(gl::MAX_CUBE_MAP_TEXTURE_SIZE, 1024) as u32,
+        max_image_array_layers: get(gl::MAX_ARRAY_TEXTURE_LAYERS, 256) as u16,
+        max_texel_elements: get_if(
+            &[Core(3, 1), Es(3, 2), Ext("GL_ARB_texture_buffer_object")],
+            gl::MAX_TEXTURE_BUFFER_SIZE,
+            65536,
+        ),
+        max_uniform_buffer_range: get_if(
+            &[Core(3, 1), Es(3, 0), Ext("GL_ARB_uniform_buffer_object")],
+            gl::MAX_UNIFORM_BLOCK_SIZE,
+            16384,
+        ) as _,
+        max_storage_buffer_range: get_if(
+            &[
+                Core(4, 3),
+                Es(3, 1),
+                Ext("GL_ARB_shader_storage_buffer_object"),
+            ],
+            gl::MAX_SHADER_STORAGE_BLOCK_SIZE,
+            1 << 24,
+        ) as _,
+        // Push constants are emulated with uniforms of the default block,
+        // which provides at least this amount.
+        max_push_constants_size: 128,
+        max_per_stage_descriptor_samplers: max_texture_units,
+        max_per_stage_descriptor_uniform_buffers: get_if(
+            &[Core(3, 1), Es(3, 0), Ext("GL_ARB_uniform_buffer_object")],
+            gl::MAX_FRAGMENT_UNIFORM_BLOCKS,
+            12,
+        ),
+        max_per_stage_descriptor_storage_buffers: get_if(
+            &[
+                Core(4, 3),
+                Es(3, 1),
+                Ext("GL_ARB_shader_storage_buffer_object"),
+            ],
+            gl::MAX_FRAGMENT_SHADER_STORAGE_BLOCKS,
+            8,
+        ),
+        max_per_stage_descriptor_sampled_images: max_texture_units,
+        max_per_stage_descriptor_storage_images: get_if(
+            &[Core(4, 2), Es(3, 1), Ext("GL_ARB_shader_image_load_store")],
+            gl::MAX_FRAGMENT_IMAGE_UNIFORMS,
+            8,
+        ),
+        max_per_stage_descriptor_input_attachments: max_texture_units,
+        max_descriptor_set_samplers: get(gl::MAX_COMBINED_TEXTURE_IMAGE_UNITS, 48),
+        max_descriptor_set_uniform_buffers: get_if(
+            &[Core(3, 1), Es(3, 0), Ext("GL_ARB_uniform_buffer_object")],
+            gl::MAX_UNIFORM_BUFFER_BINDINGS,
+            36,
+        ),
+        max_descriptor_set_storage_buffers: get_if(
+            &[
+                Core(4, 3),
+                Es(3, 1),
+                Ext("GL_ARB_shader_storage_buffer_object"),
+            ],
+            gl::MAX_SHADER_STORAGE_BUFFER_BINDINGS,
+            8,
+        ),
+        max_descriptor_set_sampled_images: get(gl::MAX_COMBINED_TEXTURE_IMAGE_UNITS, 48),
+        max_descriptor_set_storage_images: get_if(
+            &[Core(4, 2), Es(3, 1), Ext("GL_ARB_shader_image_load_store")],
+            gl::MAX_IMAGE_UNITS,
+            8,
+        ),
+        max_vertex_input_attributes: max_vertex_attributes,
+        max_vertex_input_bindings: if info.is_supported(&[
+            Core(4, 3),
+            Es(3, 1),
+            Ext("GL_ARB_vertex_attrib_binding"),
+        ]) {
+            get(gl::MAX_VERTEX_ATTRIB_BINDINGS, 16)
+        } else {
+            // Each attribute is sourced from its own buffer binding.
+            max_vertex_attributes
+        },
+        max_vertex_input_attribute_offset: if info.is_supported(&[
+            Core(4, 3),
+            Es(3, 1),
+            Ext("GL_ARB_vertex_attrib_binding"),
+        ]) {
+            get(gl::MAX_VERTEX_ATTRIB_RELATIVE_OFFSET, 2047)
+        } else {
+            2047
+        },
+        max_vertex_input_binding_stride: if info.is_supported(&[Core(4, 4), Es(3, 1)]) {
+            get(gl::MAX_VERTEX_ATTRIB_STRIDE, 2048)
+        } else {
+            2048
+        },
+        max_vertex_output_components: get_if(
+            &[Core(3, 2), Es(3, 0)],
+            gl::MAX_VERTEX_OUTPUT_COMPONENTS,
+            64,
+        ),
+        max_geometry_shader_invocations: get_if(
+            &[Core(4, 0), Ext("GL_ARB_gpu_shader5")],
+            gl::MAX_GEOMETRY_SHADER_INVOCATIONS,
+            32,
+        ),
+        max_geometry_input_components: get_if(
+            &[Core(3, 2), Es(3, 2)],
+            gl::MAX_GEOMETRY_INPUT_COMPONENTS,
+            64,
+        ),
+        max_geometry_output_components: get_if(
+            &[Core(3, 2), Es(3, 2)],
+            gl::MAX_GEOMETRY_OUTPUT_COMPONENTS,
+            128,
+        ),
+        max_geometry_output_vertices: get_if(
+            &[Core(3, 2), Es(3, 2)],
+            gl::MAX_GEOMETRY_OUTPUT_VERTICES,
+            256,
+        ),
+        max_geometry_total_output_components: get_if(
+            &[Core(3, 2), Es(3, 2)],
+            gl::MAX_GEOMETRY_TOTAL_OUTPUT_COMPONENTS,
+            1024,
+        ),
+        max_fragment_input_components: get_if(
+            &[Core(3, 2), Es(3, 0)],
+            gl::MAX_FRAGMENT_INPUT_COMPONENTS,
+            128,
+        ),
+        max_fragment_output_attachments: max_draw_buffers,
+        max_fragment_dual_source_attachments: get_if(
+            &[Core(3, 3), Ext("GL_ARB_blend_func_extended")],
+            gl::MAX_DUAL_SOURCE_DRAW_BUFFERS,
+            1,
+        ),
+        max_fragment_combined_output_resources: if info.is_supported(&[Core(4, 3), Es(3, 1)]) {
+            get(gl::MAX_COMBINED_SHADER_OUTPUT_RESOURCES, max_draw_buffers)
+        } else {
+            max_draw_buffers
+        },
+        max_draw_indexed_index_value: if info.is_supported(&[
+            Core(4, 3),
+            Es(3, 0),
+            Ext("GL_ARB_ES3_compatibility"),
+        ]) {
+            get(gl::MAX_ELEMENT_INDEX, (1 << 24) - 1) as _
+        } else {
+            (1 << 24) - 1
+        },
+        max_draw_indirect_count: if info
+            .is_supported(&[Core(4, 3), Ext("GL_ARB_multi_draw_indirect")])
+        {
+            !0
+        } else {
+            1
+        },
+        max_sampler_lod_bias: {
+            let mut value = 2.0;
+            unsafe { gl.GetFloatv(gl::MAX_TEXTURE_LOD_BIAS, &mut value) };
+            value
+        },
         max_viewports: 1,
+        max_viewport_dimensions: [
+            max_viewport_dimensions[0] as _,
+            max_viewport_dimensions[1] as _,
+        ],
+        max_framebuffer_extent: if info.is_supported(&[
+            Core(4, 3),
+            Es(3, 1),
+            Ext("GL_ARB_framebuffer_no_attachments"),
+        ]) {
+            image::Extent {
+                width: get(gl::MAX_FRAMEBUFFER_WIDTH, 16384) as _,
+                height: get(gl::MAX_FRAMEBUFFER_HEIGHT, 16384) as _,
+                depth: get(gl::MAX_FRAMEBUFFER_LAYERS, 2048) as _,
+            }
+        } else {
+            image::Extent {
+                width: max_texture_size,
+                height: max_texture_size,
+                depth: 1,
+            }
+        },
+        framebuffer_color_samples_count: sample_count_mask,
+        framebuffer_depth_samples_count: sample_count_mask,
+        framebuffer_stencil_samples_count: sample_count_mask,
+        max_color_attachments: get(gl::MAX_COLOR_ATTACHMENTS, 8),
         optimal_buffer_copy_offset_alignment: 1,
         optimal_buffer_copy_pitch_alignment: 1,
-        min_texel_buffer_offset_alignment: 1,   // TODO
-        min_uniform_buffer_offset_alignment: 1, // TODO
-        min_storage_buffer_offset_alignment: 1, // TODO
+        min_texel_buffer_offset_alignment: get_if(
+            &[Core(4, 3), Es(3, 2), Ext("GL_ARB_texture_buffer_range")],
+            gl::TEXTURE_BUFFER_OFFSET_ALIGNMENT,
+            256,
+        )
+        .max(1) as _,
+        min_uniform_buffer_offset_alignment: get_if(
+            &[Core(3, 1), Es(3, 0), Ext("GL_ARB_uniform_buffer_object")],
+            gl::UNIFORM_BUFFER_OFFSET_ALIGNMENT,
+            256,
+        )
+        .max(1) as _,
+        min_storage_buffer_offset_alignment: get_if(
+            &[
+                Core(4, 3),
+                Es(3, 1),
+                Ext("GL_ARB_shader_storage_buffer_object"),
+            ],
+            gl::SHADER_STORAGE_BUFFER_OFFSET_ALIGNMENT,
+            256,
+        )
+        .max(1) as _,
         ..Limits::default()
     };
 
-    if info.is_supported(&[Core(4, 0), Ext("GL_ARB_tessellation_shader")]) {
+    if info.is_supported(&[Core(4, 0), Es(3, 2), Ext("GL_ARB_tessellation_shader")]) {
         limits.max_patch_size = get_usize(gl, gl::MAX_PATCH_VERTICES).unwrap_or(0) as _;
     }
-    if info.is_supported(&[Core(4, 1)]) {
-        // TODO: extension
+    if info.is_supported(&[Core(4, 1), Ext("GL_ARB_viewport_array")]) {
         limits.max_viewports = get_usize(gl, gl::MAX_VIEWPORTS).unwrap_or(0);
     }
 
-    if false
-        && info.is_supported(&[
-            //TODO: enable when compute is implemented
-            Core(4, 3),
-            Ext("GL_ARB_compute_shader"),
-        ])
-    {
+    if info.is_supported(&[Core(4, 3), Es(3, 1), Ext("GL_ARB_compute_shader")]) {
         let mut values = [0 as gl::types::GLint; 2];
         for (i, (count, size)) in limits
             .max_compute_work_group_count
@@ -352,17 +681,39 @@ pub(crate) fn query_all(gl: &GlContainer) -> (Info, Features, LegacyFeatures, Li
             *count = values[0] as _;
             *size = values[1] as _;
         }
+        limits.max_compute_shared_memory_size = get(gl::MAX_COMPUTE_SHARED_MEMORY_SIZE, 32768);
+        limits.max_compute_work_group_invocations =
+            get(gl::MAX_COMPUTE_WORK_GROUP_INVOCATIONS, 1024);
     }
 
     let mut features = Features::empty();
     let mut legacy = LegacyFeatures::empty();
 
+    let robustness = Robustness::get(gl, &info);
+    if robustness.robust_access {
+        features |= Features::ROBUST_BUFFER_ACCESS;
+    }
+
+    if line_width_range[1] > 1.0 {
+        features |= Features::LINE_WIDTH;
+    }
+    // GLES has no `glPolygonMode`.
+    if !info.version.is_embedded {
+        features |= Features::NON_FILL_POLYGON_MODE;
+    }
+
     if info.is_supported(&[
         Core(4, 6),
         Ext("GL_ARB_texture_filter_anisotropic"),
         Ext("GL_EXT_texture_filter_anisotropic"),
     ]) {
         features |= Features::SAMPLER_ANISOTROPY;
+        unsafe {
+            gl.GetFloatv(
+                gl::MAX_TEXTURE_MAX_ANISOTROPY_EXT,
+                &mut limits.max_sampler_anisotropy,
+            )
+        };
     }
     if info.is_supported(&[Core(4, 2)]) {
         legacy |= LegacyFeatures::EXPLICIT_LAYOUTS_IN_SHADER;
@@ -437,6 +788,73 @@ pub(crate) fn query_all(gl: &GlContainer) -> (Info, Features, LegacyFeatures, Li
         legacy |= LegacyFeatures::INSTANCED_ATTRIBUTE_BINDING;
     }
 
+    if info.is_supported(&[Core(4, 0), Es(3, 2), Ext("GL_ARB_draw_buffers_blend")]) {
+        features |= Features::INDEPENDENT_BLENDING;
+    }
+    // Logic operations on color buffers aren't available on GLES.
+    if info.is_supported(&[Core(1, 1)]) {
+        features |= Features::LOGIC_OP;
+    }
+    if info.is_supported(&[
+        Core(3, 2),
+        Ext("GL_ARB_depth_clamp"),
+        Ext("GL_EXT_depth_clamp"),
+    ]) {
+        features |= Features::DEPTH_CLAMP;
+    }
+    if info.is_supported(&[
+        Core(3, 2),
+        Es(3, 2),
+        Ext("GL_ARB_geometry_shader4"),
+        Ext("GL_EXT_geometry_shader"),
+    ]) {
+        features |= Features::GEOMETRY_SHADER;
+    }
+    if info.is_supported(&[Core(4, 0), Es(3, 2), Ext("GL_ARB_tessellation_shader")]) {
+        features |= Features::TESSELLATION_SHADER;
+    }
+
+    let mut texture_compression = TextureCompression::empty();
+    if info.is_supported(&[Ext("GL_EXT_texture_compression_s3tc")]) {
+        texture_compression |= TextureCompression::S3TC;
+        if info.is_supported(&[
+            Ext("GL_EXT_texture_sRGB"),
+            Ext("GL_EXT_texture_compression_s3tc_srgb"),
+        ]) {
+            texture_compression |= TextureCompression::S3TC_SRGB;
+        }
+    }
+    if info.is_supported(&[
+        Core(3, 0),
+        Ext("GL_ARB_texture_compression_rgtc"),
+        Ext("GL_EXT_texture_compression_rgtc"),
+    ]) {
+        texture_compression |= TextureCompression::RGTC;
+    }
+    if info.is_supported(&[
+        Core(4, 2),
+        Ext("GL_ARB_texture_compression_bptc"),
+        Ext("GL_EXT_texture_compression_bptc"),
+    ]) {
+        texture_compression |= TextureCompression::BPTC;
+    }
+    if info.is_supported(&[Core(4, 3), Es(3, 0), Ext("GL_ARB_ES3_compatibility")]) {
+        texture_compression |= TextureCompression::ETC2;
+        features |= Features::FORMAT_ETC2;
+    }
+    if info.is_supported(&[Es(3, 2), Ext("GL_KHR_texture_compression_astc_ldr")]) {
+        texture_compression |= TextureCompression::ASTC_LDR;
+        features |= Features::FORMAT_ASTC_LDR;
+    }
+    if texture_compression.contains(
+        TextureCompression::S3TC
+            | TextureCompression::S3TC_SRGB
+            | TextureCompression::RGTC
+            | TextureCompression::BPTC,
+    ) {
+        features |= Features::FORMAT_BC;
+    }
+
     let private = PrivateCaps {
         vertex_array: info.is_supported(&[Core(3, 0), Es(3, 0), Ext("GL_ARB_vertex_array_object")])
             && gl.GenVertexArrays.is_loaded(),
@@ -454,6 +872,52 @@ pub(crate) fn query_all(gl: &GlContainer) -> (Info, Features, LegacyFeatures, Li
         sampler_anisotropy_ext: !info
             .is_supported(&[Core(4, 6), Ext("GL_ARB_texture_filter_anisotropic")])
             && info.is_supported(&[Ext("GL_EXT_texture_filter_anisotropic")]),
+        max_samples,
+        line_width_range,
+        debug_output: info.is_supported(&[Core(4, 3), Es(3, 2), Ext("GL_KHR_debug")]),
+        // Platform extensions, which some drivers list among the GL ones.
+        swap_control_tear: info.is_supported(&[
+            Ext("WGL_EXT_swap_control_tear"),
+            Ext("GLX_EXT_swap_control_tear"),
+        ]),
+        no_error: info.is_supported(&[Core(4, 6), Ext("GL_KHR_no_error")])
+            && get_usize(gl, gl::CONTEXT_FLAGS)
+                .map_or(false, |flags| flags & CONTEXT_FLAG_NO_ERROR_BIT != 0),
+        draw_indirect: info.is_supported(&[Core(4, 0), Es(3, 1), Ext("GL_ARB_draw_indirect")]),
+        multi_draw_indirect: info.is_supported(&[Core(4, 3), Ext("GL_ARB_multi_draw_indirect")]),
+        indirect_parameters: info.is_supported(&[Core(4, 6), Ext("GL_ARB_indirect_parameters")])
+            && gl.multi_draw_elements_indirect_count.is_some(),
+        // Some drivers expose the entry points without any binary format.
+        program_binary: info.is_supported(&[
+            Core(4, 1),
+            Es(3, 0),
+            Ext("GL_ARB_get_program_binary"),
+        ]) && get_usize(gl, gl::NUM_PROGRAM_BINARY_FORMATS)
+            .map_or(false, |n| n > 0),
+        spirv: info.is_supported(&[Core(4, 6), Ext("GL_ARB_gl_spirv")])
+            && gl.specialize_shader.is_some(),
+        texture_view: info.is_supported(&[Core(4, 3), Ext("GL_ARB_texture_view")]),
+        texture_compression,
+        // Always enabled on ES 3.0+, there is no state to toggle.
+        seamless_cube_map: info.is_supported(&[Core(3, 2), Ext("GL_ARB_seamless_cube_map")]),
+        primitive_restart: info.is_supported(&[Core(3, 1)]),
+        primitive_restart_fixed_index: info.is_supported(&[
+            Core(4, 3),
+            Es(3, 0),
+            Ext("GL_ARB_ES3_compatibility"),
+        ]),
+        transform_feedback: info.is_supported(&[
+            Core(3, 0),
+            Es(3, 0),
+            Ext("GL_EXT_transform_feedback"),
+        ]),
+        invalidate_framebuffer: info.is_supported(&[
+            Core(4, 3),
+            Es(3, 0),
+            Ext("GL_ARB_invalidate_subdata"),
+        ]),
+        query_buffer_object: info.is_supported(&[Core(4, 4), Ext("GL_ARB_query_buffer_object")]),
+        robustness,
     };
 
     (info, features, legacy, limits, private)
@@ -461,7 +925,17 @@ pub(crate) fn query_all(gl: &GlContainer) -> (Info, Features, LegacyFeatures, Li
 
 #[cfg(test)]
 mod tests {
-    use super::Version;
+    use super::{sample_count_mask, Version};
+
+    #[test]
+    fn test_sample_count_mask() {
+        assert_eq!(sample_count_mask(0), 0b1);
+        assert_eq!(sample_count_mask(1), 0b1);
+        assert_eq!(sample_count_mask(4), 0b111);
+        assert_eq!(sample_count_mask(6), 0b111);
+        assert_eq!(sample_count_mask(8), 0b1111);
+        assert_eq!(sample_count_mask(128), 0b111_1111);
+    }
 
     #[test]
     fn test_version_parse() {
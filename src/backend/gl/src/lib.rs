@@ -1,5 +1,13 @@
 //! OpenGL implementation of a device, striving to support OpenGL 2.0 with at
 //! least VAOs, but using newer extensions when available.
+//!
+//! Images are stored with their first row at the top, as in HAL: GL window
+//! coordinates are used as framebuffer coordinates without flipping the
+//! rendering. Viewports, scissors, copies and read-backs thus map directly
+//! between HAL and GL, and the image is only flipped when blitting swapchain
+//! images to the bottom-left origin of the window on present. Swapchains
+//! therefore always render into images, and can't be created without
+//! framebuffer support.
 
 #![allow(missing_docs, missing_copy_implementations)]
 
@@ -14,17 +22,31 @@ pub extern crate glutin;
 extern crate smallvec;
 extern crate spirv_cross;
 
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::fmt;
 use std::ops::Deref;
-use std::sync::{Arc, Weak};
+use std::sync::{Arc, Mutex, Weak};
 use std::thread::{self, ThreadId};
 
 use crate::hal::queue::{QueueFamilyId, Queues};
 use crate::hal::{error, image, pso};
 
-pub use self::device::Device;
-pub use self::info::{Info, PlatformName, Version};
+pub use self::command::{ConditionalMode, MipmapError};
+pub use self::device::{Device, TransformFeedbackDesc};
+pub use self::info::{Info, PlatformName, Robustness, Version};
+pub use self::transfer::PixelTransfer;
+
+/// Call a GL function, logging the errors it raised if the `check-errors`
+/// feature is enabled.
+macro_rules! gl_call {
+    ($gl:ident.$func:ident($($arg:expr),* $(,)*)) => {{
+        let gl = &$gl;
+        let result = gl.$func($($arg),*);
+        #[cfg(feature = "check-errors")]
+        crate::log_errors(gl, &concat!("gl", stringify!($func)));
+        result
+    }};
+}
 
 mod command;
 mod conv;
@@ -34,16 +56,71 @@ mod native;
 mod pool;
 mod queue;
 mod state;
+mod transfer;
 mod window;
 
 #[cfg(feature = "glutin")]
-pub use crate::window::glutin::{config_context, Headless, Surface, Swapchain};
+pub use crate::window::glutin::{
+    config_context, config_context_robust, config_context_version, Headless, PresentStats, Surface,
+    Swapchain,
+};
+pub use crate::window::offscreen::OffscreenSurface;
+
+/// `glSpecializeShader`, which isn't part of the generated bindings.
+pub(crate) type SpecializeShaderFn = unsafe extern "system" fn(
+    gl::types::GLuint,
+    *const gl::types::GLchar,
+    gl::types::GLuint,
+    *const gl::types::GLuint,
+    *const gl::types::GLuint,
+);
+
+/// `glMultiDrawElementsIndirectCount`, which isn't part of the generated bindings.
+pub(crate) type MultiDrawElementsIndirectCountFn = unsafe extern "system" fn(
+    gl::types::GLenum,
+    gl::types::GLenum,
+    *const gl::types::GLvoid,
+    gl::types::GLintptr,
+    gl::types::GLsizei,
+    gl::types::GLsizei,
+);
 
 pub(crate) struct GlContainer {
     context: gl::Gl,
+    pub(crate) specialize_shader: Option<SpecializeShaderFn>,
+    pub(crate) multi_draw_elements_indirect_count: Option<MultiDrawElementsIndirectCountFn>,
 }
 
 impl GlContainer {
+    /// Load the GL entry points.
+    ///
+    /// Each symbol is only resolved once per load. Addresses aren't kept
+    /// across loads, as they may depend on the context (e.g. with WGL).
+    fn from_fn_proc<F>(mut fn_proc: F) -> GlContainer
+    where
+        F: FnMut(&str) -> *const std::os::raw::c_void,
+    {
+        let context = gl::Gl::load_with(&mut fn_proc);
+        let specialize_shader = ["glSpecializeShader", "glSpecializeShaderARB"]
+            .iter()
+            .map(|&symbol| fn_proc(symbol))
+            .find(|addr| !addr.is_null())
+            .map(|addr| unsafe { std::mem::transmute::<_, SpecializeShaderFn>(addr) });
+        let multi_draw_elements_indirect_count = [
+            "glMultiDrawElementsIndirectCount",
+            "glMultiDrawElementsIndirectCountARB",
+        ]
+        .iter()
+        .map(|&symbol| fn_proc(symbol))
+        .find(|addr| !addr.is_null())
+        .map(|addr| unsafe { std::mem::transmute::<_, MultiDrawElementsIndirectCountFn>(addr) });
+        GlContainer {
+            context,
+            specialize_shader,
+            multi_draw_elements_indirect_count,
+        }
+    }
+
     fn make_current(&self) {
         // Unimplemented
     }
@@ -87,14 +164,14 @@ impl hal::Backend for Backend {
     type ComputePipeline = native::ComputePipeline;
     type GraphicsPipeline = native::GraphicsPipeline;
     type PipelineLayout = native::PipelineLayout;
-    type PipelineCache = ();
+    type PipelineCache = native::PipelineCache;
     type DescriptorSetLayout = native::DescriptorSetLayout;
     type DescriptorPool = native::DescriptorPool;
     type DescriptorSet = native::DescriptorSet;
 
     type Fence = native::Fence;
     type Semaphore = native::Semaphore;
-    type QueryPool = ();
+    type QueryPool = native::QueryPool;
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -122,6 +199,70 @@ impl Error {
     }
 }
 
+/// Log all errors raised since the last check, naming the call raising them.
+#[cfg(feature = "check-errors")]
+fn log_errors(gl: &gl::Gl, call: &dyn fmt::Display) {
+    // Implementations may record several errors, bail out if the flags
+    // don't get cleared (e.g. after a context loss).
+    for _ in 0..8 {
+        let err = Error::from_error_code(unsafe { gl.GetError() });
+        if err == Error::NoError {
+            break;
+        }
+        error!("{:?} raised by {}", err, call);
+    }
+}
+
+/// Severity of a message reported by the driver.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum DebugSeverity {
+    Notification,
+    Low,
+    Medium,
+    High,
+}
+
+impl DebugSeverity {
+    fn from_gl(severity: gl::types::GLenum) -> Self {
+        match severity {
+            gl::DEBUG_SEVERITY_HIGH => DebugSeverity::High,
+            gl::DEBUG_SEVERITY_MEDIUM => DebugSeverity::Medium,
+            gl::DEBUG_SEVERITY_LOW => DebugSeverity::Low,
+            _ => DebugSeverity::Notification,
+        }
+    }
+}
+
+/// Callback receiving the debug messages of the driver.
+pub type DebugCallback = dyn Fn(DebugSeverity, &str);
+
+extern "system" fn debug_message_callback(
+    _source: gl::types::GLenum,
+    _gltype: gl::types::GLenum,
+    _id: gl::types::GLuint,
+    severity: gl::types::GLenum,
+    length: gl::types::GLsizei,
+    message: *const gl::types::GLchar,
+    user_param: *mut std::os::raw::c_void,
+) {
+    let callback = unsafe { &*(user_param as *const Box<DebugCallback>) };
+    let message = unsafe {
+        if length < 0 {
+            std::ffi::CStr::from_ptr(message).to_string_lossy()
+        } else {
+            let bytes = std::slice::from_raw_parts(message as *const u8, length as usize);
+            std::string::String::from_utf8_lossy(bytes)
+        }
+    };
+    // Unwinding across the FFI boundary is undefined behavior.
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        callback(DebugSeverity::from_gl(severity), &message)
+    }));
+    if result.is_err() {
+        error!("Debug callback panicked on message: {}", message);
+    }
+}
+
 /// Internal struct of shared data between the physical and logical device.
 struct Share {
     context: GlContainer,
@@ -132,12 +273,28 @@ struct Share {
     private_caps: info::PrivateCaps,
     // Indicates if there is an active logical device.
     open: Cell<bool>,
+    // Indicates if cube maps are filtered across faces.
+    seamless_cube_map: Cell<bool>,
+    // Boxed twice, as the driver only keeps a thin pointer to it.
+    debug_callback: RefCell<Option<Box<Box<DebugCallback>>>>,
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        // The context outlives the share, the driver must not call into the
+        // callback once it's freed.
+        if self.debug_callback.get_mut().is_some() {
+            unsafe { self.context.DebugMessageCallback(None, std::ptr::null()) };
+        }
+    }
 }
 
 impl Share {
     /// Fails during a debug build if the implementation's error flag was set.
     fn check(&self) -> Result<(), Error> {
-        if cfg!(debug_assertions) {
+        // Errors are undefined behavior in no-error contexts, there is
+        // nothing to query.
+        if cfg!(debug_assertions) && !self.private_caps.no_error {
             let gl = &self.context;
             let err = Error::from_error_code(unsafe { gl.GetError() });
             if err != Error::NoError {
@@ -146,6 +303,44 @@ impl Share {
         }
         Ok(())
     }
+
+    /// Memory types exposed to the application, selecting the usage of the
+    /// buffers bound to their allocations.
+    ///
+    /// Device local memory maps to `GL_STATIC_DRAW` buffers, host visible
+    /// memory to buffers persistently mapped if supported, or otherwise
+    /// `GL_DYNAMIC_DRAW` for uploads and `GL_STREAM_READ` for downloads.
+    fn memory_types(&self) -> Vec<hal::MemoryType> {
+        use crate::hal::memory::Properties;
+
+        // COHERENT flags require that the backend does flushing and invalidation
+        // by itself. If we move towards persistent mapping we need to re-evaluate it.
+        if self.private_caps.map {
+            vec![
+                hal::MemoryType {
+                    properties: Properties::DEVICE_LOCAL,
+                    heap_index: 1,
+                },
+                hal::MemoryType {
+                    // upload
+                    properties: Properties::CPU_VISIBLE | Properties::COHERENT,
+                    heap_index: 0,
+                },
+                hal::MemoryType {
+                    // download
+                    properties: Properties::CPU_VISIBLE
+                        | Properties::COHERENT
+                        | Properties::CPU_CACHED,
+                    heap_index: 0,
+                },
+            ]
+        } else {
+            vec![hal::MemoryType {
+                properties: Properties::DEVICE_LOCAL,
+                heap_index: 0,
+            }]
+        }
+    }
 }
 
 /// Single-threaded `Arc`.
@@ -202,6 +397,12 @@ impl<T> Starc<T> {
     pub fn get_mut(this: &mut Starc<T>) -> Option<&mut T> {
         Arc::get_mut(&mut this.arc)
     }
+
+    /// Check if the value can be accessed from the current thread.
+    #[inline]
+    pub fn is_local(this: &Starc<T>) -> bool {
+        thread::current().id() == this.thread
+    }
 }
 
 unsafe impl<T: ?Sized> Send for Starc<T> {}
@@ -235,13 +436,16 @@ unsafe impl<T: ?Sized> Sync for Wstarc<T> {}
 pub struct PhysicalDevice(Starc<Share>);
 
 impl PhysicalDevice {
+    /// Get the robustness the context was created with.
+    pub fn robustness(&self) -> Robustness {
+        self.0.private_caps.robustness
+    }
+
     fn new_adapter<F>(fn_proc: F) -> hal::Adapter<Backend>
     where
         F: FnMut(&str) -> *const std::os::raw::c_void,
     {
-        let gl = GlContainer {
-            context: gl::Gl::load_with(fn_proc),
-        };
+        let gl = GlContainer::from_fn_proc(fn_proc);
 
         // query information
         let (info, features, legacy_features, limits, private_caps) = info::query_all(&gl);
@@ -260,6 +464,7 @@ impl PhysicalDevice {
         let renderer: std::string::String = info.platform_name.renderer.into();
 
         // create the shared context
+        let seamless_cube_map = Cell::new(private_caps.seamless_cube_map);
         let share = Share {
             context: gl,
             info,
@@ -268,6 +473,8 @@ impl PhysicalDevice {
             limits,
             private_caps,
             open: Cell::new(false),
+            seamless_cube_map,
+            debug_callback: RefCell::new(None),
         };
         if let Err(err) = share.check() {
             panic!("Error querying info: {:?}", err);
@@ -299,8 +506,22 @@ impl PhysicalDevice {
             "mali",
             "intel",
         ];
+        let strings_that_imply_software = [
+            "llvmpipe",
+            "softpipe",
+            "swrast",
+            "swiftshader",
+            "software rasterizer",
+            "gdi generic",
+            "microsoft basic render",
+        ];
         // todo: Intel will release a discrete gpu soon, and we will need to update this logic when they do
-        let inferred_device_type = if vendor_lower.contains("qualcomm")
+        let inferred_device_type = if strings_that_imply_software
+            .into_iter()
+            .any(|&s| renderer_lower.contains(s))
+        {
+            hal::adapter::DeviceType::Cpu
+        } else if vendor_lower.contains("qualcomm")
             || vendor_lower.contains("intel")
             || strings_that_imply_integrated
                 .into_iter()
@@ -328,6 +549,24 @@ impl PhysicalDevice {
             0
         };
 
+        // Compute support is exposed through the queue type.
+        let queue_type = if share.limits.max_compute_work_group_count[0] != 0 {
+            hal::QueueType::General
+        } else {
+            hal::QueueType::Graphics
+        };
+        let gl = &share.context;
+        let mut timestamp_valid_bits = 0;
+        if gl.QueryCounter.is_loaded() {
+            unsafe {
+                gl.GetQueryiv(
+                    gl::TIMESTAMP,
+                    gl::QUERY_COUNTER_BITS,
+                    &mut timestamp_valid_bits,
+                )
+            };
+        }
+
         hal::Adapter {
             info: hal::AdapterInfo {
                 name,
@@ -336,7 +575,10 @@ impl PhysicalDevice {
                 device_type: inferred_device_type,
             },
             physical_device: PhysicalDevice(Starc::new(share)),
-            queue_families: vec![QueueFamily],
+            queue_families: vec![QueueFamily {
+                queue_type,
+                timestamp_valid_bits: timestamp_valid_bits as u32,
+            }],
         }
     }
 
@@ -344,6 +586,59 @@ impl PhysicalDevice {
     pub fn legacy_features(&self) -> &info::LegacyFeatures {
         &self.0.legacy_features
     }
+
+    /// Install a callback receiving the messages of the driver.
+    ///
+    /// Requires `GL_KHR_debug`, and usually a debug context for the driver
+    /// to report anything.
+    pub fn set_debug_callback<F>(&self, callback: F)
+    where
+        F: Fn(DebugSeverity, &str) + 'static,
+    {
+        if !self.0.private_caps.debug_output {
+            warn!("Debug output is not supported");
+            return;
+        }
+
+        let callback: Box<Box<DebugCallback>> = Box::new(Box::new(callback));
+        let user_param = &*callback as *const Box<DebugCallback>;
+        let gl = &self.0.context;
+        unsafe {
+            gl.Enable(gl::DEBUG_OUTPUT);
+            // Messages have to be delivered on the thread owning the context.
+            gl.Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
+            gl.DebugMessageCallback(Some(debug_message_callback), user_param as *const _);
+        }
+        // The previous callback is only freed once the driver got the new one.
+        *self.0.debug_callback.borrow_mut() = Some(callback);
+    }
+
+    /// Enable or disable filtering across the faces of cube maps.
+    ///
+    /// Seamless filtering is enabled by default where supported. Disabling
+    /// it clamps the sampling to each face instead. Has to be called before
+    /// opening the logical device.
+    pub fn set_seamless_cube_map(&self, enable: bool) {
+        if self.0.open.get() {
+            warn!("Cube map filtering can't be changed on an open device");
+            return;
+        }
+        if enable && !self.0.private_caps.seamless_cube_map {
+            warn!("Seamless cube map filtering is not supported");
+            return;
+        }
+        self.0.seamless_cube_map.set(enable);
+    }
+
+    /// Check if the driver exposes the given extension.
+    pub fn supports_extension(&self, name: &str) -> bool {
+        self.0.info.extensions.contains(name)
+    }
+
+    /// Get the version of the API negotiated with the driver.
+    pub fn version(&self) -> &Version {
+        &self.0.info.version
+    }
 }
 
 impl hal::PhysicalDevice<Backend> for PhysicalDevice {
@@ -365,23 +660,19 @@ impl hal::PhysicalDevice<Backend> for PhysicalDevice {
         }
 
         // initialize permanent states
+        // sRGB encoding is toggled per subpass, following the formats of its attachments.
         let gl = &self.0.context;
-        if self
-            .0
-            .legacy_features
-            .contains(info::LegacyFeatures::SRGB_COLOR)
-        {
-            // TODO: Find way to emulate this on older Opengl versions.
-
-            gl.Enable(gl::FRAMEBUFFER_SRGB);
-        }
-
         gl.PixelStorei(gl::UNPACK_ALIGNMENT, 1);
 
+        // Point sizes are always written by shaders, ES has no fixed point size.
         if !self.0.info.version.is_embedded {
             gl.Enable(gl::PROGRAM_POINT_SIZE);
         }
 
+        if self.0.seamless_cube_map.get() {
+            gl.Enable(gl::TEXTURE_CUBE_MAP_SEAMLESS);
+        }
+
         // create main VAO and bind it
         let mut vao = 0;
         if self.0.private_caps.vertex_array {
@@ -393,15 +684,17 @@ impl hal::PhysicalDevice<Backend> for PhysicalDevice {
             panic!("Error opening adapter: {:?}", err);
         }
 
+        let vertex_arrays = Arc::new(Mutex::new(queue::VertexArrayCache::new()));
+
         Ok(hal::Gpu {
-            device: Device::new(self.0.clone()),
+            device: Device::new(self.0.clone(), vertex_arrays.clone()),
             queues: Queues::new(
                 families
                     .into_iter()
                     .map(|&(proto_family, priorities)| {
                         assert_eq!(priorities.len(), 1);
                         let mut family = hal::backend::RawQueueGroup::new(proto_family.clone());
-                        let queue = queue::CommandQueue::new(&self.0, vao);
+                        let queue = queue::CommandQueue::new(&self.0, vao, vertex_arrays.clone());
                         family.add_queue(queue);
                         family
                     })
@@ -426,38 +719,8 @@ impl hal::PhysicalDevice<Backend> for PhysicalDevice {
     }
 
     fn memory_properties(&self) -> hal::MemoryProperties {
-        use crate::hal::memory::Properties;
-
-        // COHERENT flags require that the backend does flushing and invalidation
-        // by itself. If we move towards persistent mapping we need to re-evaluate it.
-        let memory_types = if self.0.private_caps.map {
-            vec![
-                hal::MemoryType {
-                    properties: Properties::DEVICE_LOCAL,
-                    heap_index: 1,
-                },
-                hal::MemoryType {
-                    // upload
-                    properties: Properties::CPU_VISIBLE | Properties::COHERENT,
-                    heap_index: 0,
-                },
-                hal::MemoryType {
-                    // download
-                    properties: Properties::CPU_VISIBLE
-                        | Properties::COHERENT
-                        | Properties::CPU_CACHED,
-                    heap_index: 0,
-                },
-            ]
-        } else {
-            vec![hal::MemoryType {
-                properties: Properties::DEVICE_LOCAL,
-                heap_index: 0,
-            }]
-        };
-
         hal::MemoryProperties {
-            memory_types,
+            memory_types: self.0.memory_types(),
             memory_heaps: vec![!0, !0],
         }
     }
@@ -471,12 +734,25 @@ impl hal::PhysicalDevice<Backend> for PhysicalDevice {
     }
 }
 
+/// The single queue family of a GL context, supporting graphics and transfer
+/// operations, and compute operations with GL 4.3 or ES 3.1.
 #[derive(Debug, Clone, Copy)]
-pub struct QueueFamily;
+pub struct QueueFamily {
+    queue_type: hal::QueueType,
+    timestamp_valid_bits: u32,
+}
+
+impl QueueFamily {
+    /// Number of meaningful bits in timestamp query results, `0` if
+    /// timestamps aren't supported.
+    pub fn timestamp_valid_bits(&self) -> u32 {
+        self.timestamp_valid_bits
+    }
+}
 
 impl hal::QueueFamily for QueueFamily {
     fn queue_type(&self) -> hal::QueueType {
-        hal::QueueType::General
+        self.queue_type
     }
     fn max_queues(&self) -> usize {
         1
@@ -14,6 +14,7 @@ pub type Program = gl::types::GLuint;
 pub type FrameBuffer = gl::types::GLuint;
 pub type Surface = gl::types::GLuint;
 pub type Texture = gl::types::GLuint;
+pub type TextureTarget = gl::types::GLenum;
 pub type Sampler = gl::types::GLuint;
 
 pub type DescriptorSetLayout = Vec<pso::DescriptorSetLayoutBinding>;
@@ -45,9 +46,11 @@ impl Fence {
 pub enum BindingTypes {
     Images,
     UniformBuffers,
+    StorageBuffers,
+    StorageImages,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct DescRemapData {
     bindings: FastHashMap<
         (
@@ -65,6 +68,15 @@ pub struct DescRemapData {
             pso::DescriptorBinding,
         ),
     >,
+    // Texture units of combined separate images and samplers, so that
+    // pipelines sharing a layout reuse the same units.
+    combined: FastHashMap<
+        (
+            (pso::DescriptorSetIndex, pso::DescriptorBinding),
+            (pso::DescriptorSetIndex, pso::DescriptorBinding),
+        ),
+        pso::DescriptorBinding,
+    >,
     next_binding: FastHashMap<BindingTypes, pso::DescriptorBinding>,
 }
 
@@ -76,6 +88,7 @@ impl DescRemapData {
         DescRemapData {
             bindings: FastHashMap::default(),
             names: FastHashMap::default(),
+            combined: FastHashMap::default(),
             next_binding: FastHashMap::default(),
         }
     }
@@ -117,6 +130,28 @@ impl DescRemapData {
         &*val
     }
 
+    /// Get the texture unit of a separate image and sampler combined in a
+    /// shader, allocating one the first time they are combined.
+    pub fn insert_combined_binding(
+        &mut self,
+        image: (pso::DescriptorSetIndex, pso::DescriptorBinding),
+        sampler: (pso::DescriptorSetIndex, pso::DescriptorBinding),
+    ) -> pso::DescriptorBinding {
+        if let Some(&nb) = self.combined.get(&(image, sampler)) {
+            return nb;
+        }
+        let nb = self.reserve_binding(BindingTypes::Images);
+        self.insert_missing_binding(nb, BindingTypes::Images, image.0, image.1);
+        self.insert_missing_binding(nb, BindingTypes::Images, sampler.0, sampler.1);
+        self.combined.insert((image, sampler), nb);
+        nb
+    }
+
+    /// Number of flattened bindings of the given type in use.
+    pub fn binding_count(&self, btype: BindingTypes) -> pso::DescriptorBinding {
+        self.next_binding.get(&btype).cloned().unwrap_or(0)
+    }
+
     pub fn get_binding(
         &self,
         btype: BindingTypes,
@@ -132,19 +167,147 @@ pub struct GraphicsPipeline {
     pub(crate) program: Program,
     pub(crate) primitive: gl::types::GLenum,
     pub(crate) patch_size: Option<gl::types::GLint>,
+    pub(crate) primitive_restart: pso::PrimitiveRestart,
+    pub(crate) line_width: Option<f32>,
+    pub(crate) rasterizer: pso::Rasterizer,
+    pub(crate) depth_stencil: pso::DepthStencilDesc,
+    pub(crate) logic_op: Option<gl::types::GLenum>,
     pub(crate) blend_targets: Vec<pso::ColorBlendDesc>,
     pub(crate) attributes: Vec<AttributeDesc>,
     pub(crate) vertex_buffers: Vec<Option<pso::VertexBufferDesc>>,
+    pub(crate) uniforms: Vec<UniformDesc>,
 }
 
 #[derive(Clone, Debug)]
 pub struct ComputePipeline {
     pub(crate) program: Program,
+    pub(crate) uniforms: Vec<UniformDesc>,
+}
+
+/// Linked program retrieved through `glGetProgramBinary`.
+#[derive(Clone, Debug)]
+pub struct ProgramBinary {
+    pub(crate) format: gl::types::GLenum,
+    pub(crate) data: Vec<u8>,
+}
+
+/// Program binaries keyed by the GLSL sources they were linked from.
+#[derive(Debug, Default)]
+pub struct PipelineCache {
+    pub(crate) binaries: Mutex<FastHashMap<Vec<u8>, ProgramBinary>>,
+}
+
+const PIPELINE_CACHE_MAGIC: &[u8; 8] = b"GFXGLPC\0";
+const PIPELINE_CACHE_VERSION: u32 = 1;
+
+impl PipelineCache {
+    /// Load a cache blob written by `serialize`.
+    ///
+    /// Blobs from a different cache version or driver are discarded, as program
+    /// binaries are only valid for the exact implementation which produced them.
+    pub(crate) fn deserialize(data: &[u8], driver: &str) -> Self {
+        let cache = PipelineCache::default();
+        match Self::parse(data, driver) {
+            Some(binaries) => *cache.binaries.lock().unwrap() = binaries,
+            None => info!("Discarding stale or invalid pipeline cache data"),
+        }
+        cache
+    }
+
+    fn parse(data: &[u8], driver: &str) -> Option<FastHashMap<Vec<u8>, ProgramBinary>> {
+        fn read_u32(data: &mut &[u8]) -> Option<u32> {
+            let bytes = read_bytes(data, 4)?;
+            Some(
+                bytes[0] as u32
+                    | (bytes[1] as u32) << 8
+                    | (bytes[2] as u32) << 16
+                    | (bytes[3] as u32) << 24,
+            )
+        }
+        fn read_bytes<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+            if data.len() < len {
+                return None;
+            }
+            let (bytes, rest) = data.split_at(len);
+            *data = rest;
+            Some(bytes)
+        }
+        fn read_blob<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+            let len = read_u32(data)? as usize;
+            read_bytes(data, len)
+        }
+
+        let mut data = data;
+        if read_bytes(&mut data, PIPELINE_CACHE_MAGIC.len())? != PIPELINE_CACHE_MAGIC
+            || read_u32(&mut data)? != PIPELINE_CACHE_VERSION
+            || read_blob(&mut data)? != driver.as_bytes()
+        {
+            return None;
+        }
+
+        let count = read_u32(&mut data)?;
+        let mut binaries = FastHashMap::default();
+        for _ in 0..count {
+            let key = read_blob(&mut data)?.to_vec();
+            let format = read_u32(&mut data)?;
+            let binary = read_blob(&mut data)?.to_vec();
+            binaries.insert(
+                key,
+                ProgramBinary {
+                    format,
+                    data: binary,
+                },
+            );
+        }
+        Some(binaries)
+    }
+
+    /// Write the cached binaries, prefixed by a header identifying the driver.
+    pub(crate) fn serialize(&self, driver: &str) -> Vec<u8> {
+        fn write_u32(data: &mut Vec<u8>, value: u32) {
+            data.extend_from_slice(&[
+                value as u8,
+                (value >> 8) as u8,
+                (value >> 16) as u8,
+                (value >> 24) as u8,
+            ]);
+        }
+        fn write_blob(data: &mut Vec<u8>, blob: &[u8]) {
+            write_u32(data, blob.len() as u32);
+            data.extend_from_slice(blob);
+        }
+
+        let binaries = self.binaries.lock().unwrap();
+        let mut data = Vec::new();
+        data.extend_from_slice(PIPELINE_CACHE_MAGIC);
+        write_u32(&mut data, PIPELINE_CACHE_VERSION);
+        write_blob(&mut data, driver.as_bytes());
+        write_u32(&mut data, binaries.len() as u32);
+        for (key, binary) in binaries.iter() {
+            write_blob(&mut data, key);
+            write_u32(&mut data, binary.format);
+            write_blob(&mut data, &binary.data);
+        }
+        data
+    }
+}
+
+/// Uniform emulating a member of a push constant block.
+#[derive(Clone, Debug)]
+pub struct UniformDesc {
+    pub(crate) location: gl::types::GLint,
+    pub(crate) utype: gl::types::GLenum,
+    /// Byte range covered in the push constant data.
+    pub(crate) offset: u32,
+    pub(crate) size: u32,
+    /// Stages accessing the uniform.
+    pub(crate) stages: pso::ShaderStageFlags,
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct Image {
     pub(crate) kind: ImageKind,
+    pub(crate) format: format::Format,
     // Required for clearing operations
     pub(crate) channel: format::ChannelType,
     pub(crate) requirements: Requirements,
@@ -169,6 +332,9 @@ pub enum ImageView {
     Surface(Surface),
     Texture(Texture, i::Level),
     TextureLayer(Texture, i::Level, i::Layer),
+    /// Texture created with `glTextureView`, owned by the image view, along
+    /// with the target of its view kind.
+    TextureView(Texture, TextureTarget),
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
@@ -180,14 +346,21 @@ pub(crate) enum DescSetBindings {
         offset: gl::types::GLintptr,
         size: gl::types::GLsizeiptr,
     },
-    Texture(pso::DescriptorBinding, Texture),
+    Texture(pso::DescriptorBinding, Texture, TextureTarget),
+    StorageImage {
+        binding: pso::DescriptorBinding,
+        texture: Texture,
+        level: i::Level,
+        layer: Option<i::Layer>,
+        format: gl::types::GLenum,
+    },
     Sampler(pso::DescriptorBinding, Sampler),
     SamplerInfo(pso::DescriptorBinding, i::SamplerInfo),
 }
 
 #[derive(Clone, Debug)]
 pub struct DescriptorSet {
-    layout: DescriptorSetLayout,
+    pub(crate) layout: DescriptorSetLayout,
     pub(crate) bindings: Arc<Mutex<Vec<DescSetBindings>>>,
 }
 
@@ -217,10 +390,29 @@ impl pso::DescriptorPool<Backend> for DescriptorPool {
     }
 }
 
-#[derive(Clone, Debug, Hash)]
+#[derive(Debug)]
 pub enum ShaderModule {
     Raw(Shader),
-    Spirv(Vec<u8>),
+    Spirv {
+        data: Vec<u8>,
+        /// GLSL generated from the module so far.
+        translations: Mutex<Vec<ShaderTranslation>>,
+    },
+}
+
+/// GLSL translation of a SPIR-V module together with the reflection gathered on the way.
+///
+/// Only reused for the same stage, specialization and descriptor remapping, as
+/// translating also reserves bindings for combined image samplers.
+#[derive(Debug)]
+pub struct ShaderTranslation {
+    pub(crate) stage: pso::Stage,
+    pub(crate) specialization: Vec<(u32, Vec<u8>)>,
+    pub(crate) remap_before: DescRemapData,
+    pub(crate) remap_after: DescRemapData,
+    pub(crate) name_bindings: FastHashMap<String, (BindingTypes, pso::DescriptorBinding)>,
+    pub(crate) push_constants: FastHashMap<String, (u32, pso::ShaderStageFlags)>,
+    pub(crate) glsl: String,
 }
 
 #[derive(Debug)]
@@ -229,6 +421,12 @@ pub struct Memory {
     pub(crate) first_bound_buffer: Cell<RawBuffer>,
     /// Allocation size
     pub(crate) size: u64,
+    /// Size of the storage allocated for the first bound buffer, if it can
+    /// be mapped persistently.
+    pub(crate) persistent_size: Cell<Option<u64>>,
+    /// Persistent mapping of the first bound buffer, kept across `map_memory`
+    /// calls.
+    pub(crate) persistent_ptr: Cell<*mut u8>,
 }
 
 unsafe impl Send for Memory {}
@@ -262,15 +460,29 @@ pub struct RenderPass {
     pub(crate) subpasses: Vec<SubpassDesc>,
 }
 
+impl RenderPass {
+    /// Check if command buffers recorded for `other` can be used in this render pass.
+    pub(crate) fn is_compatible(&self, other: &RenderPass) -> bool {
+        self.subpasses.len() == other.subpasses.len()
+            && self.attachments.len() == other.attachments.len()
+            && self
+                .attachments
+                .iter()
+                .zip(&other.attachments)
+                .all(|(a, b)| a.format == b.format && a.samples == b.samples)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SubpassDesc {
     pub(crate) color_attachments: Vec<usize>,
+    pub(crate) depth_stencil: Option<usize>,
 }
 
 impl SubpassDesc {
     /// Check if an attachment is used by this sub-pass.
     pub(crate) fn is_using(&self, at_id: pass::AttachmentId) -> bool {
-        self.color_attachments.iter().any(|id| *id == at_id)
+        self.color_attachments.iter().any(|id| *id == at_id) || self.depth_stencil == Some(at_id)
     }
 }
 
@@ -279,11 +491,27 @@ pub struct PipelineLayout {
     pub(crate) desc_remap_data: Arc<RwLock<DescRemapData>>,
 }
 
+/// Semaphores are backed by GL sync objects, which are waited on the server
+/// side with `glWaitSync`.
 #[derive(Debug)]
-// No inter-queue synchronization required for GL.
-pub struct Semaphore;
+pub struct Semaphore(pub(crate) Cell<gl::types::GLsync>);
+unsafe impl Send for Semaphore {}
+unsafe impl Sync for Semaphore {}
 
-#[derive(Clone, Debug)]
+impl Semaphore {
+    pub(crate) fn new(sync: gl::types::GLsync) -> Self {
+        Semaphore(Cell::new(sync))
+    }
+}
+
+/// A pool of GL query objects, all sharing the same target.
+#[derive(Debug)]
+pub struct QueryPool {
+    pub(crate) target: gl::types::GLenum,
+    pub(crate) queries: Vec<gl::types::GLuint>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct AttributeDesc {
     pub(crate) location: gl::types::GLuint,
     pub(crate) offset: u32,
@@ -293,9 +521,18 @@ pub struct AttributeDesc {
     pub(crate) vertex_attrib_fn: VertexAttribFunction,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum VertexAttribFunction {
     Float,   // glVertexAttribPointer
     Integer, // glVertexAttribIPointer
     Double,  // glVertexAttribLPointer
 }
+
+/// Vertex attribute sourced from a vertex buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VertexAttribute {
+    pub(crate) desc: AttributeDesc,
+    pub(crate) buffer: gl::types::GLuint,
+    pub(crate) stride: gl::types::GLsizei,
+    pub(crate) rate: gl::types::GLuint,
+}
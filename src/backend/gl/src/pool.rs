@@ -53,6 +53,7 @@ pub enum BufferMemory {
 
 pub struct RawCommandPool {
     pub(crate) fbo: Option<n::FrameBuffer>,
+    pub(crate) blit_fbo: Option<n::FrameBuffer>,
     pub(crate) limits: command::Limits,
     pub(crate) memory: Arc<Mutex<BufferMemory>>,
 }
@@ -79,8 +80,8 @@ impl pool::RawCommandPool<Backend> for RawCommandPool {
     }
 
     fn allocate_one(&mut self, _level: hal::command::RawLevel) -> RawCommandBuffer {
-        // TODO: Implement secondary buffers
-        RawCommandBuffer::new(self.fbo, self.limits, self.memory.clone())
+        // Secondary buffers are recorded the same way and replayed by `execute_commands`.
+        RawCommandBuffer::new(self.fbo, self.blit_fbo, self.limits, self.memory.clone())
     }
 
     unsafe fn free<I>(&mut self, buffers: I)
@@ -1,19 +1,107 @@
 use std::borrow::Borrow;
 use std::{mem, ptr, slice};
 use crate::Starc;
+use std::sync::{Arc, Mutex, Once};
 
 use crate::hal;
-use crate::hal::error;
+use crate::hal::backend::FastHashMap;
+use crate::hal::{buffer, error, pso};
 
 use crate::gl;
 use smallvec::SmallVec;
 
 use crate::info::LegacyFeatures;
-use crate::{command as com, device, native, state, window};
-use crate::{Backend, Share};
+use crate::pool::BufferMemory;
+use crate::{command as com, conv, device, native, state, window};
+use crate::{Backend, GlContainer, Share};
 
 pub type ArrayBuffer = gl::types::GLuint;
 
+/// Maximum number of vertex array objects kept by the cache.
+const MAX_VERTEX_ARRAYS: usize = 64;
+
+/// `GL_PARAMETER_BUFFER`, missing from the generated bindings.
+const PARAMETER_BUFFER: gl::types::GLenum = 0x80EE;
+
+/// Memory of the command pool being replayed, locked for the whole submission.
+#[derive(Clone, Copy)]
+struct PoolMemory<'a> {
+    pool: &'a Arc<Mutex<BufferMemory>>,
+    memory: &'a BufferMemory,
+}
+
+/// Vertex array objects keyed by the vertex attributes they have been set
+/// up with. The least recently used ones are evicted once the cache is full.
+///
+/// Without separate attribute formats and bindings (`GL_ARB_vertex_attrib_binding`)
+/// the vertex buffers are part of the attribute state, and so of the key.
+pub(crate) struct VertexArrayCache {
+    vaos: FastHashMap<Vec<native::VertexAttribute>, (ArrayBuffer, u64)>,
+    // Incremented on each lookup, used to order entries by last use.
+    clock: u64,
+}
+
+impl VertexArrayCache {
+    pub(crate) fn new() -> Self {
+        VertexArrayCache {
+            vaos: FastHashMap::default(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, attributes: &[native::VertexAttribute]) -> Option<ArrayBuffer> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.vaos.get_mut(attributes).map(|entry| {
+            entry.1 = clock;
+            entry.0
+        })
+    }
+
+    // Insert a new VAO, returning the evicted one which needs to be deleted.
+    fn insert(
+        &mut self,
+        attributes: Vec<native::VertexAttribute>,
+        vao: ArrayBuffer,
+    ) -> Option<ArrayBuffer> {
+        let evicted = if self.vaos.len() >= MAX_VERTEX_ARRAYS {
+            let oldest = self
+                .vaos
+                .iter()
+                .min_by_key(|&(_, &(_, last_use))| last_use)
+                .map(|(key, _)| key.clone());
+            oldest
+                .and_then(|key| self.vaos.remove(&key))
+                .map(|(vao, _)| vao)
+        } else {
+            None
+        };
+        self.vaos.insert(attributes, (vao, self.clock));
+        evicted
+    }
+
+    /// Delete all VAOs sourcing attributes from the given buffer, the
+    /// buffer name may be reused for a new buffer.
+    pub(crate) fn evict_buffer(&mut self, gl: &GlContainer, buffer: gl::types::GLuint) {
+        self.vaos.retain(|attributes, &mut (vao, _)| {
+            let used = attributes
+                .iter()
+                .any(|attribute| attribute.buffer == buffer);
+            if used {
+                unsafe { gl.DeleteVertexArrays(1, &vao) };
+            }
+            !used
+        });
+    }
+
+    /// Delete all cached VAOs.
+    pub(crate) fn clear(&mut self, gl: &GlContainer) {
+        for (_, (vao, _)) in self.vaos.drain() {
+            unsafe { gl.DeleteVertexArrays(1, &vao) };
+        }
+    }
+}
+
 // State caching system for command queue.
 //
 // We track the current global state, which is based on
@@ -32,6 +120,25 @@ struct State {
     num_viewports: usize,
     // Currently set scissor rects.
     num_scissors: usize,
+    // First scissor rect, saved while clears are restricted to the render area.
+    saved_scissor: Option<[i32; 4]>,
+    // Currently bound program.
+    // None denotes that we don't know what is currently bound.
+    program: Option<gl::types::GLuint>,
+    // Currently bound draw framebuffer.
+    draw_framebuffer: Option<native::FrameBuffer>,
+    // Currently bound read framebuffer.
+    read_framebuffer: Option<native::FrameBuffer>,
+    // Currently set blend color.
+    blend_color: Option<pso::ColorValue>,
+    // Currently set blend state per color slot.
+    blend_slots: Vec<Option<pso::ColorBlendDesc>>,
+    // Indicates if color writes are encoded as sRGB.
+    // None denotes that we don't know the current state.
+    framebuffer_srgb: Option<bool>,
+    // Indicates if seamless cube map filtering is enabled.
+    // None denotes that we don't know the current state.
+    seamless_cube_map: Option<bool>,
 }
 
 impl State {
@@ -43,6 +150,14 @@ impl State {
             index_buffer: None,
             num_viewports: 0,
             num_scissors: 0,
+            saved_scissor: None,
+            program: None,
+            draw_framebuffer: None,
+            read_framebuffer: None,
+            blend_color: None,
+            blend_slots: Vec::new(),
+            framebuffer_srgb: None,
+            seamless_cube_map: None,
         }
     }
 
@@ -51,6 +166,13 @@ impl State {
     fn flush(&mut self) {
         self.vao = false;
         self.index_buffer = None;
+        self.program = None;
+        self.draw_framebuffer = None;
+        self.read_framebuffer = None;
+        self.blend_color = None;
+        self.blend_slots.clear();
+        self.framebuffer_srgb = None;
+        self.seamless_cube_map = None;
 
         // TOOD: reset viewports and scissors
         //       do we need to clear everything from 0..MAX_VIEWPORTS?
@@ -60,15 +182,21 @@ impl State {
 pub struct CommandQueue {
     pub(crate) share: Starc<Share>,
     vao: ArrayBuffer,
+    vertex_arrays: Arc<Mutex<VertexArrayCache>>,
     state: State,
 }
 
 impl CommandQueue {
     /// Create a new command queue.
-    pub(crate) fn new(share: &Starc<Share>, vao: ArrayBuffer) -> Self {
+    pub(crate) fn new(
+        share: &Starc<Share>,
+        vao: ArrayBuffer,
+        vertex_arrays: Arc<Mutex<VertexArrayCache>>,
+    ) -> Self {
         CommandQueue {
             share: share.clone(),
             vao,
+            vertex_arrays,
             state: State::new(),
         }
     }
@@ -86,6 +214,17 @@ impl CommandQueue {
         self.state.flush();
     }
 
+    /// Flush all submitted commands to the GPU, without waiting for them to
+    /// complete (`glFlush`).
+    ///
+    /// Use `wait_idle` to block until all submitted commands completed
+    /// (`glFinish`), e.g. before reading back results on the host.
+    pub fn flush(&self) {
+        unsafe {
+            self.share.context.Flush();
+        }
+    }
+
     /*
     fn bind_attribute(&mut self, slot: hal::AttributeSlot, buffer: n::Buffer, bel: BufferElement) {
         use core::format::SurfaceType as S;
@@ -165,6 +304,9 @@ impl CommandQueue {
             &native::ImageView::Texture(texture, level) => unsafe {
                 gl.FramebufferTexture(point, attachment, texture, level as gl::types::GLint);
             },
+            &native::ImageView::TextureView(texture, _) => unsafe {
+                gl.FramebufferTexture(point, attachment, texture, 0);
+            },
             &native::ImageView::TextureLayer(texture, level, layer) => unsafe {
                 gl.FramebufferTextureLayer(
                     point,
@@ -196,6 +338,205 @@ impl CommandQueue {
         &data[ptr.offset as usize..(ptr.offset + ptr.size) as usize]
     }
 
+    // Set up a vertex attribute in the bound VAO.
+    fn bind_attribute(&self, attribute: &native::VertexAttribute) {
+        use crate::native::VertexAttribFunction::*;
+
+        let native::AttributeDesc {
+            location,
+            size,
+            format,
+            offset,
+            vertex_attrib_fn,
+            ..
+        } = attribute.desc;
+        let offset = offset as *const gl::types::GLvoid;
+        let stride = attribute.stride;
+        let gl = &self.share.context;
+
+        unsafe {
+            gl.BindBuffer(gl::ARRAY_BUFFER, attribute.buffer);
+
+            match vertex_attrib_fn {
+                Float => gl.VertexAttribPointer(location, size, format, gl::FALSE, stride, offset),
+                Integer => gl.VertexAttribIPointer(location, size, format, stride, offset),
+                Double => gl.VertexAttribLPointer(location, size, format, stride, offset),
+            }
+
+            if attribute.rate != 0 {
+                if self
+                    .share
+                    .legacy_features
+                    .contains(LegacyFeatures::INSTANCED_ATTRIBUTE_BINDING)
+                {
+                    gl.VertexAttribDivisor(location, attribute.rate);
+                } else {
+                    error!("Binding attribute with instanced input rate is not supported");
+                }
+            }
+
+            gl.EnableVertexAttribArray(location);
+            gl.BindBuffer(gl::ARRAY_BUFFER, 0);
+        }
+    }
+
+    /// Read the parameters of indirect draws back to the host, `words` per
+    /// draw, for drivers without indirect drawing support.
+    fn read_indirect_args(
+        &self,
+        buffer: gl::types::GLuint,
+        offset: buffer::Offset,
+        draw_count: hal::DrawCount,
+        stride: u32,
+        words: usize,
+    ) -> Vec<u32> {
+        static FALLBACK_WARNING: Once = Once::new();
+        FALLBACK_WARNING.call_once(|| {
+            warn!("Indirect drawing is not supported, reading draw parameters back to the host")
+        });
+
+        let mut args = Vec::with_capacity(draw_count as usize * words);
+        if draw_count == 0 {
+            return args;
+        }
+        // The stride is ignored for single draws.
+        let stride = if draw_count > 1 {
+            stride as usize
+        } else {
+            words * mem::size_of::<u32>()
+        };
+        let size = (draw_count as usize - 1) * stride + words * mem::size_of::<u32>();
+
+        let gl = &self.share.context;
+        unsafe {
+            gl.BindBuffer(gl::COPY_READ_BUFFER, buffer);
+            let ptr = gl.MapBufferRange(
+                gl::COPY_READ_BUFFER,
+                offset as _,
+                size as _,
+                gl::MAP_READ_BIT,
+            ) as *const u8;
+            if ptr.is_null() {
+                error!("Failed to map indirect buffer {}", buffer);
+            } else {
+                for draw in 0..draw_count as usize {
+                    let draw_ptr = ptr.add(draw * stride) as *const u32;
+                    for word in 0..words {
+                        args.push(ptr::read_unaligned(draw_ptr.add(word)));
+                    }
+                }
+                gl.UnmapBuffer(gl::COPY_READ_BUFFER);
+            }
+            gl.BindBuffer(gl::COPY_READ_BUFFER, 0);
+        }
+        args
+    }
+
+    /// Read the number of draws of an indirect draw from a buffer, for
+    /// contexts without `glMultiDrawElementsIndirectCount`.
+    fn read_draw_count(&self, buffer: gl::types::GLuint, offset: buffer::Offset) -> u32 {
+        static FALLBACK_WARNING: Once = Once::new();
+        FALLBACK_WARNING.call_once(|| {
+            warn!("Indirect draw counts are not supported, reading them back to the host")
+        });
+
+        let gl = &self.share.context;
+        let mut count = 0;
+        unsafe {
+            gl.BindBuffer(gl::COPY_READ_BUFFER, buffer);
+            let ptr = gl.MapBufferRange(
+                gl::COPY_READ_BUFFER,
+                offset as _,
+                mem::size_of::<u32>() as _,
+                gl::MAP_READ_BIT,
+            ) as *const u32;
+            if ptr.is_null() {
+                error!("Failed to map draw count buffer {}", buffer);
+            } else {
+                count = ptr::read_unaligned(ptr);
+                gl.UnmapBuffer(gl::COPY_READ_BUFFER);
+            }
+            gl.BindBuffer(gl::COPY_READ_BUFFER, 0);
+        }
+        count
+    }
+
+    /// Enable writes to all color channels, as write masks apply to clears.
+    /// The blend states carrying the masks of the pipeline have to be bound
+    /// again afterwards.
+    fn unlock_color_mask(&mut self) {
+        state::unlock_color_mask(&self.share.context);
+        self.state.blend_slots.clear();
+    }
+
+    /// Copy between buffers through the host, for contexts without `glCopyBufferSubData`.
+    fn copy_buffer_mapped(
+        &self,
+        src: gl::types::GLuint,
+        dst: gl::types::GLuint,
+        r: &hal::command::BufferCopy,
+    ) {
+        if !self.share.private_caps.map {
+            error!("Copying buffers is not supported");
+            return;
+        }
+
+        static FALLBACK_WARNING: Once = Once::new();
+        FALLBACK_WARNING.call_once(|| warn!("Copying buffers through the host"));
+
+        let gl = &self.share.context;
+        let size = r.size as usize;
+        unsafe {
+            if src == dst {
+                // A buffer can only be mapped once, map the range covering
+                // both regions, which may overlap.
+                let start = r.src.min(r.dst);
+                let end = r.src.max(r.dst) + r.size;
+                let access = gl::MAP_READ_BIT | gl::MAP_WRITE_BIT;
+                gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, src);
+                match map_buffer_range(gl, gl::PIXEL_UNPACK_BUFFER, start, end - start, access) {
+                    Some((ptr, mapped)) => {
+                        let src_ptr = ptr.offset((r.src - start) as isize);
+                        let dst_ptr = ptr.offset((r.dst - start) as isize);
+                        ptr::copy(src_ptr, dst_ptr, size);
+                        if mapped {
+                            gl.UnmapBuffer(gl::PIXEL_UNPACK_BUFFER);
+                        }
+                    }
+                    None => error!("Failed to map buffer {}", src),
+                }
+                gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, 0);
+                return;
+            }
+
+            gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, src);
+            gl.BindBuffer(gl::PIXEL_PACK_BUFFER, dst);
+            let src_map =
+                map_buffer_range(gl, gl::PIXEL_UNPACK_BUFFER, r.src, r.size, gl::MAP_READ_BIT);
+            let dst_map = map_buffer_range(
+                gl,
+                gl::PIXEL_PACK_BUFFER,
+                r.dst,
+                r.size,
+                gl::MAP_WRITE_BIT | gl::MAP_INVALIDATE_RANGE_BIT,
+            );
+            match (src_map, dst_map) {
+                (Some((src_ptr, _)), Some((dst_ptr, _))) => {
+                    ptr::copy_nonoverlapping(src_ptr, dst_ptr, size)
+                }
+                _ => error!("Failed to map buffers {} and {}", src, dst),
+            }
+            if let Some((_, true)) = src_map {
+                gl.UnmapBuffer(gl::PIXEL_UNPACK_BUFFER);
+            }
+            if let Some((_, true)) = dst_map {
+                gl.UnmapBuffer(gl::PIXEL_PACK_BUFFER);
+            }
+            gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, 0);
+            gl.BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+        }
+    }
+
     // Reset the state to match our _expected_ state before executing
     // a command buffer.
     fn reset_state(&mut self) {
@@ -205,6 +546,8 @@ impl CommandQueue {
         if !self.state.vao {
             if self.share.private_caps.vertex_array {
                 unsafe { gl.BindVertexArray(self.vao) };
+                // The index buffer binding of the default VAO is unknown.
+                self.state.index_buffer = None;
             }
             self.state.vao = true
         }
@@ -214,6 +557,7 @@ impl CommandQueue {
             .share
             .legacy_features
             .contains(LegacyFeatures::INDIRECT_EXECUTION)
+            || self.share.private_caps.draw_indirect
         {
             unsafe { gl.BindBuffer(gl::DRAW_INDIRECT_BUFFER, 0) };
         }
@@ -227,6 +571,19 @@ impl CommandQueue {
             }
         }
 
+        // Restore cube map filtering chosen at device creation
+        if self.share.private_caps.seamless_cube_map {
+            let seamless = self.share.seamless_cube_map.get();
+            if self.state.seamless_cube_map != Some(seamless) {
+                if seamless {
+                    unsafe { gl.Enable(gl::TEXTURE_CUBE_MAP_SEAMLESS) };
+                } else {
+                    unsafe { gl.Disable(gl::TEXTURE_CUBE_MAP_SEAMLESS) };
+                }
+                self.state.seamless_cube_map = Some(seamless);
+            }
+        }
+
         // Reset viewports
         if self.state.num_viewports == 1 {
             unsafe { gl.Viewport(0, 0, 0, 0) };
@@ -259,7 +616,26 @@ impl CommandQueue {
         }
     }
 
-    fn process(&mut self, cmd: &com::Command, data_buf: &[u8]) {
+    fn execute_secondary(&mut self, secondary: &com::SecondaryBuffer, memory: PoolMemory) {
+        let buffer = match *memory.memory {
+            BufferMemory::Linear(ref buffer) => buffer,
+            BufferMemory::Individual { ref storage, .. } => match storage.get(&secondary.id) {
+                Some(buffer) => buffer,
+                None => {
+                    error!("Secondary command buffer {} has been freed", secondary.id);
+                    return;
+                }
+            },
+        };
+
+        let range =
+            secondary.buf.offset as usize..(secondary.buf.offset + secondary.buf.size) as usize;
+        for com in &buffer.commands[range] {
+            self.process(com, memory, &buffer.data);
+        }
+    }
+
+    fn process(&mut self, cmd: &com::Command, memory: PoolMemory, data_buf: &[u8]) {
         match *cmd {
             com::Command::BindIndexBuffer(buffer) => {
                 let gl = &self.share.context;
@@ -276,31 +652,31 @@ impl CommandQueue {
                 let legacy = &self.share.legacy_features;
                 if instances == &(0u32..1) {
                     unsafe {
-                        gl.DrawArrays(
+                        gl_call!(gl.DrawArrays(
                             primitive,
                             vertices.start as _,
                             (vertices.end - vertices.start) as _,
-                        );
+                        ));
                     }
                 } else if legacy.contains(LegacyFeatures::DRAW_INSTANCED) {
                     if instances.start == 0 {
                         unsafe {
-                            gl.DrawArraysInstanced(
+                            gl_call!(gl.DrawArraysInstanced(
                                 primitive,
                                 vertices.start as _,
                                 (vertices.end - vertices.start) as _,
                                 instances.end as _,
-                            );
+                            ));
                         }
                     } else if legacy.contains(LegacyFeatures::DRAW_INSTANCED_BASE) {
                         unsafe {
-                            gl.DrawArraysInstancedBaseInstance(
+                            gl_call!(gl.DrawArraysInstancedBaseInstance(
                                 primitive,
                                 vertices.start as _,
                                 (vertices.end - vertices.start) as _,
                                 (instances.end - instances.start) as _,
                                 instances.start as _,
-                            );
+                            ));
                         }
                     } else {
                         error!(
@@ -326,17 +702,22 @@ impl CommandQueue {
                 if instances == &(0u32..1) {
                     if base_vertex == 0 {
                         unsafe {
-                            gl.DrawElements(primitive, index_count as _, index_type, offset);
+                            gl_call!(gl.DrawElements(
+                                primitive,
+                                index_count as _,
+                                index_type,
+                                offset
+                            ));
                         }
                     } else if legacy.contains(LegacyFeatures::DRAW_INDEXED_BASE) {
                         unsafe {
-                            gl.DrawElementsBaseVertex(
+                            gl_call!(gl.DrawElementsBaseVertex(
                                 primitive,
                                 index_count as _,
                                 index_type,
                                 offset,
                                 base_vertex as _,
-                            );
+                            ));
                         }
                     } else {
                         error!("Base vertex with indexed drawing not supported");
@@ -344,32 +725,32 @@ impl CommandQueue {
                 } else if legacy.contains(LegacyFeatures::DRAW_INDEXED_INSTANCED) {
                     if base_vertex == 0 && instances.start == 0 {
                         unsafe {
-                            gl.DrawElementsInstanced(
+                            gl_call!(gl.DrawElementsInstanced(
                                 primitive,
                                 index_count as _,
                                 index_type,
                                 offset,
                                 instances.end as _,
-                            );
+                            ));
                         }
                     } else if instances.start == 0
                         && legacy.contains(LegacyFeatures::DRAW_INDEXED_INSTANCED_BASE_VERTEX)
                     {
                         unsafe {
-                            gl.DrawElementsInstancedBaseVertex(
+                            gl_call!(gl.DrawElementsInstancedBaseVertex(
                                 primitive,
                                 index_count as _,
                                 index_type,
                                 offset,
                                 instances.end as _,
                                 base_vertex as _,
-                            );
+                            ));
                         }
                     } else if instances.start == 0 {
                         error!("Base vertex with instanced indexed drawing is not supported");
                     } else if legacy.contains(LegacyFeatures::DRAW_INDEXED_INSTANCED_BASE) {
                         unsafe {
-                            gl.DrawElementsInstancedBaseVertexBaseInstance(
+                            gl_call!(gl.DrawElementsInstancedBaseVertexBaseInstance(
                                 primitive,
                                 index_count as _,
                                 index_type,
@@ -377,7 +758,7 @@ impl CommandQueue {
                                 (instances.end - instances.start) as _,
                                 base_vertex as _,
                                 instances.start as _,
-                            );
+                            ));
                         }
                     } else {
                         error!("Instance bases with instanced indexed drawing is not supported");
@@ -386,12 +767,154 @@ impl CommandQueue {
                     error!("Instanced indexed drawing is not supported");
                 }
             }
+            com::Command::DrawIndirect {
+                primitive,
+                buffer,
+                offset,
+                draw_count,
+                stride,
+            } => {
+                let caps = &self.share.private_caps;
+                if caps.draw_indirect {
+                    let gl = &self.share.context;
+                    unsafe { gl.BindBuffer(gl::DRAW_INDIRECT_BUFFER, buffer) };
+                    if caps.multi_draw_indirect {
+                        unsafe {
+                            gl_call!(gl.MultiDrawArraysIndirect(
+                                primitive,
+                                offset as *const gl::types::GLvoid,
+                                draw_count as _,
+                                stride as _,
+                            ))
+                        };
+                    } else {
+                        for draw in 0..draw_count as buffer::Offset {
+                            let draw_offset = offset + draw * stride as buffer::Offset;
+                            unsafe {
+                                gl_call!(gl.DrawArraysIndirect(
+                                    primitive,
+                                    draw_offset as *const gl::types::GLvoid,
+                                ))
+                            };
+                        }
+                    }
+                } else {
+                    // DrawArraysIndirectCommand:
+                    // count, instance count, first vertex, first instance
+                    let args = self.read_indirect_args(buffer, offset, draw_count, stride, 4);
+                    for draw in args.chunks(4) {
+                        let cmd = com::Command::Draw {
+                            primitive,
+                            vertices: draw[2]..draw[2] + draw[0],
+                            instances: draw[3]..draw[3] + draw[1],
+                        };
+                        self.process(&cmd, memory, data_buf);
+                    }
+                }
+            }
+            com::Command::DrawIndexedIndirect {
+                primitive,
+                index_type,
+                buffer,
+                offset,
+                draw_count,
+                stride,
+            } => {
+                let caps = &self.share.private_caps;
+                if caps.draw_indirect {
+                    let gl = &self.share.context;
+                    unsafe { gl.BindBuffer(gl::DRAW_INDIRECT_BUFFER, buffer) };
+                    if caps.multi_draw_indirect {
+                        unsafe {
+                            gl_call!(gl.MultiDrawElementsIndirect(
+                                primitive,
+                                index_type,
+                                offset as *const gl::types::GLvoid,
+                                draw_count as _,
+                                stride as _,
+                            ))
+                        };
+                    } else {
+                        for draw in 0..draw_count as buffer::Offset {
+                            let draw_offset = offset + draw * stride as buffer::Offset;
+                            unsafe {
+                                gl_call!(gl.DrawElementsIndirect(
+                                    primitive,
+                                    index_type,
+                                    draw_offset as *const gl::types::GLvoid,
+                                ))
+                            };
+                        }
+                    }
+                } else {
+                    // DrawElementsIndirectCommand:
+                    // count, instance count, first index, base vertex, first instance
+                    let index_size = match index_type {
+                        gl::UNSIGNED_SHORT => 2,
+                        _ => 4,
+                    };
+                    let args = self.read_indirect_args(buffer, offset, draw_count, stride, 5);
+                    for draw in args.chunks(5) {
+                        let cmd = com::Command::DrawIndexed {
+                            primitive,
+                            index_type,
+                            index_count: draw[0],
+                            index_buffer_offset: draw[2] as buffer::Offset * index_size,
+                            base_vertex: draw[3] as hal::VertexOffset,
+                            instances: draw[4]..draw[4] + draw[1],
+                        };
+                        self.process(&cmd, memory, data_buf);
+                    }
+                }
+            }
+            com::Command::DrawIndexedIndirectCount {
+                primitive,
+                index_type,
+                buffer,
+                offset,
+                count_buffer,
+                count_offset,
+                max_draw_count,
+                stride,
+            } => {
+                let gl = &self.share.context;
+                match gl.multi_draw_elements_indirect_count {
+                    Some(multi_draw) if self.share.private_caps.indirect_parameters => unsafe {
+                        gl.BindBuffer(gl::DRAW_INDIRECT_BUFFER, buffer);
+                        gl.BindBuffer(PARAMETER_BUFFER, count_buffer);
+                        multi_draw(
+                            primitive,
+                            index_type,
+                            offset as *const gl::types::GLvoid,
+                            count_offset as _,
+                            max_draw_count as _,
+                            stride as _,
+                        );
+                        // Loaded manually, so `gl_call!` can't wrap it.
+                        #[cfg(feature = "check-errors")]
+                        crate::log_errors(gl, &"glMultiDrawElementsIndirectCount");
+                        gl.BindBuffer(PARAMETER_BUFFER, 0);
+                    },
+                    _ => {
+                        let draw_count = self.read_draw_count(count_buffer, count_offset);
+                        let cmd = com::Command::DrawIndexedIndirect {
+                            primitive,
+                            index_type,
+                            buffer,
+                            offset,
+                            draw_count: draw_count.min(max_draw_count),
+                            stride,
+                        };
+                        self.process(&cmd, memory, data_buf);
+                    }
+                }
+            }
             com::Command::Dispatch(count) => {
                 // Capability support is given by which queue types will be exposed.
                 // If there is no compute support, this pattern should never be reached
                 // because no queue with compute capability can be created.
                 let gl = &self.share.context;
-                unsafe { gl.DispatchCompute(count[0], count[1], count[2]) };
+                unsafe { gl_call!(gl.DispatchCompute(count[0], count[1], count[2])) };
             }
             com::Command::DispatchIndirect(buffer, offset) => {
                 // Capability support is given by which queue types will be exposed.
@@ -399,9 +922,17 @@ impl CommandQueue {
                 // because no queue with compute capability can be created.
                 let gl = &self.share.context;
                 unsafe {
-                    gl.BindBuffer(gl::DRAW_INDIRECT_BUFFER, buffer);
+                    gl.BindBuffer(gl::DISPATCH_INDIRECT_BUFFER, buffer);
                     // TODO: possible integer conversion issue
-                    gl.DispatchComputeIndirect(offset as _);
+                    gl_call!(gl.DispatchComputeIndirect(offset as _));
+                    gl.BindBuffer(gl::DISPATCH_INDIRECT_BUFFER, 0);
+                }
+            }
+            com::Command::MemoryBarrier(barriers) => {
+                let gl = &self.share.context;
+                // Unavailable before GL 4.2, which has no incoherent shader writes.
+                if gl.MemoryBarrier.is_loaded() {
+                    unsafe { gl.MemoryBarrier(barriers) };
                 }
             }
             com::Command::SetViewports {
@@ -415,9 +946,13 @@ impl CommandQueue {
 
                 let num_viewports = viewports.len();
                 assert_eq!(num_viewports, depth_ranges.len());
-                assert!(0 < num_viewports && num_viewports <= self.share.limits.max_viewports);
+                assert!(
+                    0 < num_viewports
+                        && first_viewport as usize + num_viewports
+                            <= self.share.limits.max_viewports
+                );
 
-                if num_viewports == 1 {
+                if num_viewports == 1 && first_viewport == 0 {
                     let view = viewports[0];
                     let depth_range = depth_ranges[0];
                     unsafe {
@@ -429,9 +964,10 @@ impl CommandQueue {
                         )
                     };
                     unsafe { gl.DepthRange(depth_range[0], depth_range[1]) };
-                } else if num_viewports > 1 {
+                } else {
                     // Support for these functions is coupled with the support
-                    // of multiple viewports.
+                    // of multiple viewports, only the first viewport can be
+                    // set during recording otherwise.
                     unsafe {
                         gl.ViewportArrayv(
                             first_viewport,
@@ -447,19 +983,28 @@ impl CommandQueue {
                         )
                     };
                 }
+
+                self.state.num_viewports = self
+                    .state
+                    .num_viewports
+                    .max(first_viewport as usize + num_viewports);
             }
             com::Command::SetScissors(first_scissor, data_ptr) => {
                 let gl = &self.share.context;
                 let scissors = Self::get::<[i32; 4]>(data_buf, data_ptr);
                 let num_scissors = scissors.len();
-                assert!(0 < num_scissors && num_scissors <= self.share.limits.max_viewports);
+                assert!(
+                    0 < num_scissors
+                        && first_scissor as usize + num_scissors <= self.share.limits.max_viewports
+                );
 
-                if num_scissors == 1 {
+                if num_scissors == 1 && first_scissor == 0 {
                     let scissor = scissors[0];
                     unsafe { gl.Scissor(scissor[0], scissor[1], scissor[2], scissor[3]) };
                 } else {
                     // Support for this function is coupled with the support
-                    // of multiple viewports.
+                    // of multiple viewports, only the first scissor can be
+                    // set during recording otherwise.
                     unsafe {
                         gl.ScissorArrayv(
                             first_scissor,
@@ -468,21 +1013,59 @@ impl CommandQueue {
                         )
                     };
                 }
+
+                self.state.num_scissors = self
+                    .state
+                    .num_scissors
+                    .max(first_scissor as usize + num_scissors);
             }
             com::Command::SetBlendColor(color) => {
-                state::set_blend_color(&self.share.context, color);
+                if self.state.blend_color != Some(color) {
+                    self.state.blend_color = Some(color);
+                    state::set_blend_color(&self.share.context, color);
+                }
             }
+            com::Command::SetClearArea(area) => unsafe {
+                let gl = &self.share.context;
+                // Only the first scissor rect applies to clears, keep the others.
+                let indexed = self.state.num_scissors > 1;
+                let set_scissor = |rect: [i32; 4]| {
+                    if indexed {
+                        gl.ScissorIndexedv(0, rect.as_ptr());
+                    } else {
+                        gl.Scissor(rect[0], rect[1], rect[2], rect[3]);
+                    }
+                };
+                match area {
+                    Some(area) => {
+                        let mut scissor = [0; 4];
+                        gl.GetIntegerv(gl::SCISSOR_BOX, scissor.as_mut_ptr());
+                        self.state.saved_scissor = Some(scissor);
+                        gl.Enable(gl::SCISSOR_TEST);
+                        set_scissor(area);
+                    }
+                    None => {
+                        gl.Disable(gl::SCISSOR_TEST);
+                        if let Some(scissor) = self.state.saved_scissor.take() {
+                            set_scissor(scissor);
+                        }
+                    }
+                }
+            },
             com::Command::ClearBufferColorF(draw_buffer, cv) => unsafe {
+                self.unlock_color_mask();
                 self.share
                     .context
                     .ClearBufferfv(gl::COLOR, draw_buffer, cv.as_ptr());
             },
             com::Command::ClearBufferColorU(draw_buffer, cv) => unsafe {
+                self.unlock_color_mask();
                 self.share
                     .context
                     .ClearBufferuiv(gl::COLOR, draw_buffer, cv.as_ptr());
             },
             com::Command::ClearBufferColorI(draw_buffer, cv) => unsafe {
+                self.unlock_color_mask();
                 self.share
                     .context
                     .ClearBufferiv(gl::COLOR, draw_buffer, cv.as_ptr());
@@ -495,9 +1078,11 @@ impl CommandQueue {
                     _ => unreachable!(),
                 };
 
-                self.share
-                    .context
-                    .ClearBufferfi(target, 0, depth, stencil as _);
+                // Write masks apply to clears as well.
+                let gl = &self.share.context;
+                gl.DepthMask(gl::TRUE);
+                gl.StencilMask(!0);
+                gl.ClearBufferfi(target, 0, depth, stencil as _);
             },
             com::Command::ClearTexture(_color) => unimplemented!(),
             com::Command::DrawBuffers(draw_buffers) => unsafe {
@@ -506,10 +1091,64 @@ impl CommandQueue {
                     .context
                     .DrawBuffers(draw_buffers.len() as _, draw_buffers.as_ptr());
             },
+            com::Command::InvalidateFramebuffer(point, attachments, area) => unsafe {
+                let attachments = Self::get::<gl::types::GLenum>(data_buf, attachments);
+                match area {
+                    Some([x, y, w, h]) => self.share.context.InvalidateSubFramebuffer(
+                        point,
+                        attachments.len() as _,
+                        attachments.as_ptr(),
+                        x,
+                        y,
+                        w,
+                        h,
+                    ),
+                    None => self.share.context.InvalidateFramebuffer(
+                        point,
+                        attachments.len() as _,
+                        attachments.as_ptr(),
+                    ),
+                }
+            },
+            com::Command::BlitFramebuffer {
+                ref src,
+                ref dst,
+                mask,
+                filter,
+            } => unsafe {
+                self.share.context.BlitFramebuffer(
+                    src.start.x,
+                    src.start.y,
+                    src.end.x,
+                    src.end.y,
+                    dst.start.x,
+                    dst.start.y,
+                    dst.end.x,
+                    dst.end.y,
+                    mask,
+                    filter,
+                );
+            },
             com::Command::BindFrameBuffer(point, frame_buffer) => {
                 if self.share.private_caps.framebuffer {
-                    let gl = &self.share.context;
-                    unsafe { gl.BindFramebuffer(point, frame_buffer) };
+                    let (draw, read) = match point {
+                        gl::DRAW_FRAMEBUFFER => (true, false),
+                        gl::READ_FRAMEBUFFER => (false, true),
+                        _ => (true, true),
+                    };
+                    let bound = Some(frame_buffer);
+                    if (draw && self.state.draw_framebuffer != bound)
+                        || (read && self.state.read_framebuffer != bound)
+                    {
+                        let gl = &self.share.context;
+                        unsafe { gl.BindFramebuffer(point, frame_buffer) };
+                        if draw {
+                            self.state.draw_framebuffer = bound;
+                        }
+                        if read {
+                            self.state.read_framebuffer = bound;
+                        }
+                    }
                 } else if frame_buffer != 0 {
                     error!("Tried to bind FBO {} without FBO support!", frame_buffer);
                 }
@@ -520,149 +1159,430 @@ impl CommandQueue {
             com::Command::SetDrawColorBuffers(num) => {
                 state::bind_draw_color_buffers(&self.share.context, num);
             }
+            com::Command::SetLineWidth(width) => unsafe {
+                self.share.context.LineWidth(width);
+            },
+            com::Command::SetFramebufferSrgb(srgb) => {
+                if self.state.framebuffer_srgb != Some(srgb) {
+                    let gl = &self.share.context;
+                    if srgb {
+                        unsafe { gl.Enable(gl::FRAMEBUFFER_SRGB) };
+                    } else {
+                        unsafe { gl.Disable(gl::FRAMEBUFFER_SRGB) };
+                    }
+                    self.state.framebuffer_srgb = Some(srgb);
+                }
+            }
             com::Command::SetPatchSize(num) => unsafe {
                 self.share.context.PatchParameteri(gl::PATCH_VERTICES, num);
             },
-            com::Command::BindProgram(program) => unsafe {
-                self.share.context.UseProgram(program);
+            com::Command::SetLogicOp(op) => unsafe {
+                let gl = &self.share.context;
+                match op {
+                    Some(op) => {
+                        gl.Enable(gl::COLOR_LOGIC_OP);
+                        gl.LogicOp(op);
+                    }
+                    None => gl.Disable(gl::COLOR_LOGIC_OP),
+                }
             },
-            com::Command::BindBlendSlot(slot, ref blend) => {
-                state::bind_blend_slot(&self.share.context, slot, blend);
-            }
-            com::Command::BindAttribute(ref attribute, handle, stride, rate) => unsafe {
-                use crate::native::VertexAttribFunction::*;
-
-                let &native::AttributeDesc {
-                    location,
-                    size,
-                    format,
-                    offset,
-                    vertex_attrib_fn,
-                    ..
-                } = attribute;
-                let offset = offset as *const gl::types::GLvoid;
+            com::Command::SetPrimitiveRestart(restart) => unsafe {
                 let gl = &self.share.context;
-
-                gl.BindBuffer(gl::ARRAY_BUFFER, handle);
-
-                match vertex_attrib_fn {
-                    Float => {
-                        gl.VertexAttribPointer(location, size, format, gl::FALSE, stride, offset)
+                // The fixed index is the maximum value of the bound index type,
+                // matching the restart value requested by the pipeline.
+                let fixed_index = self.share.private_caps.primitive_restart_fixed_index;
+                let index = match restart {
+                    pso::PrimitiveRestart::Disabled => None,
+                    pso::PrimitiveRestart::U16 => Some(0xFFFF),
+                    pso::PrimitiveRestart::U32 => Some(0xFFFF_FFFF),
+                };
+                match index {
+                    Some(_) if fixed_index => gl.Enable(gl::PRIMITIVE_RESTART_FIXED_INDEX),
+                    Some(index) => {
+                        gl.Enable(gl::PRIMITIVE_RESTART);
+                        gl.PrimitiveRestartIndex(index);
+                    }
+                    None if fixed_index => gl.Disable(gl::PRIMITIVE_RESTART_FIXED_INDEX),
+                    None if self.share.private_caps.primitive_restart => {
+                        gl.Disable(gl::PRIMITIVE_RESTART)
                     }
-                    Integer => gl.VertexAttribIPointer(location, size, format, stride, offset),
-                    Double => gl.VertexAttribLPointer(location, size, format, stride, offset),
+                    None => (),
                 }
-
-                if rate != 0 {
+            },
+            com::Command::SetRasterizer(ref rasterizer) => {
+                let is_embedded = self.share.info.version.is_embedded;
+                let depth_clamp = self.share.features.contains(hal::Features::DEPTH_CLAMP);
+                state::bind_rasterizer(&self.share.context, rasterizer, is_embedded, depth_clamp);
+            }
+            com::Command::SetDepthState(depth) => {
+                state::bind_depth(&self.share.context, &depth);
+            }
+            com::Command::SetStencilState(stencil) => {
+                state::bind_stencil(&self.share.context, &stencil);
+            }
+            com::Command::SetDepthBias(bias) => unsafe {
+                self.share
+                    .context
+                    .PolygonOffset(bias.slope_factor, bias.const_factor);
+            },
+            com::Command::BindProgram(program) => {
+                if self.state.program != Some(program) {
+                    self.state.program = Some(program);
+                    unsafe { self.share.context.UseProgram(program) };
+                }
+            }
+            com::Command::BindBlendSlot(slot, ref blend) => {
+                let slot_index = slot as usize;
+                if self.state.blend_slots.len() <= slot_index {
+                    self.state.blend_slots.resize(slot_index + 1, None);
+                }
+                if self.state.blend_slots[slot_index] != Some(*blend) {
+                    self.state.blend_slots[slot_index] = Some(*blend);
                     if self
                         .share
-                        .legacy_features
-                        .contains(LegacyFeatures::INSTANCED_ATTRIBUTE_BINDING)
+                        .features
+                        .contains(hal::Features::INDEPENDENT_BLENDING)
                     {
-                        gl.VertexAttribDivisor(location, rate);
-                    } else {
-                        error!("Binding attribute with instanced input rate is not supported");
+                        state::bind_blend_slot(&self.share.context, slot, blend);
+                    } else if slot == 0 {
+                        // Applies to all attachments, which were validated
+                        // to share the same state on pipeline creation.
+                        state::bind_blend(&self.share.context, blend);
                     }
                 }
+            }
+            com::Command::BindVertexInput(attributes) => {
+                let attributes = Self::get::<native::VertexAttribute>(data_buf, attributes);
+                if self.share.private_caps.vertex_array {
+                    let gl = &self.share.context;
+                    let mut cache = self.vertex_arrays.lock().unwrap();
+                    match cache.get(attributes) {
+                        Some(vao) => unsafe { gl.BindVertexArray(vao) },
+                        None => {
+                            let mut vao = 0;
+                            unsafe {
+                                gl.GenVertexArrays(1, &mut vao);
+                                gl.BindVertexArray(vao);
+                            }
+                            for attribute in attributes {
+                                self.bind_attribute(attribute);
+                            }
+                            if let Some(evicted) = cache.insert(attributes.to_vec(), vao) {
+                                unsafe { gl.DeleteVertexArrays(1, &evicted) };
+                            }
+                        }
+                    }
+                    drop(cache);
 
-                gl.EnableVertexAttribArray(location);
-                gl.BindBuffer(gl::ARRAY_BUFFER, 0);
-            },
+                    // The element array buffer binding is part of the VAO state.
+                    if let Some(buffer) = self.state.index_buffer {
+                        unsafe { gl.BindBuffer(gl::ELEMENT_ARRAY_BUFFER, buffer) };
+                    }
+                    self.state.vao = false;
+                } else {
+                    for attribute in attributes {
+                        self.bind_attribute(attribute);
+                    }
+                }
+            }
             /*
             com::Command::UnbindAttribute(ref attribute) => unsafe {
                 self.share.context.DisableVertexAttribArray(attribute.location);
             }*/
-            com::Command::CopyBufferToBuffer(src, dst, ref r) => unsafe {
+            com::Command::CopyBufferToBuffer(src, dst, ref r) => {
+                if self
+                    .share
+                    .legacy_features
+                    .contains(LegacyFeatures::COPY_BUFFER)
+                {
+                    let gl = &self.share.context;
+                    unsafe {
+                        gl.BindBuffer(gl::COPY_READ_BUFFER, src);
+                        gl.BindBuffer(gl::COPY_WRITE_BUFFER, dst);
+                        gl.CopyBufferSubData(
+                            gl::COPY_READ_BUFFER,
+                            gl::COPY_WRITE_BUFFER,
+                            r.src as _,
+                            r.dst as _,
+                            r.size as _,
+                        );
+                        gl.BindBuffer(gl::COPY_READ_BUFFER, 0);
+                        gl.BindBuffer(gl::COPY_WRITE_BUFFER, 0);
+                    }
+                } else {
+                    self.copy_buffer_mapped(src, dst, r);
+                }
+            }
+            com::Command::CopyBufferToTexture(buffer, texture, format, pixel_type, ref r) => unsafe {
+                // TODO: Fix active texture
+                assert_eq!(r.image_offset.z, 0);
                 let gl = &self.share.context;
-                gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, src);
-                gl.BindBuffer(gl::PIXEL_PACK_BUFFER, dst);
-                gl.CopyBufferSubData(
-                    gl::PIXEL_UNPACK_BUFFER,
-                    gl::PIXEL_PACK_BUFFER,
-                    r.src as _,
-                    r.dst as _,
-                    r.size as _,
+                gl.ActiveTexture(gl::TEXTURE0);
+                gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, buffer);
+                gl.BindTexture(gl::TEXTURE_2D, texture);
+                // Rows are tightly packed, which doesn't have to be a multiple of 4 bytes.
+                gl.PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+                gl.PixelStorei(gl::UNPACK_ROW_LENGTH, r.buffer_width as _);
+                gl.TexSubImage2D(
+                    gl::TEXTURE_2D,
+                    r.image_layers.level as _,
+                    r.image_offset.x,
+                    r.image_offset.y,
+                    r.image_extent.width as _,
+                    r.image_extent.height as _,
+                    format,
+                    pixel_type,
+                    r.buffer_offset as *const _,
                 );
+                gl.PixelStorei(gl::UNPACK_ROW_LENGTH, 0);
+                gl.PixelStorei(gl::UNPACK_ALIGNMENT, 4);
                 gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, 0);
-                gl.BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
             },
-            com::Command::CopyBufferToTexture(buffer, texture, ref r) => unsafe {
-                // TODO: Fix format and active texture
+            com::Command::CopyBufferToCompressedTexture(buffer, texture, internal, size, ref r) => unsafe {
                 assert_eq!(r.image_offset.z, 0);
                 let gl = &self.share.context;
                 gl.ActiveTexture(gl::TEXTURE0);
                 gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, buffer);
                 gl.BindTexture(gl::TEXTURE_2D, texture);
-                gl.TexSubImage2D(
+                gl.CompressedTexSubImage2D(
                     gl::TEXTURE_2D,
                     r.image_layers.level as _,
                     r.image_offset.x,
                     r.image_offset.y,
                     r.image_extent.width as _,
                     r.image_extent.height as _,
-                    gl::RGBA,
-                    gl::UNSIGNED_BYTE,
-                    ptr::null(),
+                    internal,
+                    size as _,
+                    r.buffer_offset as *const _,
                 );
                 gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, 0);
             },
             com::Command::CopyBufferToSurface(..) => {
                 unimplemented!() //TODO: use FBO
             }
-            com::Command::CopyTextureToBuffer(texture, buffer, ref r) => unsafe {
-                // TODO: Fix format and active texture
-                // TODO: handle partial copies gracefully
+            com::Command::CopyTextureToBuffer(texture, buffer, format, pixel_type, ref r) => unsafe {
+                // TODO: Fix active texture
+                // Only used without framebuffers, which can't read back partial images.
                 assert_eq!(r.image_offset, hal::image::Offset { x: 0, y: 0, z: 0 });
                 let gl = &self.share.context;
                 gl.ActiveTexture(gl::TEXTURE0);
                 gl.BindBuffer(gl::PIXEL_PACK_BUFFER, buffer);
                 gl.BindTexture(gl::TEXTURE_2D, texture);
+                gl.PixelStorei(gl::PACK_ALIGNMENT, 1);
                 gl.GetTexImage(
                     gl::TEXTURE_2D,
                     r.image_layers.level as _,
-                    //r.image_offset.x, r.image_offset.y,
-                    //r.image_extent.width as _, r.image_extent.height as _,
-                    gl::RGBA,
-                    gl::UNSIGNED_BYTE,
-                    ptr::null_mut(),
+                    format,
+                    pixel_type,
+                    r.buffer_offset as *mut _,
                 );
+                gl.PixelStorei(gl::PACK_ALIGNMENT, 4);
+                gl.BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+            },
+            com::Command::CopyFramebufferToBuffer(buffer, format, pixel_type, ref r) => unsafe {
+                // The first row is the top one, matching HAL.
+                let gl = &self.share.context;
+                gl.BindBuffer(gl::PIXEL_PACK_BUFFER, buffer);
+                // Rows are tightly packed, which doesn't have to be a multiple of 4 bytes.
+                gl.PixelStorei(gl::PACK_ALIGNMENT, 1);
+                gl.PixelStorei(gl::PACK_ROW_LENGTH, r.buffer_width as _);
+                gl.ReadPixels(
+                    r.image_offset.x,
+                    r.image_offset.y,
+                    r.image_extent.width as _,
+                    r.image_extent.height as _,
+                    format,
+                    pixel_type,
+                    r.buffer_offset as *mut _,
+                );
+                gl.PixelStorei(gl::PACK_ROW_LENGTH, 0);
+                gl.PixelStorei(gl::PACK_ALIGNMENT, 4);
                 gl.BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
             },
-            com::Command::CopySurfaceToBuffer(..) => {
-                unimplemented!() //TODO: use FBO
-            }
             com::Command::CopyImageToTexture(..) => {
                 unimplemented!() //TODO: use FBO
             }
             com::Command::CopyImageToSurface(..) => {
                 unimplemented!() //TODO: use FBO
             }
+            com::Command::GenerateMipmap(texture, ref levels) => unsafe {
+                let gl = &self.share.context;
+                gl.BindTexture(gl::TEXTURE_2D, texture);
+                let mut base_level = 0;
+                let mut max_level = 0;
+                gl.GetTexParameteriv(gl::TEXTURE_2D, gl::TEXTURE_BASE_LEVEL, &mut base_level);
+                gl.GetTexParameteriv(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, &mut max_level);
+                // Restricting the levels keeps the texture complete even if
+                // later levels are missing, and leaves other levels untouched.
+                gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_BASE_LEVEL, levels.start as _);
+                gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, (levels.end - 1) as _);
+                gl.GenerateMipmap(gl::TEXTURE_2D);
+                gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_BASE_LEVEL, base_level);
+                gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, max_level);
+            },
+            com::Command::ExecuteCommands(ref secondary) => {
+                // Secondary buffers from the pool being submitted are replayed
+                // through the memory already locked by `submit`.
+                if Arc::ptr_eq(&secondary.memory, memory.pool) {
+                    self.execute_secondary(secondary, memory);
+                } else {
+                    let locked = match secondary.memory.try_lock() {
+                        Ok(locked) => locked,
+                        Err(_) => {
+                            error!(
+                                "Executing a secondary command buffer, while its memory is in-use"
+                            );
+                            return;
+                        }
+                    };
+                    let memory = PoolMemory {
+                        pool: &secondary.memory,
+                        memory: &*locked,
+                    };
+                    self.execute_secondary(secondary, memory);
+                }
+            }
+            com::Command::BeginTransformFeedback(mode) => unsafe {
+                self.share.context.BeginTransformFeedback(mode);
+            },
+            com::Command::EndTransformFeedback => unsafe {
+                self.share.context.EndTransformFeedback();
+            },
             com::Command::BindBufferRange(target, index, buffer, offset, size) => unsafe {
                 let gl = &self.share.context;
                 gl.BindBufferRange(target, index, buffer, offset, size);
             },
-            com::Command::BindTexture(index, texture) => unsafe {
+            com::Command::BindTexture(index, texture, target) => unsafe {
                 let gl = &self.share.context;
                 gl.ActiveTexture(gl::TEXTURE0 + index);
-                gl.BindTexture(gl::TEXTURE_2D, texture);
+                gl.BindTexture(target, texture);
             },
             com::Command::BindSampler(index, sampler) => unsafe {
                 let gl = &self.share.context;
                 gl.BindSampler(index, sampler);
             },
-            com::Command::SetTextureSamplerSettings(index, texture, ref sinfo) => unsafe {
+            com::Command::BindImageTexture(unit, texture, level, layer, format) => unsafe {
+                // HAL descriptors don't tell how shaders access the image.
+                self.share.context.BindImageTexture(
+                    unit,
+                    texture,
+                    level as _,
+                    gl::FALSE,
+                    layer.unwrap_or(0) as _,
+                    gl::READ_WRITE,
+                    format,
+                );
+            },
+            com::Command::SetTextureSamplerSettings(index, texture, target, ref sinfo) => unsafe {
                 let gl = &self.share.context;
                 gl.ActiveTexture(gl::TEXTURE0 + index);
-                gl.BindTexture(gl::TEXTURE_2D, texture);
+                gl.BindTexture(target, texture);
 
                 // TODO: Optimization: only change texture properties that have changed.
                 device::set_sampler_info(
                     &self.share,
                     &sinfo,
-                    |a, b| gl.TexParameterf(gl::TEXTURE_2D, a, b),
-                    |a, b| gl.TexParameterfv(gl::TEXTURE_2D, a, &b[0]),
-                    |a, b| gl.TexParameteri(gl::TEXTURE_2D, a, b),
+                    |a, b| gl.TexParameterf(target, a, b),
+                    |a, b| gl.TexParameterfv(target, a, &b[0]),
+                    |a, b| gl.TexParameteri(target, a, b),
                 );
+            },
+            com::Command::BeginQuery(target, query) => unsafe {
+                self.share.context.BeginQuery(target, query);
+            },
+            com::Command::BeginConditionalRender(query, mode) => unsafe {
+                let gl = &self.share.context;
+                if gl.BeginConditionalRender.is_loaded() {
+                    gl.BeginConditionalRender(query, mode);
+                } else {
+                    error!("Conditional rendering is not supported");
+                }
+            },
+            com::Command::EndConditionalRender => unsafe {
+                let gl = &self.share.context;
+                if gl.EndConditionalRender.is_loaded() {
+                    gl.EndConditionalRender();
+                }
+            },
+            com::Command::PushDebugGroup(name_ptr) => unsafe {
+                if self.share.private_caps.debug_output {
+                    let name = Self::get_raw(data_buf, name_ptr);
+                    self.share.context.PushDebugGroup(
+                        gl::DEBUG_SOURCE_APPLICATION,
+                        0,
+                        name.len() as _,
+                        name.as_ptr() as *const gl::types::GLchar,
+                    );
+                }
+            },
+            com::Command::PopDebugGroup => unsafe {
+                if self.share.private_caps.debug_output {
+                    self.share.context.PopDebugGroup();
+                }
+            },
+            com::Command::InsertDebugMarker(name_ptr) => unsafe {
+                if self.share.private_caps.debug_output {
+                    let name = Self::get_raw(data_buf, name_ptr);
+                    self.share.context.DebugMessageInsert(
+                        gl::DEBUG_SOURCE_APPLICATION,
+                        gl::DEBUG_TYPE_MARKER,
+                        0,
+                        gl::DEBUG_SEVERITY_NOTIFICATION,
+                        name.len() as _,
+                        name.as_ptr() as *const gl::types::GLchar,
+                    );
+                }
+            },
+            com::Command::EndQuery(target) => unsafe {
+                self.share.context.EndQuery(target);
+            },
+            com::Command::SetUniform(ref uniform, data_ptr) => unsafe {
+                let gl = &self.share.context;
+                let data = Self::get_raw(data_buf, data_ptr);
+                let count = (data.len() as u32 / conv::uniform_type_size(uniform.utype).unwrap())
+                    as gl::types::GLsizei;
+                let location = uniform.location;
+                let f = data.as_ptr() as *const gl::types::GLfloat;
+                let i = data.as_ptr() as *const gl::types::GLint;
+                let u = data.as_ptr() as *const gl::types::GLuint;
+                match uniform.utype {
+                    gl::FLOAT => gl.Uniform1fv(location, count, f),
+                    gl::FLOAT_VEC2 => gl.Uniform2fv(location, count, f),
+                    gl::FLOAT_VEC3 => gl.Uniform3fv(location, count, f),
+                    gl::FLOAT_VEC4 => gl.Uniform4fv(location, count, f),
+                    gl::INT | gl::BOOL => gl.Uniform1iv(location, count, i),
+                    gl::INT_VEC2 | gl::BOOL_VEC2 => gl.Uniform2iv(location, count, i),
+                    gl::INT_VEC3 | gl::BOOL_VEC3 => gl.Uniform3iv(location, count, i),
+                    gl::INT_VEC4 | gl::BOOL_VEC4 => gl.Uniform4iv(location, count, i),
+                    gl::UNSIGNED_INT => gl.Uniform1uiv(location, count, u),
+                    gl::UNSIGNED_INT_VEC2 => gl.Uniform2uiv(location, count, u),
+                    gl::UNSIGNED_INT_VEC3 => gl.Uniform3uiv(location, count, u),
+                    gl::UNSIGNED_INT_VEC4 => gl.Uniform4uiv(location, count, u),
+                    gl::FLOAT_MAT2 => gl.UniformMatrix2fv(location, count, gl::FALSE, f),
+                    gl::FLOAT_MAT4 => gl.UniformMatrix4fv(location, count, gl::FALSE, f),
+                    other => error!("Unsupported uniform type 0x{:x}", other),
+                }
+            },
+            com::Command::WriteTimestamp(query) => unsafe {
+                self.share.context.QueryCounter(query, gl::TIMESTAMP);
+            },
+            com::Command::CopyQueryResult {
+                query,
+                result_name,
+                buffer,
+                offset,
+                bits_64,
+            } => unsafe {
+                // With a buffer bound to `GL_QUERY_BUFFER`, the pointer
+                // is interpreted as an offset into it.
+                let gl = &self.share.context;
+                gl.BindBuffer(gl::QUERY_BUFFER, buffer);
+                if bits_64 {
+                    gl.GetQueryObjectui64v(query, result_name, offset as *mut _);
+                } else {
+                    gl.GetQueryObjectuiv(query, result_name, offset as *mut _);
+                }
+                gl.BindBuffer(gl::QUERY_BUFFER, 0);
             }, /*
                com::Command::BindConstantBuffer(pso::ConstantBufferParam(buffer, _, slot)) => unsafe {
                    self.share.context.BindBufferBase(gl::UNIFORM_BUFFER, slot as gl::types::GLuint, buffer);
@@ -772,15 +1692,15 @@ impl CommandQueue {
     }
 
     fn signal_fence(&mut self, fence: &native::Fence) {
-        if self.share.private_caps.sync {
-            let sync = if self.share.private_caps.sync {
-                let gl = &self.share.context;
-                unsafe { gl.FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0) }
-            } else {
-                ptr::null()
-            };
+        device::signal_sync(&self.share, &fence.0);
+    }
 
-            fence.0.set(sync);
+    // Make the server wait for the semaphore before executing further commands.
+    fn wait_semaphore(&mut self, semaphore: &native::Semaphore) {
+        let sync = semaphore.0.get();
+        if self.share.private_caps.sync && !sync.is_null() {
+            let gl = &self.share.context;
+            unsafe { gl.WaitSync(sync, 0, gl::TIMEOUT_IGNORED) };
         }
     }
 }
@@ -797,7 +1717,12 @@ impl hal::queue::RawCommandQueue<Backend> for CommandQueue {
         Iw: IntoIterator<Item = (&'a S, hal::pso::PipelineStage)>,
         Is: IntoIterator<Item = &'a S>,
     {
-        use crate::pool::BufferMemory;
+        // The device may have changed bindings since the last submission,
+        // only trust the cached state within a single submission.
+        self.state.flush();
+        for (semaphore, _) in submit_info.wait_semaphores {
+            self.wait_semaphore(semaphore.borrow());
+        }
         {
             for buf in submit_info.command_buffers {
                 let cb = buf.borrow();
@@ -815,11 +1740,20 @@ impl hal::queue::RawCommandQueue<Backend> for CommandQueue {
                 let commands = &buffer.commands
                     [cb.buf.offset as usize..(cb.buf.offset + cb.buf.size) as usize];
                 self.reset_state();
+                let memory = PoolMemory {
+                    pool: &cb.memory,
+                    memory: &*memory,
+                };
                 for com in commands {
-                    self.process(com, &buffer.data);
+                    self.process(com, memory, &buffer.data);
+                    #[cfg(feature = "check-errors")]
+                    crate::log_errors(&self.share.context, &format_args!("{:?}", com));
                 }
             }
         }
+        for semaphore in submit_info.signal_semaphores {
+            device::signal_sync(&self.share, &semaphore.borrow().0);
+        }
         fence.map(|fence| self.signal_fence(fence));
     }
 
@@ -827,7 +1761,7 @@ impl hal::queue::RawCommandQueue<Backend> for CommandQueue {
     unsafe fn present<'a, W, Is, S, Iw>(
         &mut self,
         swapchains: Is,
-        _wait_semaphores: Iw,
+        wait_semaphores: Iw,
     ) -> Result<(), ()>
     where
         W: 'a + Borrow<window::glutin::Swapchain>,
@@ -835,8 +1769,102 @@ impl hal::queue::RawCommandQueue<Backend> for CommandQueue {
         S: 'a + Borrow<native::Semaphore>,
         Iw: IntoIterator<Item = &'a S>,
     {
-        for swapchain in swapchains {
-            swapchain.0.borrow().window.swap_buffers().unwrap();
+        // GL contexts can't migrate between threads, presentation has to
+        // happen on the thread which created the context.
+        if !Starc::is_local(&self.share) {
+            error!("Presenting from a thread not owning the GL context");
+            return Err(());
+        }
+
+        for semaphore in wait_semaphores {
+            self.wait_semaphore(semaphore.borrow());
+        }
+
+        for (swapchain, index) in swapchains {
+            let swapchain = swapchain.borrow();
+            let index = index as usize;
+
+            // Presenting requires the window's context to be current.
+            let (fbos, resolve) = match swapchain.begin_present() {
+                Some(framebuffers) => framebuffers,
+                None => return Err(()),
+            };
+
+            // Copy the swapchain image onto the default framebuffer, resolving
+            // it if multisampled.
+            if let Some(&fbo) = fbos.get(index) {
+                let gl = &self.share.context;
+                let extent = swapchain.extent;
+                // Copy the raw values, the swapchain format defines how they
+                // are interpreted regardless of the default framebuffer.
+                let srgb = self
+                    .share
+                    .legacy_features
+                    .contains(LegacyFeatures::SRGB_COLOR);
+                if srgb {
+                    gl.Disable(gl::FRAMEBUFFER_SRGB);
+                }
+                gl.BindFramebuffer(gl::READ_FRAMEBUFFER, fbo);
+                if let Some(resolve_fbo) = resolve {
+                    gl.BindFramebuffer(gl::DRAW_FRAMEBUFFER, resolve_fbo);
+                    gl.BlitFramebuffer(
+                        0,
+                        0,
+                        extent.width as _,
+                        extent.height as _,
+                        0,
+                        0,
+                        extent.width as _,
+                        extent.height as _,
+                        gl::COLOR_BUFFER_BIT,
+                        gl::NEAREST,
+                    );
+                    gl.BindFramebuffer(gl::READ_FRAMEBUFFER, resolve_fbo);
+                }
+                gl.BindFramebuffer(gl::DRAW_FRAMEBUFFER, native::DEFAULT_FRAMEBUFFER);
+                // Images are stored top row first, while the window origin
+                // is in the bottom-left corner.
+                gl.BlitFramebuffer(
+                    0,
+                    0,
+                    extent.width as _,
+                    extent.height as _,
+                    0,
+                    extent.height as _,
+                    extent.width as _,
+                    0,
+                    gl::COLOR_BUFFER_BIT,
+                    gl::NEAREST,
+                );
+                gl.BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+
+                // Opaque presentation on a window with an alpha channel
+                // requires overwriting the alpha values seen by the compositor.
+                if swapchain.composite_alpha == hal::CompositeAlpha::OPAQUE && swapchain.has_alpha {
+                    gl.ColorMask(gl::FALSE, gl::FALSE, gl::FALSE, gl::TRUE);
+                    gl.ClearColor(0.0, 0.0, 0.0, 1.0);
+                    gl.Clear(gl::COLOR_BUFFER_BIT);
+                    gl.ColorMask(gl::TRUE, gl::TRUE, gl::TRUE, gl::TRUE);
+                }
+            }
+
+            // Presentation changes framebuffer bindings and the color mask
+            // behind the back of the state cache.
+            self.state.flush();
+
+            swapchain.set_swap_interval();
+            if let Err(err) = swapchain.swap_buffers() {
+                // The context may have been lost, see `Surface::is_context_lost`.
+                error!("Failed to swap buffers: {:?}", err);
+                let _ = swapchain.end_present();
+                return Err(());
+            }
+
+            // The image can be acquired again once the copy has completed.
+            if let Some(presented) = swapchain.presented.get(index) {
+                device::signal_sync(&self.share, &presented.0);
+            }
+            swapchain.end_present()?;
         }
 
         Ok(())
@@ -849,3 +1877,48 @@ impl hal::queue::RawCommandQueue<Backend> for CommandQueue {
         Ok(())
     }
 }
+
+/// Map a range of the buffer bound to `target`, returning the pointer and
+/// whether it has to be unmapped again.
+///
+/// Buffers can only be mapped once: if the buffer is already mapped, such as
+/// memory mapped persistently, the existing mapping is used if it covers the
+/// range with the requested access.
+unsafe fn map_buffer_range(
+    gl: &GlContainer,
+    target: gl::types::GLenum,
+    offset: buffer::Offset,
+    size: buffer::Offset,
+    access: gl::types::GLbitfield,
+) -> Option<(*mut u8, bool)> {
+    let mut mapped = 0;
+    gl.GetBufferParameteriv(target, gl::BUFFER_MAPPED, &mut mapped);
+    if mapped == 0 {
+        let ptr = gl.MapBufferRange(target, offset as _, size as _, access) as *mut u8;
+        return if ptr.is_null() {
+            None
+        } else {
+            Some((ptr, true))
+        };
+    }
+
+    let mut map_offset = 0;
+    let mut map_length = 0;
+    let mut map_access = 0;
+    let mut map_ptr = ptr::null_mut();
+    gl.GetBufferParameteri64v(target, gl::BUFFER_MAP_OFFSET, &mut map_offset);
+    gl.GetBufferParameteri64v(target, gl::BUFFER_MAP_LENGTH, &mut map_length);
+    gl.GetBufferParameteriv(target, gl::BUFFER_ACCESS_FLAGS, &mut map_access);
+    gl.GetBufferPointerv(target, gl::BUFFER_MAP_POINTER, &mut map_ptr);
+    let (map_offset, map_length) = (map_offset as buffer::Offset, map_length as buffer::Offset);
+    let required = access & (gl::MAP_READ_BIT | gl::MAP_WRITE_BIT);
+    if map_ptr.is_null()
+        || offset < map_offset
+        || offset + size > map_offset + map_length
+        || map_access as gl::types::GLbitfield & required != required
+    {
+        return None;
+    }
+    let ptr = map_ptr as *mut u8;
+    Some((ptr.offset((offset - map_offset) as isize), false))
+}
@@ -5,40 +5,57 @@ use crate::hal::ColorSlot;
 use smallvec::SmallVec;
 use crate::{gl, GlContainer};
 
-pub(crate) fn bind_polygon_mode(
+pub(crate) fn bind_polygon_mode(gl: &GlContainer, mode: pso::PolygonMode) {
+    use crate::hal::pso::PolygonMode::*;
+
+    let gl_draw = match mode {
+        Point => gl::POINT,
+        // Line width is applied separately, clamped to the supported range.
+        Line(_) => gl::LINE,
+        Fill => gl::FILL,
+    };
+
+    unsafe { gl.PolygonMode(gl::FRONT_AND_BACK, gl_draw) };
+}
+
+pub(crate) fn bind_depth_bias(
     gl: &GlContainer,
     mode: pso::PolygonMode,
     bias: Option<pso::State<pso::DepthBias>>,
 ) {
     use crate::hal::pso::PolygonMode::*;
 
-    let (gl_draw, gl_offset) = match mode {
-        Point => (gl::POINT, gl::POLYGON_OFFSET_POINT),
-        Line(width) => {
-            unsafe { gl.LineWidth(width) };
-            (gl::LINE, gl::POLYGON_OFFSET_LINE)
-        }
-        Fill => (gl::FILL, gl::POLYGON_OFFSET_FILL),
+    let gl_offset = match mode {
+        Point => gl::POLYGON_OFFSET_POINT,
+        Line(_) => gl::POLYGON_OFFSET_LINE,
+        Fill => gl::POLYGON_OFFSET_FILL,
     };
 
-    unsafe { gl.PolygonMode(gl::FRONT_AND_BACK, gl_draw) };
-
     match bias {
         Some(pso::State::Static(bias)) => unsafe {
             gl.Enable(gl_offset);
             gl.PolygonOffset(bias.slope_factor as _, bias.const_factor as _);
         },
-        _ => unsafe { gl.Disable(gl_offset) },
+        // Factors are provided by `set_depth_bias`.
+        Some(pso::State::Dynamic) => unsafe { gl.Enable(gl_offset) },
+        None => unsafe { gl.Disable(gl_offset) },
     }
 }
 
-pub(crate) fn bind_rasterizer(gl: &GlContainer, r: &pso::Rasterizer, is_embedded: bool) {
+pub(crate) fn bind_rasterizer(
+    gl: &GlContainer,
+    r: &pso::Rasterizer,
+    is_embedded: bool,
+    depth_clamp: bool,
+) {
     use crate::hal::pso::FrontFace::*;
 
+    // Rendering isn't flipped vertically, so framebuffer coordinates have Y
+    // pointing up from GL's point of view, inverting the winding.
     unsafe {
         gl.FrontFace(match r.front_face {
-            Clockwise => gl::CW,
-            CounterClockwise => gl::CCW,
+            Clockwise => gl::CCW,
+            CounterClockwise => gl::CW,
         })
     };
 
@@ -58,11 +75,16 @@ pub(crate) fn bind_rasterizer(gl: &GlContainer, r: &pso::Rasterizer, is_embedded
     }
 
     if !is_embedded {
-        bind_polygon_mode(gl, r.polygon_mode, r.depth_bias);
-        match false {
-            //TODO
-            true => unsafe { gl.Enable(gl::MULTISAMPLE) },
-            false => unsafe { gl.Disable(gl::MULTISAMPLE) },
+        bind_polygon_mode(gl, r.polygon_mode);
+    }
+
+    bind_depth_bias(gl, r.polygon_mode, r.depth_bias);
+
+    if depth_clamp {
+        if r.depth_clamping {
+            unsafe { gl.Enable(gl::DEPTH_CLAMP) };
+        } else {
+            unsafe { gl.Disable(gl::DEPTH_CLAMP) };
         }
     }
 }
@@ -114,28 +136,18 @@ fn map_operation(op: pso::StencilOp) -> gl::types::GLenum {
     }
 }
 
-pub(crate) fn bind_stencil(
-    gl: &GlContainer,
-    stencil: &pso::StencilTest,
-    (ref_front, ref_back): (pso::StencilValue, pso::StencilValue),
-    cull: Option<pso::Face>,
-) {
-    fn bind_side(
-        gl: &GlContainer,
-        face: gl::types::GLenum,
-        side: &pso::StencilFace,
-        ref_value: pso::StencilValue,
-    ) {
+pub(crate) fn bind_stencil(gl: &GlContainer, stencil: &pso::StencilTest) {
+    fn bind_side(gl: &GlContainer, face: gl::types::GLenum, side: &pso::StencilFace) {
+        // Dynamic values are resolved by the command buffer, these are just fallbacks.
+        let value = |state, default| match state {
+            pso::State::Static(v) => v,
+            pso::State::Dynamic => default,
+        };
+        let reference = value(side.reference, 0);
+        let mr = value(side.mask_read, !0);
+        let mw = value(side.mask_write, !0);
         unsafe {
-            let mr = match side.mask_read {
-                pso::State::Static(v) => v,
-                pso::State::Dynamic => !0,
-            };
-            let mw = match side.mask_write {
-                pso::State::Static(v) => v,
-                pso::State::Dynamic => !0,
-            };
-            gl.StencilFuncSeparate(face, map_comparison(side.fun), ref_value as _, mr);
+            gl.StencilFuncSeparate(face, map_comparison(side.fun), reference as _, mr);
             gl.StencilMaskSeparate(face, mw);
             gl.StencilOpSeparate(
                 face,
@@ -151,14 +163,8 @@ pub(crate) fn bind_stencil(
             ref back,
         } => {
             unsafe { gl.Enable(gl::STENCIL_TEST) };
-            if let Some(cf) = cull {
-                if !cf.contains(pso::Face::FRONT) {
-                    bind_side(gl, gl::FRONT, front, ref_front);
-                }
-                if !cf.contains(pso::Face::BACK) {
-                    bind_side(gl, gl::BACK, back, ref_back);
-                }
-            }
+            bind_side(gl, gl::FRONT, front);
+            bind_side(gl, gl::BACK, back);
         }
         pso::StencilTest::Off => unsafe {
             gl.Disable(gl::STENCIL_TEST);
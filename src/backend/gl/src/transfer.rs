@@ -0,0 +1,409 @@
+//! Asynchronous pixel transfers between the host and images, going through
+//! a ring of pixel buffer objects.
+
+use std::cell::Cell;
+use std::sync::{Arc, Weak};
+use std::{ptr, u64};
+
+use crate::gl;
+use crate::hal::format::Format;
+use crate::hal::image as i;
+use crate::{conv, device, native as n, Share, Starc};
+
+/// Default number of buffers in the ring.
+const DEFAULT_COUNT: usize = 3;
+/// Default size of each buffer, in bytes.
+const DEFAULT_SIZE: u64 = 4 << 20;
+
+/// Read-back of an image region, pending in a pixel buffer object.
+///
+/// See `Device::read_image`. Dropping it without calling `finish_read`
+/// discards the data and releases the buffer.
+#[derive(Debug)]
+#[must_use]
+pub struct PixelTransfer {
+    slot: usize,
+    generation: u64,
+    size: usize,
+    // Keeps the buffer reserved until collected or dropped.
+    owner: Arc<()>,
+}
+
+#[derive(Debug)]
+struct Slot {
+    buffer: n::RawBuffer,
+    // Signalled once the last transfer through the buffer completed.
+    sync: Cell<gl::types::GLsync>,
+    // Pending read-back, which has to be collected before reusing the buffer
+    // unless its transfer has been dropped.
+    read: Option<Weak<()>>,
+}
+
+impl Slot {
+    fn is_reserved(&self) -> bool {
+        self.read
+            .as_ref()
+            .map_or(false, |owner| owner.upgrade().is_some())
+    }
+}
+
+/// Ring of pixel buffer objects, used in turn for transfers. Each buffer
+/// is fenced after a transfer and only reused once the fence is signalled.
+#[derive(Debug)]
+pub(crate) struct TransferPool {
+    slots: Vec<Slot>,
+    count: usize,
+    size: u64,
+    next: usize,
+    // Incremented when the buffers are released, invalidating pending read-backs.
+    generation: u64,
+    // Framebuffer the images are attached to for reading them back.
+    read_fbo: Option<n::FrameBuffer>,
+}
+
+// Sync objects are only accessed from the thread owning the context.
+unsafe impl Send for TransferPool {}
+
+impl TransferPool {
+    pub(crate) fn new() -> Self {
+        TransferPool {
+            slots: Vec::new(),
+            count: DEFAULT_COUNT,
+            size: DEFAULT_SIZE,
+            next: 0,
+            generation: 0,
+            read_fbo: None,
+        }
+    }
+
+    /// Change the number and size of the buffers, releasing the current ones.
+    pub(crate) fn configure(&mut self, share: &Starc<Share>, count: usize, size: u64) {
+        self.clear(share);
+        self.count = count.max(1);
+        self.size = size;
+    }
+
+    /// Release the buffers, they are allocated again on the next transfer.
+    pub(crate) fn clear(&mut self, share: &Starc<Share>) {
+        let gl = &share.context;
+        for slot in self.slots.drain(..) {
+            if slot.is_reserved() {
+                warn!("Discarding a pending image read-back");
+            }
+            let sync = slot.sync.get();
+            unsafe {
+                if !sync.is_null() {
+                    gl.DeleteSync(sync);
+                }
+                gl.DeleteBuffers(1, &slot.buffer);
+            }
+        }
+        if let Some(fbo) = self.read_fbo.take() {
+            unsafe { gl.DeleteFramebuffers(1, &fbo) };
+        }
+        self.generation += 1;
+        self.next = 0;
+    }
+
+    /// Pick the next buffer not holding a pending read-back, waiting for its
+    /// last transfer to complete.
+    fn acquire(&mut self, share: &Starc<Share>, size: usize) -> Result<usize, ()> {
+        if size as u64 > self.size {
+            error!(
+                "Transfer of {} bytes exceeds the size of the transfer buffers ({} bytes)",
+                size, self.size
+            );
+            return Err(());
+        }
+        if self.slots.is_empty() {
+            self.allocate(share);
+        }
+
+        let count = self.slots.len();
+        let slots = &self.slots;
+        let index = match (0..count)
+            .map(|i| (self.next + i) % count)
+            .find(|&i| !slots[i].is_reserved())
+        {
+            Some(index) => index,
+            None => {
+                error!("All transfer buffers hold pending image read-backs");
+                return Err(());
+            }
+        };
+        self.next = (index + 1) % count;
+        self.slots[index].read = None;
+        wait_slot(share, &self.slots[index]);
+        Ok(index)
+    }
+
+    fn allocate(&mut self, share: &Starc<Share>) {
+        let gl = &share.context;
+        let mut buffers = vec![0; self.count];
+        unsafe {
+            gl.GenBuffers(self.count as _, buffers.as_mut_ptr());
+            for &buffer in &buffers {
+                gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, buffer);
+                gl.BufferData(
+                    gl::PIXEL_UNPACK_BUFFER,
+                    self.size as _,
+                    ptr::null(),
+                    gl::STREAM_DRAW,
+                );
+            }
+            gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, 0);
+        }
+        self.slots = buffers
+            .into_iter()
+            .map(|buffer| Slot {
+                buffer,
+                sync: Cell::new(ptr::null()),
+                read: None,
+            })
+            .collect();
+    }
+
+    /// Upload tightly packed texels to a region of a texture level.
+    pub(crate) fn write(
+        &mut self,
+        share: &Starc<Share>,
+        image: &n::Image,
+        level: i::Level,
+        offset: i::Offset,
+        extent: i::Extent,
+        data: &[u8],
+    ) -> Result<(), ()> {
+        let texture = match image.kind {
+            n::ImageKind::Texture(texture) => texture,
+            n::ImageKind::Surface(_) => {
+                error!("Only images backed by textures can be written to");
+                return Err(());
+            }
+        };
+        let (_, format, pixel_type) = transfer_format(image.format)?;
+        check_region(offset, extent)?;
+        if data.len() != region_size(image.format, extent) {
+            error!(
+                "Data size {} doesn't match the size of the region {:?}",
+                data.len(),
+                extent
+            );
+            return Err(());
+        }
+
+        let index = self.acquire(share, data.len())?;
+        let slot = &self.slots[index];
+        let gl = &share.context;
+        unsafe {
+            gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, slot.buffer);
+            let ptr = gl.MapBufferRange(
+                gl::PIXEL_UNPACK_BUFFER,
+                0,
+                data.len() as _,
+                gl::MAP_WRITE_BIT | gl::MAP_INVALIDATE_BUFFER_BIT,
+            );
+            if ptr.is_null() {
+                error!("Failed to map transfer buffer {}", slot.buffer);
+                gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, 0);
+                return Err(());
+            }
+            ptr::copy_nonoverlapping(data.as_ptr(), ptr as *mut u8, data.len());
+            gl.UnmapBuffer(gl::PIXEL_UNPACK_BUFFER);
+
+            gl.ActiveTexture(gl::TEXTURE0);
+            gl.BindTexture(gl::TEXTURE_2D, texture);
+            gl.PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl.TexSubImage2D(
+                gl::TEXTURE_2D,
+                level as _,
+                offset.x,
+                offset.y,
+                extent.width as _,
+                extent.height as _,
+                format,
+                pixel_type,
+                ptr::null(),
+            );
+            gl.PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+            gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, 0);
+        }
+        device::signal_sync(share, &slot.sync);
+        Ok(())
+    }
+
+    /// Start reading back a region of an image level, tightly packed.
+    pub(crate) fn read(
+        &mut self,
+        share: &Starc<Share>,
+        image: &n::Image,
+        level: i::Level,
+        offset: i::Offset,
+        extent: i::Extent,
+    ) -> Result<PixelTransfer, ()> {
+        let (_, format, pixel_type) = transfer_format(image.format)?;
+        check_region(offset, extent)?;
+        if self.read_fbo.is_none() {
+            self.read_fbo = device::create_fbo_internal(share);
+        }
+        let fbo = match self.read_fbo {
+            Some(fbo) => fbo,
+            None => {
+                error!("Reading back images requires framebuffer support");
+                return Err(());
+            }
+        };
+
+        let size = region_size(image.format, extent);
+        let index = self.acquire(share, size)?;
+        let owner = Arc::new(());
+        let slot = &mut self.slots[index];
+        let gl = &share.context;
+        unsafe {
+            gl.BindFramebuffer(gl::READ_FRAMEBUFFER, fbo);
+            match image.kind {
+                n::ImageKind::Texture(texture) => gl.FramebufferTexture2D(
+                    gl::READ_FRAMEBUFFER,
+                    gl::COLOR_ATTACHMENT0,
+                    gl::TEXTURE_2D,
+                    texture,
+                    level as _,
+                ),
+                n::ImageKind::Surface(surface) => gl.FramebufferRenderbuffer(
+                    gl::READ_FRAMEBUFFER,
+                    gl::COLOR_ATTACHMENT0,
+                    gl::RENDERBUFFER,
+                    surface,
+                ),
+            }
+            gl.BindBuffer(gl::PIXEL_PACK_BUFFER, slot.buffer);
+            gl.PixelStorei(gl::PACK_ALIGNMENT, 1);
+            gl.ReadPixels(
+                offset.x,
+                offset.y,
+                extent.width as _,
+                extent.height as _,
+                format,
+                pixel_type,
+                ptr::null_mut(),
+            );
+            gl.PixelStorei(gl::PACK_ALIGNMENT, 4);
+            gl.BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+            gl.BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+        }
+        device::signal_sync(share, &slot.sync);
+        slot.read = Some(Arc::downgrade(&owner));
+
+        Ok(PixelTransfer {
+            slot: index,
+            generation: self.generation,
+            size,
+            owner,
+        })
+    }
+
+    /// Check if a read-back completed, without waiting.
+    pub(crate) fn is_complete(&self, share: &Starc<Share>, transfer: &PixelTransfer) -> bool {
+        if transfer.generation != self.generation {
+            return true;
+        }
+        let sync = self.slots[transfer.slot].sync.get();
+        sync.is_null() || device::is_sync_signalled(share, sync)
+    }
+
+    /// Wait for a read-back to complete and copy its data out of the buffer.
+    pub(crate) fn finish_read(
+        &mut self,
+        share: &Starc<Share>,
+        transfer: PixelTransfer,
+    ) -> Result<Vec<u8>, ()> {
+        if transfer.generation != self.generation {
+            error!("The transfer buffers were released before completing the read-back");
+            return Err(());
+        }
+        let slot = &mut self.slots[transfer.slot];
+        let owned = slot
+            .read
+            .as_ref()
+            .and_then(Weak::upgrade)
+            .map_or(false, |owner| Arc::ptr_eq(&owner, &transfer.owner));
+        if !owned {
+            error!(
+                "Transfer buffer {} doesn't hold this read-back",
+                slot.buffer
+            );
+            return Err(());
+        }
+        slot.read = None;
+        wait_slot(share, slot);
+
+        let gl = &share.context;
+        let mut data = vec![0; transfer.size];
+        unsafe {
+            gl.BindBuffer(gl::PIXEL_PACK_BUFFER, slot.buffer);
+            let ptr = gl.MapBufferRange(
+                gl::PIXEL_PACK_BUFFER,
+                0,
+                transfer.size as _,
+                gl::MAP_READ_BIT,
+            );
+            if ptr.is_null() {
+                error!("Failed to map transfer buffer {}", slot.buffer);
+                gl.BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+                return Err(());
+            }
+            ptr::copy_nonoverlapping(ptr as *const u8, data.as_mut_ptr(), transfer.size);
+            gl.UnmapBuffer(gl::PIXEL_PACK_BUFFER);
+            gl.BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+        }
+        Ok(data)
+    }
+}
+
+/// Wait for the last transfer through a buffer to complete.
+///
+/// Without sync objects, mapping the buffer waits for the transfer instead.
+fn wait_slot(share: &Starc<Share>, slot: &Slot) {
+    let sync = slot.sync.replace(ptr::null());
+    if sync.is_null() {
+        return;
+    }
+    let gl = &share.context;
+    unsafe {
+        gl.ClientWaitSync(sync, gl::SYNC_FLUSH_COMMANDS_BIT, u64::MAX);
+        gl.DeleteSync(sync);
+    }
+}
+
+fn transfer_format(
+    format: Format,
+) -> Result<(gl::types::GLenum, gl::types::GLenum, gl::types::GLenum), ()> {
+    if !format.is_color() || format.surface_desc().is_compressed() {
+        error!(
+            "Only uncompressed color formats can be transferred, got {:?}",
+            format
+        );
+        return Err(());
+    }
+    conv::image_format_to_gl(format).ok_or_else(|| {
+        error!("Format {:?} can't be transferred", format);
+    })
+}
+
+/// Textures are created as `GL_TEXTURE_2D`, only single layer regions of
+/// 2D images can be transferred.
+fn check_region(offset: i::Offset, extent: i::Extent) -> Result<(), ()> {
+    if offset.z != 0 || extent.depth != 1 {
+        error!(
+            "Only 2D image regions can be transferred, got offset {:?} and extent {:?}",
+            offset, extent
+        );
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Size in bytes of a tightly packed image region.
+fn region_size(format: Format, extent: i::Extent) -> usize {
+    let bytes_per_texel = format.surface_desc().bits as usize / 8;
+    extent.width as usize * extent.height as usize * bytes_per_texel
+}
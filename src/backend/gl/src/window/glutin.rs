@@ -50,12 +50,167 @@ use hal::{
 };
 
 use crate::{
-    native,
-    Backend as B, Device, PhysicalDevice, QueueFamily, Starc
+    conv, device, gl, native,
+    Backend as B, Device, PhysicalDevice, QueueFamily, Share, Starc
 };
 
 use glutin::{self, ContextTrait};
 
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+use std::{mem, ptr};
+
+/// Maximum number of images in a swapchain.
+const MAX_IMAGE_COUNT: hal::SwapImageIndex = 3;
+
+/// Platform entry point controlling the swap interval of the current context,
+/// as exposed by `WGL_EXT_swap_control` and `GLX_{MESA,SGI}_swap_control`.
+type SwapIntervalFn = extern "system" fn(i32) -> i32;
+
+/// `glGetGraphicsResetStatus`, from GL 4.5 or the robustness extensions.
+type GetGraphicsResetStatusFn = extern "system" fn() -> gl::types::GLenum;
+
+/// Resolve the first available entry point among `names`.
+fn load_proc(window: &glutin::WindowedContext, names: &[&str]) -> Option<*const ()> {
+    names.iter().find_map(|name| {
+        let addr = window.get_proc_address(name);
+        if addr.is_null() {
+            None
+        } else {
+            Some(addr as *const ())
+        }
+    })
+}
+
+/// Swap interval shared between a surface and its swapchains.
+struct SwapInterval {
+    func: Option<SwapIntervalFn>,
+    // Interval to use for the next presentation
+    requested: Cell<i32>,
+    // Interval currently set on the context
+    applied: Cell<Option<i32>>,
+}
+
+impl SwapInterval {
+    fn load(window: &glutin::WindowedContext) -> Self {
+        let func = load_proc(
+            window,
+            &[
+                "wglSwapIntervalEXT",
+                "glXSwapIntervalMESA",
+                "glXSwapIntervalSGI",
+            ],
+        )
+        .map(|addr| unsafe { mem::transmute::<_, SwapIntervalFn>(addr) });
+
+        SwapInterval {
+            func,
+            requested: Cell::new(1),
+            applied: Cell::new(None),
+        }
+    }
+
+    /// Set the requested interval on the context, if it changed.
+    fn apply(&self) {
+        let interval = self.requested.get();
+        if self.applied.get() == Some(interval) {
+            return;
+        }
+        if let Some(func) = self.func {
+            if func(interval) == 0 {
+                warn!("Failed to set swap interval {}", interval);
+            }
+            self.applied.set(Some(interval));
+        }
+    }
+}
+
+/// Frame pacing statistics of a surface, collected when presenting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PresentStats {
+    /// Time spent swapping buffers for the last presentation.
+    pub last_present_duration: Duration,
+    /// Rolling average of the time spent swapping buffers.
+    pub average_present_duration: Duration,
+    /// Time between the last two presentations, if presented more than once.
+    pub frame_interval: Option<Duration>,
+    /// Whether the last presentation with vsync enabled missed the deadline,
+    /// i.e. the frame interval exceeded one refresh period.
+    ///
+    /// The refresh period is estimated from the shortest frame interval
+    /// observed with vsync enabled.
+    pub missed_vsync: bool,
+}
+
+/// Present timings shared between a surface and its swapchains.
+struct PresentTiming {
+    stats: Cell<Option<PresentStats>>,
+    // End of the last presentation
+    last_present: Cell<Option<Instant>>,
+    // Estimated refresh period of the display
+    refresh_period: Cell<Option<Duration>>,
+}
+
+impl PresentTiming {
+    fn new() -> Self {
+        PresentTiming {
+            stats: Cell::new(None),
+            last_present: Cell::new(None),
+            refresh_period: Cell::new(None),
+        }
+    }
+
+    /// Record a presentation, which started swapping buffers at `start`.
+    fn record(&self, start: Instant, vsync: bool) {
+        let end = Instant::now();
+        let duration = end - start;
+        let frame_interval = self.last_present.replace(Some(end)).map(|last| end - last);
+
+        let average = match self.stats.get() {
+            // Exponential moving average, weighting the last 16 frames.
+            Some(stats) => stats.average_present_duration * 15 / 16 + duration / 16,
+            None => duration,
+        };
+
+        let mut missed_vsync = false;
+        if let (Some(interval), true) = (frame_interval, vsync) {
+            let period = match self.refresh_period.get() {
+                Some(period) if period <= interval => period,
+                _ => interval,
+            };
+            self.refresh_period.set(Some(period));
+            missed_vsync = interval > period * 3 / 2;
+        }
+
+        self.stats.set(Some(PresentStats {
+            last_present_duration: duration,
+            average_present_duration: average,
+            frame_interval,
+            missed_vsync,
+        }));
+    }
+}
+
+fn load_reset_status(window: &glutin::WindowedContext) -> Option<GetGraphicsResetStatusFn> {
+    load_proc(
+        window,
+        &[
+            "glGetGraphicsResetStatus",
+            "glGetGraphicsResetStatusARB",
+            "glGetGraphicsResetStatusKHR",
+            "glGetGraphicsResetStatusEXT",
+        ],
+    )
+    .map(|addr| unsafe { mem::transmute::<_, GetGraphicsResetStatusFn>(addr) })
+}
+
+fn is_context_lost(reset_status: Option<GetGraphicsResetStatusFn>) -> bool {
+    match reset_status {
+        Some(reset_status) => reset_status() != gl::NO_ERROR,
+        None => false,
+    }
+}
+
 fn get_window_extent(window: &glutin::WindowedContext) -> image::Extent {
     let px = window
         .get_inner_size()
@@ -68,37 +223,437 @@ fn get_window_extent(window: &glutin::WindowedContext) -> image::Extent {
     }
 }
 
+/// Framebuffers for presenting to a window whose context shares objects with
+/// the context rendering the swapchain images.
+pub(crate) struct SharedPresent {
+    // Window of the rendering context, made current again after presenting
+    context: Starc<glutin::WindowedContext>,
+    // Framebuffers of the window's context, one per swapchain image
+    fbos: Vec<native::FrameBuffer>,
+    // Framebuffer of the window's context attached to the resolve renderbuffer
+    resolve: Option<native::FrameBuffer>,
+}
+
 pub struct Swapchain {
     // Underlying window, required for presentation
     pub(crate) window: Starc<glutin::WindowedContext>,
+    pub(crate) share: Starc<Share>,
+    // Extent because the window lies
+    pub(crate) extent: hal::window::Extent2D,
+    // Size of the window when the swapchain was created
+    window_extent: hal::window::Extent2D,
+    // Scale factor of the window when the swapchain was created
+    scale_factor: f64,
+    // One framebuffer per swapchain image, blitted to the window on present.
+    // Empty if the swapchain renders directly into the default framebuffer.
+    pub(crate) fbos: Vec<native::FrameBuffer>,
+    // Color renderbuffers attached to `fbos`.
+    pub(crate) renderbuffers: Vec<native::Surface>,
+    // Single-sampled framebuffer and renderbuffer, resolving multisampled
+    // images before they get flipped onto the window.
+    pub(crate) resolve: Option<(native::FrameBuffer, native::Surface)>,
+    // Signalled once the corresponding image has been presented.
+    pub(crate) presented: Vec<native::Fence>,
+    // Index of the image returned by the next `acquire_image`.
+    next_image: hal::SwapImageIndex,
+    pub(crate) composite_alpha: CompositeAlpha,
+    // Whether the default framebuffer has an alpha channel
+    pub(crate) has_alpha: bool,
+    swap_interval: Starc<SwapInterval>,
+    present_timing: Starc<PresentTiming>,
+    // Set if the window belongs to another context than the device.
+    shared: Option<SharedPresent>,
+    reset_status: Option<GetGraphicsResetStatusFn>,
+}
+
+impl Swapchain {
+    /// Apply the requested swap interval before presenting.
+    pub(crate) fn set_swap_interval(&self) {
+        self.swap_interval.apply();
+    }
+
+    /// Make the context of the window current for presenting.
+    ///
+    /// Returns the framebuffers of each image and of the resolve renderbuffer
+    /// in that context, or `None` if the window can't be presented to.
+    pub(crate) unsafe fn begin_present(
+        &self,
+    ) -> Option<(&[native::FrameBuffer], Option<native::FrameBuffer>)> {
+        let shared = match self.shared {
+            Some(ref shared) => shared,
+            None if self.window.is_current() => {
+                return Some((&self.fbos, self.resolve.map(|(fbo, _)| fbo)));
+            }
+            None => return None,
+        };
+
+        // The images have to be rendered before the window's context reads them.
+        let gl = &self.share.context;
+        let sync = if self.share.private_caps.sync {
+            let sync = gl.FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0);
+            gl.Flush();
+            Some(sync)
+        } else {
+            gl.Finish();
+            None
+        };
+        if let Err(err) = self.window.make_current() {
+            error!("Failed to make the window's context current: {:?}", err);
+            return None;
+        }
+        if let Some(sync) = sync {
+            gl.WaitSync(sync, 0, gl::TIMEOUT_IGNORED);
+            gl.DeleteSync(sync);
+        }
+        Some((&shared.fbos, shared.resolve))
+    }
+
+    /// Make the rendering context current again after presenting.
+    pub(crate) unsafe fn end_present(&self) -> Result<(), ()> {
+        match self.shared {
+            Some(ref shared) => shared.context.make_current().map_err(|err| {
+                error!("Failed to make the rendering context current: {:?}", err);
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Delete the framebuffers created in the context of the window, if it
+    /// isn't the rendering context.
+    pub(crate) unsafe fn destroy_shared(&self) {
+        let shared = match self.shared {
+            Some(ref shared) => shared,
+            None => return,
+        };
+        if self.window.make_current().is_err() {
+            return;
+        }
+        let gl = &self.share.context;
+        gl.DeleteFramebuffers(shared.fbos.len() as _, shared.fbos.as_ptr());
+        if let Some(fbo) = shared.resolve {
+            gl.DeleteFramebuffers(1, &fbo);
+        }
+        let _ = shared.context.make_current();
+    }
+
+    /// Swap the buffers of the window, recording how long it took.
+    pub(crate) fn swap_buffers(&self) -> Result<(), glutin::ContextError> {
+        let start = Instant::now();
+        let result = self.window.swap_buffers();
+        let vsync = self.swap_interval.requested.get() != 0;
+        self.present_timing.record(start, vsync);
+        result
+    }
 }
 
 impl hal::Swapchain<B> for Swapchain {
     unsafe fn acquire_image(
         &mut self,
-        _timeout_ns: u64,
-        _semaphore: Option<&native::Semaphore>,
-        _fence: Option<&native::Fence>,
+        timeout_ns: u64,
+        semaphore: Option<&native::Semaphore>,
+        fence: Option<&native::Fence>,
     ) -> Result<hal::SwapImageIndex, hal::AcquireError> {
-        // TODO: sync
-        Ok(0)
+        if is_context_lost(self.reset_status) {
+            return Err(hal::AcquireError::SurfaceLost(hal::device::SurfaceLost));
+        }
+
+        // The swapchain images have to be reallocated once the window got resized
+        // or moved to a monitor with a different scale factor. The swapchain
+        // extent itself may differ from the window size within `extents`.
+        let extent = hal::window::Extent2D::from(get_window_extent(&self.window));
+        if extent != self.window_extent || self.window.get_hidpi_factor() != self.scale_factor {
+            return Err(hal::AcquireError::OutOfDate);
+        }
+
+        let index = self.next_image;
+
+        // Wait until the previous presentation of this image has finished.
+        // A timeout of 0 only polls, while `u64::MAX` blocks indefinitely.
+        if let Some(presented) = self.presented.get(index as usize) {
+            if !presented.0.get().is_null() {
+                loop {
+                    match device::wait_fence(presented, &self.share, timeout_ns) {
+                        // Drivers may clamp the timeout, keep waiting.
+                        gl::TIMEOUT_EXPIRED if timeout_ns == !0 => continue,
+                        gl::TIMEOUT_EXPIRED => return Err(hal::AcquireError::NotReady),
+                        gl::WAIT_FAILED => {
+                            if let Err(err) = self.share.check() {
+                                error!("Error when waiting on swapchain image: {:?}", err);
+                            }
+                        }
+                        _ => (),
+                    }
+                    break;
+                }
+            }
+        }
+
+        self.next_image = (index + 1) % self.fbos.len().max(1) as hal::SwapImageIndex;
+
+        if let Some(semaphore) = semaphore {
+            device::signal_sync(&self.share, &semaphore.0);
+        }
+        if let Some(fence) = fence {
+            device::signal_sync(&self.share, &fence.0);
+        }
+
+        Ok(index)
     }
 }
 
 //TODO: if we make `Surface` a `WindowBuilder` instead of `WindowedContext`,
 // we could spawn window + GL context when a swapchain is requested
 // and actually respect the swapchain configuration provided by the user.
+// A `from_window_builder` constructor can't defer this with the current API:
+// - `Surface` is also the `Instance`, and `enumerate_adapters` queries the
+//   live context, which happens before `compatibility` or `create_swapchain`.
+// - `build_windowed` needs `&EventsLoop`. The surface can't borrow it, as
+//   `Instance` requires `Any` (so `'static`), and it can't own it either,
+//   since the application keeps polling it.
+// Use `recreate_window`, which takes the event loop as an argument, to apply
+// the formats chosen for the swapchain instead.
+/// Window surface, also acting as the instance.
+///
+/// The HAL requires surfaces to be `Send + Sync`, but the wrapped GL context
+/// is bound to the thread that created the surface: glutin can't release it
+/// from that thread. Moving the surface to another thread is allowed, but
+/// using it there (or any adapter, device, or swapchain created from it)
+/// panics. Use `is_local` to check whether the surface can be used from the
+/// current thread.
 pub struct Surface {
     window: Starc<glutin::WindowedContext>,
+    // Queried once, as the pixel format can't change during the lifetime
+    // of the context.
+    pixel_format: glutin::PixelFormat,
+    swap_interval: Starc<SwapInterval>,
+    present_timing: Starc<PresentTiming>,
+    // Window of the context this surface's context shares objects with
+    shared_context: Option<Starc<glutin::WindowedContext>>,
+    // Size of the window before going fullscreen
+    windowed_size: Starc<Cell<Option<glutin::dpi::LogicalSize>>>,
+    // Whether the user can resize the window, which winit can't be queried for
+    resizable: Starc<Cell<bool>>,
+    reset_status: Option<GetGraphicsResetStatusFn>,
 }
 
 impl Surface {
     pub fn from_window(window: glutin::WindowedContext) -> Self {
+        let pixel_format = window.get_pixel_format();
+        let swap_interval = Starc::new(SwapInterval::load(&window));
+        let reset_status = load_reset_status(&window);
         Surface {
             window: Starc::new(window),
+            pixel_format,
+            swap_interval,
+            present_timing: Starc::new(PresentTiming::new()),
+            shared_context: None,
+            windowed_size: Starc::new(Cell::new(None)),
+            resizable: Starc::new(Cell::new(true)),
+            reset_status,
+        }
+    }
+
+    /// Create a surface for another window, whose context shares objects with
+    /// the context of `surface` (see `ContextBuilder::with_shared_lists`).
+    ///
+    /// Swapchains of the new surface are created from the devices of
+    /// `surface`, each with its own images and extent. Presenting to them
+    /// switches to the context of the window and back.
+    pub fn from_shared_window(window: glutin::WindowedContext, surface: &Surface) -> Self {
+        let context = surface
+            .shared_context
+            .clone()
+            .unwrap_or_else(|| surface.window.clone());
+        // Entry points are loaded from the current context.
+        if let Err(err) = unsafe { window.make_current() } {
+            warn!("Failed to make the context current: {:?}", err);
+        }
+        let mut shared = Surface::from_window(window);
+        if let Err(err) = unsafe { context.make_current() } {
+            warn!("Failed to make the context current: {:?}", err);
+        }
+        shared.shared_context = Some(context);
+        shared
+    }
+
+    /// Check if the surface can be used from the current thread, which is
+    /// only the case on the thread that created it.
+    pub fn is_local(&self) -> bool {
+        Starc::is_local(&self.window)
+    }
+
+    /// Enable or disable vsync, taking effect at the next presentation.
+    ///
+    /// Fails if the platform doesn't allow controlling the swap interval.
+    pub fn set_vsync(&self, enabled: bool) -> Result<(), ()> {
+        if self.swap_interval.func.is_none() {
+            return Err(());
+        }
+        let interval = if enabled { 1 } else { 0 };
+        self.swap_interval.requested.set(interval);
+        Ok(())
+    }
+
+    /// Get the swap interval used for presenting, `0` meaning no vsync and
+    /// `-1` adaptive vsync.
+    pub fn swap_interval(&self) -> i32 {
+        self.swap_interval.requested.get()
+    }
+
+    /// Get the time spent swapping buffers for the last presentation,
+    /// or `None` if nothing was presented yet.
+    pub fn last_present_duration(&self) -> Option<Duration> {
+        self.present_stats()
+            .map(|stats| stats.last_present_duration)
+    }
+
+    /// Get frame pacing statistics of the presentations to this surface,
+    /// or `None` if nothing was presented yet.
+    pub fn present_stats(&self) -> Option<PresentStats> {
+        self.present_timing.stats.get()
+    }
+
+    /// Get the current scale factor of the window, which may change when
+    /// moving it between monitors.
+    pub fn scale_factor(&self) -> f64 {
+        self.window.get_hidpi_factor()
+    }
+
+    /// Make the window fullscreen on the given monitor, or return to windowed
+    /// mode with the size it had before going fullscreen.
+    ///
+    /// The window covers the monitor at its current video mode, winit doesn't
+    /// allow enumerating or switching video modes. Acquiring images from
+    /// existing swapchains fails with `OutOfDate` once the window got resized,
+    /// the new extent is reported by `compatibility`.
+    pub fn set_fullscreen(&self, monitor: Option<glutin::MonitorId>) {
+        match monitor {
+            Some(monitor) => {
+                if self.windowed_size.get().is_none() {
+                    self.windowed_size.set(self.window.get_inner_size());
+                }
+                self.window.set_fullscreen(Some(monitor));
+            }
+            None => {
+                self.window.set_fullscreen(None);
+                if let Some(size) = self.windowed_size.take() {
+                    self.window.set_inner_size(size);
+                }
+            }
         }
     }
 
+    /// Allow or prevent resizing the window by the user.
+    ///
+    /// Fixed-size windows only support their current extent, as reported by
+    /// `compatibility`. Windows built with `WindowBuilder::with_resizable(false)`
+    /// have to be declared through this as well, winit can't be queried for it.
+    pub fn set_resizable(&self, resizable: bool) {
+        self.window.set_resizable(resizable);
+        self.resizable.set(resizable);
+    }
+
+    /// Check if the window was made fullscreen with `set_fullscreen`.
+    pub fn is_fullscreen(&self) -> bool {
+        self.windowed_size.get().is_some()
+    }
+
+    /// Apply new color, depth-stencil and multisampling settings, replacing
+    /// the window if its pixel format has to change.
+    ///
+    /// Returns `true` if the current window and context could be kept, or if
+    /// the surface was created with `from_shared_window`, in which case the new
+    /// context shares objects with the same context as before. Otherwise
+    /// adapters and devices created from this surface, along with all their
+    /// resources, are lost and have to be recreated.
+    ///
+    /// The pixel format of a window can't be changed once set, and glutin
+    /// can't create a context for an existing window, so a **new window** is
+    /// built from `window_builder` at the same position and size, with
+    /// `context_builder` configured as by `config_context`. The new window has
+    /// a different `WindowId`: events of the old window are no longer
+    /// delivered for this surface. The old window stays open as long as
+    /// swapchains created before still hold it, so they have to be dropped
+    /// and recreated.
+    pub fn recreate_window(
+        &mut self,
+        events_loop: &glutin::EventsLoop,
+        window_builder: glutin::WindowBuilder,
+        context_builder: glutin::ContextBuilder,
+        color_format: f::Format,
+        ds_format: Option<f::Format>,
+        samples: u16,
+    ) -> Result<bool, glutin::CreationError> {
+        let color_base = color_format.base_format();
+        let color_bits = color_base.0.describe_bits();
+        let depth_bits = match ds_format {
+            Some(fm) => fm.base_format().0.describe_bits(),
+            None => f::BITS_ZERO,
+        };
+        let multisampling = if samples > 1 { Some(samples) } else { None };
+        let pf = &self.pixel_format;
+        if pf.color_bits == color_bits.color
+            && pf.alpha_bits == color_bits.alpha
+            && pf.depth_bits == depth_bits.depth
+            && pf.stencil_bits == depth_bits.stencil
+            && pf.srgb == (color_base.1 == f::ChannelType::Srgb)
+            && pf.multisampling == multisampling
+        {
+            return Ok(true);
+        }
+
+        let window_builder = match self.window.get_inner_size() {
+            Some(size) => window_builder.with_dimensions(size),
+            None => window_builder,
+        };
+        let context_builder =
+            config_context(context_builder, color_format, ds_format).with_multisampling(samples);
+        let context_builder = match self.shared_context {
+            Some(ref context) => context_builder.with_shared_lists(context.context()),
+            None => context_builder,
+        };
+        let window = context_builder.build_windowed(window_builder, events_loop)?;
+        if let Some(position) = self.window.get_position() {
+            window.set_position(position);
+        }
+        window.set_resizable(self.resizable.get());
+
+        // Entry points are loaded from the current context.
+        if let Err(err) = unsafe { window.make_current() } {
+            warn!("Failed to make the context current: {:?}", err);
+        }
+        let swap_interval = SwapInterval::load(&window);
+        swap_interval
+            .requested
+            .set(self.swap_interval.requested.get());
+        self.pixel_format = window.get_pixel_format();
+        self.reset_status = load_reset_status(&window);
+        self.swap_interval = Starc::new(swap_interval);
+        self.present_timing = Starc::new(PresentTiming::new());
+        self.windowed_size = Starc::new(Cell::new(None));
+        self.window = Starc::new(window);
+        if let Some(ref context) = self.shared_context {
+            if let Err(err) = unsafe { context.make_current() } {
+                warn!("Failed to make the context current: {:?}", err);
+            }
+        }
+        Ok(self.shared_context.is_some())
+    }
+
+    /// Enumerate the adapters, skipping software renderers such as llvmpipe.
+    pub fn enumerate_hardware_adapters(&self) -> Vec<hal::Adapter<B>> {
+        use hal::Instance;
+        filter_software_adapters(self.enumerate_adapters())
+    }
+
+    /// Check if the context has been lost, e.g. after a GPU reset.
+    ///
+    /// Requires the context to be created with robustness enabled, otherwise
+    /// the driver may never report a reset.
+    pub fn is_context_lost(&self) -> bool {
+        is_context_lost(self.reset_status)
+    }
+
     pub fn get_window(&self) -> &glutin::WindowedContext {
         &*self.window
     }
@@ -107,17 +662,55 @@ impl Surface {
         &self.window
     }
 
+    /// Get the format of the depth and stencil buffers of the default
+    /// framebuffer, if any.
+    pub fn depth_stencil_format(&self) -> Option<f::Format> {
+        match (self.pixel_format.depth_bits, self.pixel_format.stencil_bits) {
+            (16, 0) => Some(f::Format::D16Unorm),
+            (24, 0) => Some(f::Format::X8D24Unorm),
+            (32, 0) => Some(f::Format::D32Sfloat),
+            (16, 8) => Some(f::Format::D16UnormS8Uint),
+            (24, 8) => Some(f::Format::D24UnormS8Uint),
+            (32, 8) => Some(f::Format::D32SfloatS8Uint),
+            _ => None,
+        }
+    }
+
     fn swapchain_formats(&self) -> Vec<f::Format> {
-        let pixel_format = self.window.get_pixel_format();
+        let pixel_format = &self.pixel_format;
         let color_bits = pixel_format.color_bits;
         let alpha_bits = pixel_format.alpha_bits;
         let srgb = pixel_format.srgb;
 
-        // TODO: expose more formats
-        match (color_bits, alpha_bits, srgb) {
-            (24, 8, true) => vec![f::Format::Rgba8Srgb, f::Format::Bgra8Srgb],
-            (24, 8, false) => vec![f::Format::Rgba8Unorm, f::Format::Bgra8Unorm],
-            _ => vec![],
+        // Swapchain images are copied as-is onto the default framebuffer, so
+        // both sRGB and linear formats can be exposed, preferring the one
+        // matching the pixel format.
+        let formats_8bit = if srgb {
+            vec![
+                f::Format::Rgba8Srgb,
+                f::Format::Bgra8Srgb,
+                f::Format::Rgba8Unorm,
+                f::Format::Bgra8Unorm,
+            ]
+        } else {
+            vec![
+                f::Format::Rgba8Unorm,
+                f::Format::Bgra8Unorm,
+                f::Format::Rgba8Srgb,
+                f::Format::Bgra8Srgb,
+            ]
+        };
+
+        match (color_bits, alpha_bits) {
+            (30, 2) => vec![f::Format::A2b10g10r10Unorm, f::Format::Rgba8Unorm],
+            (48, 16) => vec![
+                f::Format::Rgba16Sfloat,
+                f::Format::Rgba16Unorm,
+                f::Format::Rgba8Unorm,
+            ],
+            // Fall back to the closest 8-bit format, the swapchain images are
+            // blitted onto the default framebuffer anyway.
+            _ => formats_8bit,
         }
     }
 }
@@ -125,13 +718,13 @@ impl Surface {
 impl hal::Surface<B> for Surface {
     fn kind(&self) -> hal::image::Kind {
         let ex = get_window_extent(&self.window);
-        let samples = self.window.get_pixel_format().multisampling.unwrap_or(1);
+        let samples = self.pixel_format.multisampling.unwrap_or(1);
         hal::image::Kind::D2(ex.width, ex.height, 1, samples as _)
     }
 
     fn compatibility(
         &self,
-        _: &PhysicalDevice,
+        physical_device: &PhysicalDevice,
     ) -> (
         hal::SurfaceCapabilities,
         Option<Vec<f::Format>>,
@@ -139,25 +732,53 @@ impl hal::Surface<B> for Surface {
     ) {
         let ex = get_window_extent(&self.window);
         let extent = hal::window::Extent2D::from(ex);
+        // The end of `extents` is exclusive.
+        let extents = if self.resizable.get() {
+            // The window can be resized up to the size of its monitor.
+            let monitor = self.window.get_current_monitor().get_dimensions();
+            hal::window::Extent2D {
+                width: 1,
+                height: 1,
+            }..hal::window::Extent2D {
+                width: ex.width.max(monitor.width as image::Size) + 1,
+                height: ex.height.max(monitor.height as image::Size) + 1,
+            }
+        } else {
+            extent..hal::window::Extent2D {
+                width: extent.width + 1,
+                height: extent.height + 1,
+            }
+        };
 
         let caps = hal::SurfaceCapabilities {
-            image_count: if self.window.get_pixel_format().double_buffer {
-                2..3
+            // GL doesn't expose the number of buffers of the default
+            // framebuffer, but swapchain images are allocated by us anyway.
+            image_count: if self.pixel_format.double_buffer {
+                2..MAX_IMAGE_COUNT + 1
             } else {
                 1..2
             },
             current_extent: Some(extent),
-            extents: extent..hal::window::Extent2D {
-                width: ex.width + 1,
-                height: ex.height + 1,
-            },
+            extents,
             max_image_layers: 1,
             usage: image::Usage::COLOR_ATTACHMENT | image::Usage::TRANSFER_SRC,
-            composite_alpha: CompositeAlpha::OPAQUE, //TODO
+            composite_alpha: if self.pixel_format.alpha_bits > 0 {
+                CompositeAlpha::OPAQUE
+                    | CompositeAlpha::PREMULTIPLIED
+                    | CompositeAlpha::POSTMULTIPLIED
+            } else {
+                CompositeAlpha::OPAQUE
+            },
         };
-        let present_modes = vec![
-            hal::PresentMode::Fifo, //TODO
-        ];
+        let mut present_modes = vec![hal::PresentMode::Fifo];
+        if self.swap_interval.func.is_some() {
+            present_modes.push(hal::PresentMode::Immediate);
+            if physical_device.0.private_caps.swap_control_tear {
+                present_modes.push(hal::PresentMode::Relaxed);
+            }
+        }
+        // Mailbox isn't supported: presenting always waits on the swap
+        // interval, there is no way to replace a queued image.
 
         (caps, Some(self.swapchain_formats()), present_modes)
     }
@@ -168,28 +789,304 @@ impl hal::Surface<B> for Surface {
 }
 
 impl Device {
-    pub(crate) fn create_swapchain_impl(
+    pub(crate) unsafe fn create_swapchain_impl(
         &self,
         surface: &mut Surface,
-        _config: hal::SwapchainConfig,
-    ) -> (Swapchain, hal::Backbuffer<B>) {
+        config: hal::SwapchainConfig,
+    ) -> Result<(Swapchain, hal::Backbuffer<B>), hal::window::CreationError> {
+        let gl = &self.share.context;
+
+        // Keep the default framebuffer in sync with the window, which is
+        // required on some platforms after a resize.
+        surface.window.resize(glutin::dpi::PhysicalSize::new(
+            config.extent.width as f64,
+            config.extent.height as f64,
+        ));
+
+        let interval = match config.present_mode {
+            hal::PresentMode::Immediate => 0,
+            hal::PresentMode::Relaxed => -1,
+            hal::PresentMode::Fifo => 1,
+            mode => {
+                warn!(
+                    "Present mode {:?} isn't supported, falling back to Fifo",
+                    mode
+                );
+                1
+            }
+        };
+        surface.swap_interval.requested.set(interval);
+
+        let native_formats = surface.swapchain_formats();
+        if !is_native_format(config.format, &native_formats) {
+            warn!(
+                "Swapchain format {:?} doesn't match the native format {:?} of the window",
+                config.format,
+                native_formats.first(),
+            );
+        }
+
+        let mut fbos = Vec::new();
+        let mut renderbuffers = Vec::new();
+        let mut resolve = None;
+
+        let backbuffer = match conv::image_format_to_gl(config.format) {
+            Some((int_format, _, _)) if self.share.private_caps.framebuffer => {
+                let channel = config.format.base_format().1;
+                let bytes_per_texel = config.format.base_format().0.desc().bits as u64 / 8;
+                let size = (config.extent.width * config.extent.height) as u64 * bytes_per_texel;
+
+                // Match the sample count reported by `Surface::kind`.
+                let max_samples = self.share.private_caps.max_samples;
+                let mut samples = surface.pixel_format.multisampling.unwrap_or(1) as u32;
+                if samples > max_samples {
+                    warn!(
+                        "Swapchain sample count {} exceeds GL_MAX_SAMPLES ({})",
+                        samples, max_samples
+                    );
+                    samples = max_samples;
+                }
+
+                let image_count = config.image_count.min(MAX_IMAGE_COUNT);
+                let mut images = Vec::with_capacity(image_count as usize);
+                let mut status = gl::FRAMEBUFFER_COMPLETE;
+                for _ in 0..image_count {
+                    let fbo = device::create_fbo_internal(&self.share).unwrap();
+                    let mut rbo = 0;
+                    gl.GenRenderbuffers(1, &mut rbo);
+                    gl.BindRenderbuffer(gl::RENDERBUFFER, rbo);
+                    if samples > 1 {
+                        gl.RenderbufferStorageMultisample(
+                            gl::RENDERBUFFER,
+                            samples as _,
+                            int_format,
+                            config.extent.width as _,
+                            config.extent.height as _,
+                        );
+                    } else {
+                        gl.RenderbufferStorage(
+                            gl::RENDERBUFFER,
+                            int_format,
+                            config.extent.width as _,
+                            config.extent.height as _,
+                        );
+                    }
+                    gl.BindFramebuffer(gl::READ_FRAMEBUFFER, fbo);
+                    gl.FramebufferRenderbuffer(
+                        gl::READ_FRAMEBUFFER,
+                        gl::COLOR_ATTACHMENT0,
+                        gl::RENDERBUFFER,
+                        rbo,
+                    );
+                    fbos.push(fbo);
+                    renderbuffers.push(rbo);
+
+                    status = gl.CheckFramebufferStatus(gl::READ_FRAMEBUFFER);
+                    if status != gl::FRAMEBUFFER_COMPLETE {
+                        break;
+                    }
+
+                    images.push(native::Image {
+                        kind: native::ImageKind::Surface(rbo),
+                        format: config.format,
+                        channel,
+                        requirements: hal::memory::Requirements {
+                            size,
+                            alignment: 1,
+                            type_mask: 0x7,
+                        },
+                    });
+                }
+
+                gl.BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+                gl.BindRenderbuffer(gl::RENDERBUFFER, 0);
+
+                if status != gl::FRAMEBUFFER_COMPLETE {
+                    error!(
+                        "Incomplete swapchain framebuffer ({}) with color attachment {:?}",
+                        framebuffer_status_name(status),
+                        config.format,
+                    );
+                    gl.DeleteFramebuffers(fbos.len() as _, fbos.as_ptr());
+                    gl.DeleteRenderbuffers(renderbuffers.len() as _, renderbuffers.as_ptr());
+                    return Err(hal::device::SurfaceLost.into());
+                }
+
+                // Multisampled blits can't flip the image.
+                if samples > 1 {
+                    let fbo = device::create_fbo_internal(&self.share).unwrap();
+                    let mut rbo = 0;
+                    gl.GenRenderbuffers(1, &mut rbo);
+                    gl.BindRenderbuffer(gl::RENDERBUFFER, rbo);
+                    gl.RenderbufferStorage(
+                        gl::RENDERBUFFER,
+                        int_format,
+                        config.extent.width as _,
+                        config.extent.height as _,
+                    );
+                    gl.BindFramebuffer(gl::READ_FRAMEBUFFER, fbo);
+                    gl.FramebufferRenderbuffer(
+                        gl::READ_FRAMEBUFFER,
+                        gl::COLOR_ATTACHMENT0,
+                        gl::RENDERBUFFER,
+                        rbo,
+                    );
+                    gl.BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+                    gl.BindRenderbuffer(gl::RENDERBUFFER, 0);
+                    resolve = Some((fbo, rbo));
+                }
+
+                if let Err(err) = self.share.check() {
+                    error!("Error creating swapchain images: {:?}", err);
+                    gl.DeleteFramebuffers(fbos.len() as _, fbos.as_ptr());
+                    gl.DeleteRenderbuffers(renderbuffers.len() as _, renderbuffers.as_ptr());
+                    if let Some((fbo, rbo)) = resolve {
+                        gl.DeleteFramebuffers(1, &fbo);
+                        gl.DeleteRenderbuffers(1, &rbo);
+                    }
+                    return Err(swapchain_error(err));
+                }
+
+                hal::Backbuffer::Images(images)
+            }
+            // Rendering directly into the default framebuffer would show the
+            // image upside down, as images are stored with the top row first.
+            _ => {
+                error!(
+                    "Can't allocate swapchain images of format {:?}",
+                    config.format
+                );
+                return Err(hal::device::SurfaceLost.into());
+            }
+        };
+
+        // Framebuffers aren't shared between contexts, the window's context
+        // needs its own ones to read the images from.
+        let shared = match surface.shared_context {
+            Some(ref context) => {
+                gl.Finish();
+                if let Err(err) = surface.window.make_current() {
+                    error!("Failed to make the window's context current: {:?}", err);
+                    return Err(hal::device::SurfaceLost.into());
+                }
+                let attach = |rbo: native::Surface| {
+                    let fbo = device::create_fbo_internal(&self.share).unwrap();
+                    gl.BindFramebuffer(gl::READ_FRAMEBUFFER, fbo);
+                    gl.FramebufferRenderbuffer(
+                        gl::READ_FRAMEBUFFER,
+                        gl::COLOR_ATTACHMENT0,
+                        gl::RENDERBUFFER,
+                        rbo,
+                    );
+                    fbo
+                };
+                let shared_fbos = renderbuffers.iter().map(|&rbo| attach(rbo)).collect();
+                let shared_resolve = resolve.map(|(_, rbo)| attach(rbo));
+                gl.BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+                if let Err(err) = context.make_current() {
+                    error!("Failed to make the context current: {:?}", err);
+                    return Err(hal::device::SurfaceLost.into());
+                }
+                Some(SharedPresent {
+                    context: context.clone(),
+                    fbos: shared_fbos,
+                    resolve: shared_resolve,
+                })
+            }
+            None => None,
+        };
+
+        let presented = fbos
+            .iter()
+            .map(|_| native::Fence::new(ptr::null()))
+            .collect();
+
         let swapchain = Swapchain {
             window: surface.window.clone(),
+            share: self.share.clone(),
+            extent: config.extent,
+            window_extent: hal::window::Extent2D::from(get_window_extent(&surface.window)),
+            scale_factor: surface.scale_factor(),
+            fbos,
+            renderbuffers,
+            resolve,
+            presented,
+            next_image: 0,
+            composite_alpha: config.composite_alpha,
+            has_alpha: surface.pixel_format.alpha_bits > 0,
+            swap_interval: surface.swap_interval.clone(),
+            present_timing: surface.present_timing.clone(),
+            shared,
+            reset_status: surface.reset_status,
         };
-        let backbuffer = hal::Backbuffer::Framebuffer(0);
-        (swapchain, backbuffer)
+        Ok((swapchain, backbuffer))
+    }
+}
+
+/// Check if `format` is the preferred format among those supported by a
+/// surface, and thus doesn't require any conversion of the pixel values.
+fn is_native_format(format: f::Format, supported: &[f::Format]) -> bool {
+    supported.first() == Some(&format)
+}
+
+/// Map a GL error raised while creating swapchain images.
+///
+/// HAL has no error for swapchain configurations the context can't back,
+/// these are reported as a lost surface, as for incomplete framebuffers.
+fn swapchain_error(err: crate::Error) -> hal::window::CreationError {
+    match err {
+        crate::Error::OutOfMemory => hal::device::OutOfMemory::OutOfDeviceMemory.into(),
+        _ => hal::device::SurfaceLost.into(),
     }
 }
 
+fn framebuffer_status_name(status: gl::types::GLenum) -> &'static str {
+    match status {
+        gl::FRAMEBUFFER_UNDEFINED => "GL_FRAMEBUFFER_UNDEFINED",
+        gl::FRAMEBUFFER_INCOMPLETE_ATTACHMENT => "GL_FRAMEBUFFER_INCOMPLETE_ATTACHMENT",
+        gl::FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT => {
+            "GL_FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT"
+        }
+        gl::FRAMEBUFFER_INCOMPLETE_MULTISAMPLE => "GL_FRAMEBUFFER_INCOMPLETE_MULTISAMPLE",
+        gl::FRAMEBUFFER_UNSUPPORTED => "GL_FRAMEBUFFER_UNSUPPORTED",
+        _ => "unknown framebuffer status",
+    }
+}
+
+fn filter_software_adapters(adapters: Vec<hal::Adapter<B>>) -> Vec<hal::Adapter<B>> {
+    adapters
+        .into_iter()
+        .filter(|adapter| {
+            let software = adapter.info.device_type == hal::adapter::DeviceType::Cpu;
+            if software {
+                info!("Skipping software adapter {:?}", adapter.info.name);
+            }
+            !software
+        })
+        .collect()
+}
+
 impl hal::Instance for Surface {
     type Backend = B;
     fn enumerate_adapters(&self) -> Vec<hal::Adapter<B>> {
-        unsafe { self.window.make_current().unwrap() };
+        if let Err(err) = unsafe { self.window.make_current() } {
+            warn!("Failed to make the context current: {:?}", err);
+            return Vec::new();
+        }
+        if self.window.get_proc_address("glGetString").is_null() {
+            warn!("Failed to load GL entry points");
+            return Vec::new();
+        }
         let adapter = PhysicalDevice::new_adapter(|s| self.window.get_proc_address(s) as *const _);
         vec![adapter]
     }
 }
 
+/// Configure the context builder to match the given color and depth-stencil formats.
+///
+/// Error checks are only done in debug builds. Contexts created with
+/// `GL_KHR_no_error` by other means are detected and never checked, glutin
+/// doesn't expose the corresponding context flag.
 pub fn config_context(
     builder: glutin::ContextBuilder,
     color_format: f::Format,
@@ -208,16 +1105,111 @@ pub fn config_context(
         .with_srgb(color_base.1 == f::ChannelType::Srgb)
 }
 
+/// Same as `config_context`, but also requests a robust context, losing the
+/// context on GPU resets (see `Surface::is_context_lost`).
+///
+/// Falls back to a regular context if robustness isn't supported, check
+/// `PhysicalDevice::robustness` for the outcome.
+pub fn config_context_robust(
+    builder: glutin::ContextBuilder,
+    color_format: f::Format,
+    ds_format: Option<f::Format>,
+) -> glutin::ContextBuilder {
+    config_context(builder, color_format, ds_format)
+        .with_gl_robustness(glutin::Robustness::TryRobustLoseContextOnReset)
+}
+
+/// Same as `config_context`, but also requests a specific API version and,
+/// for desktop GL, profile.
+pub fn config_context_version(
+    builder: glutin::ContextBuilder,
+    color_format: f::Format,
+    ds_format: Option<f::Format>,
+    api: glutin::Api,
+    version: (u8, u8),
+    profile: Option<glutin::GlProfile>,
+) -> glutin::ContextBuilder {
+    let builder = config_context(builder, color_format, ds_format)
+        .with_gl(glutin::GlRequest::Specific(api, version));
+    match profile {
+        Some(profile) => builder.with_gl_profile(profile),
+        None => builder,
+    }
+}
+
+/// Headless instance wrapping an offscreen GL context.
+///
+/// A GL context is bound to a single renderer chosen by the driver, so only
+/// one adapter is ever enumerated. Selecting a specific GPU on multi-GPU
+/// systems has to happen when creating the context (e.g. `DRI_PRIME` or the
+/// driver's application profiles).
 pub struct Headless(pub glutin::Context);
 
 unsafe impl Send for Headless {}
 unsafe impl Sync for Headless {}
 
+impl Headless {
+    /// Create a headless context sharing objects with `context`, e.g. the
+    /// context of a surface (`surface.window().context()`).
+    ///
+    /// Buffers, images, samplers and fences created by devices of either
+    /// context can be used by the devices of the other one, while container
+    /// objects like framebuffers are created per context. The new context can
+    /// be moved to another thread, for example to upload resources there,
+    /// but has to be used on a single thread from its first use on.
+    pub fn new_shared(
+        events_loop: &glutin::EventsLoop,
+        builder: glutin::ContextBuilder,
+        context: &glutin::Context,
+    ) -> Result<Self, glutin::CreationError> {
+        let builder = builder.with_shared_lists(context);
+        glutin::Context::new_headless(
+            events_loop,
+            builder,
+            glutin::dpi::PhysicalSize::new(0.0, 0.0),
+        )
+        .map(Headless)
+    }
+
+    /// Enumerate the adapters, skipping software renderers such as llvmpipe.
+    pub fn enumerate_hardware_adapters(&self) -> Vec<hal::Adapter<B>> {
+        use hal::Instance;
+        filter_software_adapters(self.enumerate_adapters())
+    }
+}
+
 impl hal::Instance for Headless {
     type Backend = B;
     fn enumerate_adapters(&self) -> Vec<hal::Adapter<B>> {
-        unsafe { self.0.make_current().unwrap() };
+        if let Err(err) = unsafe { self.0.make_current() } {
+            warn!("Failed to make the context current: {:?}", err);
+            return Vec::new();
+        }
+        if self.0.get_proc_address("glGetString").is_null() {
+            warn!("Failed to load GL entry points");
+            return Vec::new();
+        }
         let adapter = PhysicalDevice::new_adapter(|s| self.0.get_proc_address(s) as *const _);
         vec![adapter]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::is_native_format;
+    use hal::format::Format;
+
+    #[test]
+    fn test_is_native_format() {
+        let supported = [
+            Format::Rgba8Srgb,
+            Format::Bgra8Srgb,
+            Format::Rgba8Unorm,
+            Format::Bgra8Unorm,
+        ];
+        assert!(is_native_format(Format::Rgba8Srgb, &supported));
+        assert!(!is_native_format(Format::Rgba8Unorm, &supported));
+        assert!(!is_native_format(Format::Rgba16Sfloat, &supported));
+        assert!(!is_native_format(Format::Rgba8Srgb, &[]));
+    }
+}
@@ -1,2 +1,3 @@
 #[cfg(feature = "glutin")]
 pub mod glutin;
+pub mod offscreen;
@@ -0,0 +1,199 @@
+//! Offscreen rendering without a window.
+//!
+//! An `OffscreenSurface` owns a texture of a fixed size which can be
+//! rendered to through HAL like any other image, and read back to host
+//! memory, e.g. for comparing against reference images.
+
+use hal::{self, format as f, image, memory, window::Extent2D, CompositeAlpha};
+
+use crate::{
+    conv, device, gl, native, Backend as B, Device, PhysicalDevice, QueueFamily, Share, Starc,
+};
+
+use std::ptr;
+
+pub struct OffscreenSurface {
+    share: Starc<Share>,
+    extent: Extent2D,
+    format: f::Format,
+    image: native::Image,
+    // Framebuffer with `image` attached, used for reading back
+    fbo: native::FrameBuffer,
+}
+
+impl OffscreenSurface {
+    /// Create a new offscreen surface of the given size and format.
+    pub fn new(
+        device: &Device,
+        extent: Extent2D,
+        format: f::Format,
+    ) -> Result<Self, image::CreationError> {
+        let share = &device.share;
+        let gl = &share.context;
+
+        let (int_format, iformat, itype) = match conv::image_format_to_gl(format) {
+            Some(gl_format) if share.private_caps.framebuffer => gl_format,
+            _ => return Err(image::CreationError::Format(format)),
+        };
+
+        let mut texture = 0;
+        let fbo = unsafe {
+            gl.GenTextures(1, &mut texture);
+            gl.BindTexture(gl::TEXTURE_2D, texture);
+            gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, 0);
+            gl.TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                int_format as _,
+                extent.width as _,
+                extent.height as _,
+                0,
+                iformat,
+                itype,
+                ptr::null(),
+            );
+            gl.BindTexture(gl::TEXTURE_2D, 0);
+
+            let fbo = device::create_fbo_internal(share).unwrap();
+            gl.BindFramebuffer(gl::READ_FRAMEBUFFER, fbo);
+            gl.FramebufferTexture2D(
+                gl::READ_FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::TEXTURE_2D,
+                texture,
+                0,
+            );
+            let status = gl.CheckFramebufferStatus(gl::READ_FRAMEBUFFER);
+            gl.BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+
+            if status != gl::FRAMEBUFFER_COMPLETE {
+                error!("Incomplete offscreen framebuffer for {:?}", format);
+                gl.DeleteFramebuffers(1, &fbo);
+                gl.DeleteTextures(1, &texture);
+                return Err(image::CreationError::Format(format));
+            }
+            fbo
+        };
+
+        if let Err(err) = share.check() {
+            error!("Error creating offscreen surface: {:?}", err);
+            unsafe {
+                gl.DeleteFramebuffers(1, &fbo);
+                gl.DeleteTextures(1, &texture);
+            }
+            return Err(match err {
+                crate::Error::OutOfMemory => {
+                    image::CreationError::OutOfMemory(hal::device::OutOfMemory::OutOfDeviceMemory)
+                }
+                crate::Error::InvalidValue => {
+                    image::CreationError::Size(extent.width.max(extent.height))
+                }
+                _ => image::CreationError::Format(format),
+            });
+        }
+
+        let bytes_per_texel = format.base_format().0.desc().bits as u64 / 8;
+        let type_count = share.memory_types().len();
+        let image = native::Image {
+            kind: native::ImageKind::Texture(texture),
+            format,
+            channel: format.base_format().1,
+            requirements: memory::Requirements {
+                size: (extent.width * extent.height) as u64 * bytes_per_texel,
+                alignment: 1,
+                type_mask: (1 << type_count) - 1,
+            },
+        };
+
+        Ok(OffscreenSurface {
+            share: share.clone(),
+            extent,
+            format,
+            image,
+            fbo,
+        })
+    }
+
+    /// Get the image rendered to, for creating image views and framebuffers.
+    pub fn image(&self) -> &native::Image {
+        &self.image
+    }
+
+    /// Read back the current content of the surface, tightly packed, with
+    /// the channels ordered as in the surface format and the top row first.
+    ///
+    /// Waits for all pending rendering to complete.
+    pub fn read_pixels(&self) -> Vec<u8> {
+        let gl = &self.share.context;
+        let (_, iformat, itype) = conv::image_format_to_gl(self.format).unwrap();
+        let bytes_per_texel = self.format.base_format().0.desc().bits as usize / 8;
+        let mut data =
+            vec![0u8; self.extent.width as usize * self.extent.height as usize * bytes_per_texel];
+
+        unsafe {
+            gl.BindFramebuffer(gl::READ_FRAMEBUFFER, self.fbo);
+            gl.PixelStorei(gl::PACK_ALIGNMENT, 1);
+            gl.ReadPixels(
+                0,
+                0,
+                self.extent.width as _,
+                self.extent.height as _,
+                iformat,
+                itype,
+                data.as_mut_ptr() as *mut _,
+            );
+            gl.BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+        }
+
+        if let Err(err) = self.share.check() {
+            error!("Error reading offscreen surface: {:?}", err);
+        }
+
+        data
+    }
+
+    /// Destroy the surface and its image.
+    pub fn destroy(self) {
+        let gl = &self.share.context;
+        unsafe {
+            gl.DeleteFramebuffers(1, &self.fbo);
+            if let native::ImageKind::Texture(texture) = self.image.kind {
+                gl.DeleteTextures(1, &texture);
+            }
+        }
+    }
+}
+
+impl hal::Surface<B> for OffscreenSurface {
+    fn kind(&self) -> image::Kind {
+        image::Kind::D2(self.extent.width, self.extent.height, 1, 1)
+    }
+
+    fn compatibility(
+        &self,
+        _: &PhysicalDevice,
+    ) -> (
+        hal::SurfaceCapabilities,
+        Option<Vec<f::Format>>,
+        Vec<hal::PresentMode>,
+    ) {
+        let caps = hal::SurfaceCapabilities {
+            image_count: 1..2,
+            current_extent: Some(self.extent),
+            // The end of `extents` is exclusive.
+            extents: self.extent..Extent2D {
+                width: self.extent.width + 1,
+                height: self.extent.height + 1,
+            },
+            max_image_layers: 1,
+            usage: image::Usage::COLOR_ATTACHMENT | image::Usage::TRANSFER_SRC,
+            composite_alpha: CompositeAlpha::OPAQUE,
+        };
+        // There is nothing to present to.
+        (caps, Some(vec![self.format]), Vec::new())
+    }
+
+    fn supports_queue_family(&self, _: &QueueFamily) -> bool {
+        true
+    }
+}